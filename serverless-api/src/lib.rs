@@ -6,17 +6,106 @@ mod sys {
     pub fn response_set_status_line(status: u32, ptr: *const u8, size: u64);
     pub fn response_set_header(name_ptr: *const u8, name_size: u64, value_ptr: *const u8, value_size: u64);
     pub fn response_set_body(ptr: *const u8, size: u64);
+    pub fn response_write_chunk(ptr: *const u8, size: u64) -> i64;
+    pub fn response_finish();
     pub fn tcp_connect(ptr: *const u8, size: u64) -> i32;
+    pub fn tcp_connect_pooled(ptr: *const u8, size: u64) -> i32;
+    pub fn tcp_close(fd: i32) -> i32;
+    pub fn poll(entries: *mut u8, count: u64, timeout_ms: i64) -> i32;
+    pub fn clock_now(clock: i32) -> i64;
+    pub fn sleep_ms(ms: u64);
     pub fn tcp_read(fd: i32, ptr: *mut u8, size: u64) -> i64;
     pub fn tcp_write(fd: i32, ptr: *const u8, size: u64) -> i64;
     pub fn db_get(key_ptr: *const u8, key_size: u64, value_ptr: *const u8, value_size: u64) -> i64;
+    pub fn db_set(key_ptr: *const u8, key_size: u64, value_ptr: *const u8, value_size: u64) -> i32;
+    pub fn db_delete(key_ptr: *const u8, key_size: u64) -> i32;
+    pub fn secret_get(name_ptr: *const u8, name_size: u64, value_ptr: *const u8, value_size: u64) -> i64;
+    pub fn db_list_prefix(prefix_ptr: *const u8, prefix_size: u64, out_ptr: *mut u8, out_size: u64) -> i64;
+    pub fn fs_open(path_ptr: *const u8, path_size: u64) -> i32;
+    pub fn fs_read(fd: i32, ptr: *mut u8, size: u64) -> i64;
+    pub fn fs_close(fd: i32) -> i32;
+    pub fn fs_stat(path_ptr: *const u8, path_size: u64, out_ptr: *mut u8) -> i32;
+    pub fn fs_readdir(path_ptr: *const u8, path_size: u64, out_ptr: *mut u8, out_size: u64) -> i64;
+    pub fn request_method(out_ptr: *mut u8, out_size: u64) -> i64;
+    pub fn request_path(out_ptr: *mut u8, out_size: u64) -> i64;
+    pub fn request_header(name_ptr: *const u8, name_size: u64, out_ptr: *mut u8, out_size: u64) -> i64;
+    pub fn request_body(out_ptr: *mut u8, out_size: u64) -> i64;
+    pub fn log_v2(level: i32, msg_ptr: *const u8, msg_size: u64, fields_ptr: *const u8, fields_size: u64);
   }
 }
 
+/// logs at the info level, see the `info!` family of macros for other
+/// levels and fields
 pub fn log(s: &str) {
   unsafe { sys::log(s.as_ptr(), s.len() as u64) };
 }
 
+pub mod log {
+  use super::sys;
+
+  #[derive(Debug, Clone, Copy, PartialEq)]
+  pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4,
+  }
+
+  /// writes a record with key/value fields, added to the server's own
+  pub fn write(level: Level, message: &str, fields: &[(&str, &str)]) {
+    let mut encoded = Vec::new();
+    for &(key, value) in fields.iter() {
+      push(&mut encoded, key);
+      push(&mut encoded, value);
+    }
+
+    unsafe {
+      sys::log_v2(level as i32, message.as_ptr(), message.len() as u64, encoded.as_ptr(), encoded.len() as u64);
+    }
+  }
+
+  /// each string is preceded by its length as a little endian u32
+  fn push(buf: &mut Vec<u8>, s: &str) {
+    let len = s.len() as u32;
+    buf.extend_from_slice(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]);
+    buf.extend_from_slice(s.as_bytes());
+  }
+}
+
+/// `info!("message", key = value, ...)`, values are converted with `to_string()`
+#[macro_export]
+macro_rules! log_record {
+  ($level:expr, $message:expr $(, $key:ident = $value:expr)* $(,)*) => {
+    $crate::log::write($level, &$message, &[$((stringify!($key), &$value.to_string()[..])),*])
+  };
+}
+
+#[macro_export]
+macro_rules! error {
+  ($($arg:tt)+) => ($crate::log_record!($crate::log::Level::Error, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! warn {
+  ($($arg:tt)+) => ($crate::log_record!($crate::log::Level::Warn, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! info {
+  ($($arg:tt)+) => ($crate::log_record!($crate::log::Level::Info, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! debug {
+  ($($arg:tt)+) => ($crate::log_record!($crate::log::Level::Debug, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! trace {
+  ($($arg:tt)+) => ($crate::log_record!($crate::log::Level::Trace, $($arg)+));
+}
+
 pub mod db {
   use super::sys;
   use std::iter::repeat;
@@ -52,6 +141,163 @@ pub mod db {
       String::from_utf8(v).ok()
     }
   }
+
+  #[derive(Debug, Clone, PartialEq)]
+  pub enum Error {
+    /// the application has no `kv_namespace`
+    NoStore,
+    QuotaExceeded,
+    Io,
+  }
+
+  fn error(code: i64) -> Error {
+    match code {
+      -1 => Error::NoStore,
+      -2 => Error::QuotaExceeded,
+      _ => Error::Io,
+    }
+  }
+
+  pub fn set(key: &str, value: &[u8]) -> Result<(), Error> {
+    let res = unsafe { sys::db_set(key.as_ptr(), key.len() as u64, value.as_ptr(), value.len() as u64) };
+    if res < 0 {
+      Err(error(res as i64))
+    } else {
+      Ok(())
+    }
+  }
+
+  /// returns true if the key was present
+  pub fn delete(key: &str) -> Result<bool, Error> {
+    let res = unsafe { sys::db_delete(key.as_ptr(), key.len() as u64) };
+    if res < 0 {
+      Err(error(res as i64))
+    } else {
+      Ok(res == 1)
+    }
+  }
+
+  pub fn list_prefix(prefix: &str) -> Result<Vec<String>, Error> {
+    let mut v: Vec<u8> = Vec::new();
+
+    // the key list can grow between the two calls, so retry until it fits
+    loop {
+      let sz = unsafe { sys::db_list_prefix(prefix.as_ptr(), prefix.len() as u64, v.as_mut_ptr(), v.len() as u64) };
+      if sz < 0 {
+        return Err(error(sz));
+      }
+      if sz as usize <= v.len() {
+        v.truncate(sz as usize);
+        break;
+      }
+      v.resize(sz as usize, 0);
+    }
+
+    let mut keys = Vec::new();
+    let mut i = 0;
+    while i + 4 <= v.len() {
+      let len = (v[i] as usize) | (v[i + 1] as usize) << 8 | (v[i + 2] as usize) << 16 | (v[i + 3] as usize) << 24;
+      i += 4;
+      if i + len > v.len() {
+        return Err(Error::Io);
+      }
+      keys.push(String::from_utf8_lossy(&v[i..i + len]).into_owned());
+      i += len;
+    }
+
+    Ok(keys)
+  }
+}
+
+pub mod secret {
+  use super::sys;
+
+  /// returns None if the secret does not exist or the application did not declare it
+  pub fn get(name: &str) -> Option<Vec<u8>> {
+    let mut v: Vec<u8> = Vec::new();
+
+    loop {
+      let sz = unsafe { sys::secret_get(name.as_ptr(), name.len() as u64, v.as_mut_ptr(), v.len() as u64) };
+      if sz < 0 {
+        return None;
+      }
+      if sz as usize <= v.len() {
+        v.truncate(sz as usize);
+        return Some(v);
+      }
+      v.resize(sz as usize, 0);
+    }
+  }
+}
+
+pub mod time {
+  use super::sys;
+  use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+  const CLOCK_WALL: i32 = 0;
+  const CLOCK_MONOTONIC: i32 = 1;
+
+  fn from_nanos(nanos: i64) -> Duration {
+    Duration::new((nanos / 1_000_000_000) as u64, (nanos % 1_000_000_000) as u32)
+  }
+
+  /// wall clock time
+  pub fn now() -> SystemTime {
+    UNIX_EPOCH + from_nanos(unsafe { sys::clock_now(CLOCK_WALL) })
+  }
+
+  /// time elapsed since an arbitrary point, that never goes backwards.
+  /// Only meaningful when compared with another call in the same request
+  pub fn monotonic() -> Duration {
+    from_nanos(unsafe { sys::clock_now(CLOCK_MONOTONIC) })
+  }
+
+  pub fn sleep(duration: Duration) {
+    let ms = duration.as_secs() * 1000 + duration.subsec_millis() as u64;
+    unsafe { sys::sleep_ms(ms) };
+  }
+}
+
+pub mod request {
+  use super::sys;
+
+  /// calls `f` with a growing buffer until the value fits
+  fn read_value<F: Fn(*mut u8, u64) -> i64>(f: F) -> Option<Vec<u8>> {
+    let mut v: Vec<u8> = Vec::new();
+
+    loop {
+      let sz = f(v.as_mut_ptr(), v.len() as u64);
+      if sz < 0 {
+        return None;
+      }
+      if sz as usize <= v.len() {
+        v.truncate(sz as usize);
+        return Some(v);
+      }
+      v.resize(sz as usize, 0);
+    }
+  }
+
+  pub fn method() -> String {
+    let v = read_value(|ptr, size| unsafe { sys::request_method(ptr, size) }).unwrap_or(Vec::new());
+    String::from_utf8_lossy(&v).into_owned()
+  }
+
+  /// path and query string
+  pub fn path() -> String {
+    let v = read_value(|ptr, size| unsafe { sys::request_path(ptr, size) }).unwrap_or(Vec::new());
+    String::from_utf8_lossy(&v).into_owned()
+  }
+
+  /// the first header with that name, case insensitive
+  pub fn header(name: &str) -> Option<String> {
+    read_value(|ptr, size| unsafe { sys::request_header(name.as_ptr(), name.len() as u64, ptr, size) })
+      .map(|v| String::from_utf8_lossy(&v).into_owned())
+  }
+
+  pub fn body() -> Vec<u8> {
+    read_value(|ptr, size| unsafe { sys::request_body(ptr, size) }).unwrap_or(Vec::new())
+  }
 }
 
 pub mod response {
@@ -74,6 +320,215 @@ pub mod response {
       sys::response_set_body(body.as_ptr(), body.len() as u64);
     }
   }
+
+  /// sends part of the body. The status and headers must be set before
+  /// the first chunk. The body is sent with chunked transfer encoding,
+  /// unless a Content-Length header was set
+  pub fn write_chunk(data: &[u8]) -> Option<usize> {
+    let res = unsafe { sys::response_write_chunk(data.as_ptr(), data.len() as u64) };
+    if res < 0 {
+      None
+    } else {
+      Some(res as usize)
+    }
+  }
+
+  /// ends a streamed response. It is called automatically when the function returns
+  pub fn finish() {
+    unsafe { sys::response_finish() };
+  }
+}
+
+pub mod poll {
+  use super::sys;
+
+  pub const READABLE: i32 = 1;
+  pub const WRITABLE: i32 = 2;
+  pub const HUP: i32 = 4;
+  pub const ERROR: i32 = 8;
+
+  #[repr(C)]
+  #[derive(Clone, Copy, Debug)]
+  pub struct PollFd {
+    pub fd: i32,
+    /// readiness we wait for
+    pub events: i32,
+    /// readiness set by the server
+    pub revents: i32,
+  }
+
+  impl PollFd {
+    pub fn new(fd: i32, events: i32) -> PollFd {
+      PollFd { fd, events, revents: 0 }
+    }
+  }
+
+  /// waits until one of the connections is ready, or until the timeout expires.
+  /// Returns the number of ready connections (0 on timeout)
+  pub fn poll(entries: &mut [PollFd], timeout_ms: Option<u64>) -> Option<usize> {
+    let timeout = timeout_ms.map(|t| t as i64).unwrap_or(-1);
+    let res = unsafe { sys::poll(entries.as_mut_ptr() as *mut u8, entries.len() as u64, timeout) };
+    if res < 0 {
+      None
+    } else {
+      Some(res as usize)
+    }
+  }
+}
+
+/// read only files from the application's `mount`
+pub mod fs {
+  use super::sys;
+
+  #[derive(Debug, Clone, PartialEq)]
+  pub enum Error {
+    /// the application has no `mount`
+    NoMount,
+    NotFound,
+    /// the path leaves the mount
+    InvalidPath,
+    IsDirectory,
+    NotDirectory,
+    BadDescriptor,
+    TooManyFiles,
+    Io,
+  }
+
+  fn error(code: i64) -> Error {
+    match code {
+      -1 => Error::NoMount,
+      -2 => Error::NotFound,
+      -3 => Error::InvalidPath,
+      -4 => Error::IsDirectory,
+      -5 => Error::NotDirectory,
+      -6 => Error::BadDescriptor,
+      -7 => Error::TooManyFiles,
+      _ => Error::Io,
+    }
+  }
+
+  #[derive(Debug, Clone, PartialEq)]
+  pub struct Metadata {
+    pub len: u64,
+    /// seconds since the UNIX epoch
+    pub modified: u64,
+    pub is_dir: bool,
+  }
+
+  #[derive(Debug, Clone, PartialEq)]
+  pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+  }
+
+  pub struct File {
+    fd: i32,
+  }
+
+  impl File {
+    pub fn open(path: &str) -> Result<File, Error> {
+      let fd = unsafe { sys::fs_open(path.as_ptr(), path.len() as u64) };
+      if fd < 0 {
+        Err(error(fd as i64))
+      } else {
+        Ok(File { fd })
+      }
+    }
+
+    /// returns 0 at the end of the file
+    pub fn read(&mut self, data: &mut [u8]) -> Result<usize, Error> {
+      let res = unsafe { sys::fs_read(self.fd, data.as_mut_ptr(), data.len() as u64) };
+      if res < 0 {
+        Err(error(res))
+      } else {
+        Ok(res as usize)
+      }
+    }
+
+    pub fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize, Error> {
+      let mut chunk = [0u8; 4096];
+      let mut total = 0;
+      loop {
+        let sz = self.read(&mut chunk)?;
+        if sz == 0 {
+          return Ok(total);
+        }
+        buf.extend_from_slice(&chunk[..sz]);
+        total += sz;
+      }
+    }
+  }
+
+  impl Drop for File {
+    fn drop(&mut self) {
+      unsafe { sys::fs_close(self.fd) };
+    }
+  }
+
+  pub fn read(path: &str) -> Result<Vec<u8>, Error> {
+    let mut v = Vec::new();
+    File::open(path)?.read_to_end(&mut v)?;
+    Ok(v)
+  }
+
+  pub fn read_to_string(path: &str) -> Result<String, Error> {
+    read(path).and_then(|v| String::from_utf8(v).map_err(|_| Error::Io))
+  }
+
+  fn read_u64(buf: &[u8]) -> u64 {
+    buf.iter().rev().fold(0, |acc, b| acc << 8 | *b as u64)
+  }
+
+  pub fn metadata(path: &str) -> Result<Metadata, Error> {
+    let mut buf = [0u8; 24];
+    let res = unsafe { sys::fs_stat(path.as_ptr(), path.len() as u64, buf.as_mut_ptr()) };
+    if res < 0 {
+      return Err(error(res as i64));
+    }
+
+    Ok(Metadata {
+      len: read_u64(&buf[0..8]),
+      modified: read_u64(&buf[8..16]),
+      is_dir: read_u64(&buf[16..20]) == 2,
+    })
+  }
+
+  pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, Error> {
+    let mut v: Vec<u8> = Vec::new();
+
+    loop {
+      let sz = unsafe { sys::fs_readdir(path.as_ptr(), path.len() as u64, v.as_mut_ptr(), v.len() as u64) };
+      if sz < 0 {
+        return Err(error(sz));
+      }
+      if sz as usize <= v.len() {
+        v.truncate(sz as usize);
+        break;
+      }
+      v.resize(sz as usize, 0);
+    }
+
+    let mut entries = Vec::new();
+    let mut i = 0;
+    while i + 4 <= v.len() {
+      let len = read_u64(&v[i..i + 4]) as usize;
+      i += 4;
+      if i + len > v.len() {
+        return Err(Error::Io);
+      }
+      let name = String::from_utf8_lossy(&v[i..i + len]).into_owned();
+      i += len;
+
+      // directory names end with a `/`
+      entries.push(if name.ends_with('/') {
+        DirEntry { name: name[..name.len() - 1].to_string(), is_dir: true }
+      } else {
+        DirEntry { name, is_dir: false }
+      });
+    }
+
+    Ok(entries)
+  }
 }
 
 pub struct TcpStream {
@@ -90,6 +545,24 @@ impl TcpStream {
     }
   }
 
+  /// reuses an idle connection to that address if the server kept one,
+  /// otherwise opens a new one. Closing it gives it back to the pool
+  pub fn connect_pooled(address: &str) -> Option<TcpStream> {
+    let fd = unsafe { sys::tcp_connect_pooled(address.as_ptr(), address.len() as u64) };
+    if fd < 0 {
+      None
+    } else {
+      Some(TcpStream { fd })
+    }
+  }
+
+  pub fn close(self) {}
+
+  /// identifier used in `poll::PollFd`
+  pub fn fd(&self) -> i32 {
+    self.fd
+  }
+
   pub fn write(&mut self, data: &[u8]) -> Option<usize> {
     let res = unsafe { sys::tcp_write(self.fd, data.as_ptr(), data.len() as u64) };
     if res < 0 {
@@ -109,3 +582,8 @@ impl TcpStream {
   }
 }
 
+impl Drop for TcpStream {
+  fn drop(&mut self) {
+    unsafe { sys::tcp_close(self.fd) };
+  }
+}
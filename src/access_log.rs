@@ -0,0 +1,188 @@
+//! one line per response, in the Common or Combined Log Format or as JSON,
+//! appended to a file. The file is reopened on SIGUSR1, once logrotate
+//! moved it away
+
+use libc;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use http;
+use json::json_string;
+use logging;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+  Common,
+  Combined,
+  Json,
+}
+
+impl Format {
+  pub fn parse(format: &str) -> Option<Format> {
+    match format {
+      "common" => Some(Format::Common),
+      "combined" => Some(Format::Combined),
+      "json" => Some(Format::Json),
+      _ => None,
+    }
+  }
+}
+
+/// set by the signal handler, the next write reopens the file
+static REOPEN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_reopen(_signal: libc::c_int) {
+  REOPEN.store(true, Ordering::Relaxed);
+}
+
+/// a response that was sent, or interrupted by the client
+pub struct Entry<'a> {
+  pub client: Option<SocketAddr>,
+  pub method: &'a str,
+  pub path: &'a str,
+  pub status: u16,
+  /// body bytes, without the headers
+  pub bytes: u64,
+  /// when the request was received
+  pub received: SystemTime,
+  /// since the request was received
+  pub duration: Duration,
+  pub user_agent: Option<&'a str>,
+  pub referer: Option<&'a str>,
+  /// see `WasmApp::name`, None for static files
+  pub app: Option<&'a str>,
+  pub request_id: Option<u64>,
+}
+
+struct Writer {
+  path: String,
+  file: File,
+}
+
+/// cheap to clone, the handles write to the same file
+#[derive(Clone)]
+pub struct AccessLog {
+  format: Format,
+  inner: Arc<Mutex<Writer>>,
+}
+
+impl AccessLog {
+  /// opens the file in append mode, and reopens it on SIGUSR1. There is
+  /// one handler per process, only open one access log
+  pub fn open(path: &str, format: Format) -> io::Result<AccessLog> {
+    let file = append(path)?;
+    unsafe {
+      libc::signal(libc::SIGUSR1, request_reopen as libc::sighandler_t);
+    }
+
+    Ok(AccessLog {
+      format,
+      inner: Arc::new(Mutex::new(Writer {
+        path: path.to_string(),
+        file,
+      })),
+    })
+  }
+
+  pub fn write(&self, entry: &Entry) {
+    let mut line = match self.format {
+      Format::Common => common(entry),
+      Format::Combined => combined(entry),
+      Format::Json => json(entry),
+    };
+    line.push('\n');
+
+    let mut writer = self.inner.lock().unwrap();
+    if REOPEN.swap(false, Ordering::Relaxed) {
+      match append(&writer.path) {
+        Ok(file) => writer.file = file,
+        Err(e) => error!("could not reopen the access log {}: {}", writer.path, e),
+      }
+    }
+    // a single write, lines from several workers do not interleave
+    if let Err(e) = writer.file.write_all(line.as_bytes()) {
+      warn!("could not write to the access log {}: {}", writer.path, e);
+    }
+  }
+}
+
+fn append(path: &str) -> io::Result<File> {
+  OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// `-` if absent, quoted and escaped otherwise
+fn quoted(value: Option<&str>) -> String {
+  match value {
+    None => "-".to_string(),
+    Some(value) => {
+      let mut res = String::with_capacity(value.len() + 2);
+      res.push('"');
+      for c in value.chars() {
+        match c {
+          '"' => res.push_str("\\\""),
+          '\\' => res.push_str("\\\\"),
+          c if c < ' ' => res.push_str(&format!("\\x{:02x}", c as u32)),
+          c => res.push(c),
+        }
+      }
+      res.push('"');
+      res
+    }
+  }
+}
+
+/// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /hello HTTP/1.1" 200 12`
+fn common(entry: &Entry) -> String {
+  let client = entry.client.map(|address| address.ip().to_string()).unwrap_or("-".to_string());
+  let bytes = if entry.bytes == 0 { "-".to_string() } else { entry.bytes.to_string() };
+  format!(
+    "{} - - [{}] {} {} {}",
+    client,
+    http::common_log_date(entry.received),
+    quoted(Some(&format!("{} {} HTTP/1.1", entry.method, entry.path))),
+    entry.status,
+    bytes
+  )
+}
+
+/// the common format with the referer and user agent, followed by the
+/// request ID, the application and the duration in milliseconds
+fn combined(entry: &Entry) -> String {
+  format!(
+    "{} {} {} {} {} {:.3}",
+    common(entry),
+    quoted(entry.referer),
+    quoted(entry.user_agent),
+    entry.request_id.map(|id| id.to_string()).unwrap_or("-".to_string()),
+    quoted(entry.app),
+    milliseconds(entry.duration)
+  )
+}
+
+fn json(entry: &Entry) -> String {
+  let client = entry.client.map(|address| address.ip().to_string());
+  let optional = |value: Option<&str>| value.map(json_string).unwrap_or("null".to_string());
+  format!(
+    "{{\"ts\":{},\"client\":{},\"method\":{},\"path\":{},\"status\":{},\"bytes\":{},\"duration_ms\":{:.3},\
+     \"user_agent\":{},\"referer\":{},\"app\":{},\"request_id\":{}}}",
+    json_string(&logging::timestamp(entry.received)),
+    optional(client.as_ref().map(|ip| ip.as_str())),
+    json_string(entry.method),
+    json_string(entry.path),
+    entry.status,
+    entry.bytes,
+    milliseconds(entry.duration),
+    optional(entry.user_agent),
+    optional(entry.referer),
+    optional(entry.app),
+    entry.request_id.map(|id| id.to_string()).unwrap_or("null".to_string())
+  )
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+  duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1e6
+}
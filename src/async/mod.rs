@@ -1,23 +1,75 @@
-use config::{ApplicationState, Config};
+use access_log::AccessLog;
+use config::{open_access_log, open_kv, ApplicationState, Config};
+use instances;
+use kv;
+use metrics::{self, Metrics};
 
 use mio::*;
 use mio::net::{TcpListener, TcpStream};
 use mio::unix::UnixReady;
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use slab::Slab;
 
 mod host;
+mod pool;
+mod schedule;
 mod session;
 
 const SERVER: Token = Token(0);
 
-pub fn server(config: Config) {
-  let state = ApplicationState::new(&config);
+/// starts `workers` event loops accepting connections on the same socket.
+/// Returns if the address cannot be used
+pub fn server(config: Config) -> Result<(), String> {
+  let listener = net::TcpListener::bind(&config.listen_address)
+    .map_err(|e| format!("could not listen on {}: {}", config.listen_address, e))?;
 
-  let addr = (&config.listen_address).parse().unwrap();
-  let server = TcpListener::bind(&addr).unwrap();
+  let metrics = Metrics::new();
+  if let Some(ref address) = config.metrics_address {
+    metrics::serve(address, metrics.clone())?;
+  }
+  let access_log = open_access_log(&config)?;
+
+  // the workers share the key-value store, its namespaces are not
+  // one store for every worker, their writes go through the same log
+  let kv = open_kv(&config);
+  for worker in 1..config.workers.unwrap_or(1) {
+    let config = config.clone();
+    let kv = kv.clone();
+    let metrics = metrics.clone();
+    let access_log = access_log.clone();
+    let listener = listener.try_clone().map_err(|e| format!("could not start worker {}: {}", worker, e))?;
+    thread::spawn(move || event_loop(&config, listener, kv, metrics, access_log, worker));
+  }
+
+  event_loop(&config, listener, kv, metrics, access_log, 0);
+  Ok(())
+}
+
+/// schedules only run on the first worker
+fn event_loop(
+  config: &Config,
+  listener: net::TcpListener,
+  kv: Option<Arc<Mutex<kv::Store>>>,
+  metrics: Metrics,
+  access_log: Option<AccessLog>,
+  worker: usize,
+) {
+  let mut state = ApplicationState::new(config, kv);
+  state.metrics = metrics;
+  state.access_log = access_log;
+  if worker > 0 {
+    state.schedules.clear();
+  }
+  instances::prefill(&state.modules, state.instance_pool_size);
+
+  let server = TcpListener::from_std(listener).unwrap();
 
   let mut poll = Poll::new().unwrap();
 
@@ -30,10 +82,28 @@ pub fn server(config: Config) {
   let state = Rc::new(RefCell::new(state));
   let mut connections = Slab::with_capacity(1024);
   let mut ready = VecDeque::new();
+  let mut backend_pool = pool::BackendPool::new();
+  // (deadline, client token) for sessions waiting on a timeout
+  let mut timers = BinaryHeap::new();
+  let mut scheduler = schedule::Scheduler::new(&state.borrow());
+  // client sessions and scheduled jobs, backends share their session
+  let mut sessions = 0;
 
   loop {
-    poll.poll(&mut events, None).unwrap();
-    println!("got events: {:?}", events);
+    let timeout = timers.peek().map(|&Reverse((deadline, _))| {
+      let now = Instant::now();
+      if deadline > now {
+        deadline - now
+      } else {
+        Duration::from_millis(0)
+      }
+    });
+    let timeout = match (timeout, scheduler.timeout()) {
+      (Some(a), Some(b)) => Some(if a < b { a } else { b }),
+      (a, b) => a.or(b),
+    };
+    poll.poll(&mut events, timeout).unwrap();
+    trace!("got events: {:?}", events);
 
     for event in events.iter() {
       match event.token() {
@@ -41,7 +111,7 @@ pub fn server(config: Config) {
           if let Ok((sock, addr)) = server.accept() {
             match connections.vacant_entry() {
               None => {
-                println!("error: no more room for new connections");
+                error!("no more room for new connections");
               }
               Some(entry) => {
                 let index = entry.index();
@@ -58,6 +128,7 @@ pub fn server(config: Config) {
                   index,
                 )));
                 entry.insert(client);
+                sessions += 1;
               }
             }
           }
@@ -73,7 +144,7 @@ pub fn server(config: Config) {
               ready.push_back(client_token);
             }
           } else {
-            println!(
+            debug!(
               "non existing token {:?} got events {:?}",
               client_token,
               event.readiness()
@@ -84,18 +155,58 @@ pub fn server(config: Config) {
       }
     }
 
-    for client_token in ready.drain(..) {
+    let now = Instant::now();
+    while timers.peek().map(|&Reverse((deadline, _))| deadline <= now).unwrap_or(false) {
+      let Reverse((_, client_token)) = timers.pop().unwrap();
+      // the session may have been woken up by an event since then
+      let expired = connections
+        .get(client_token)
+        .and_then(|client| client.borrow().next_deadline())
+        .map(|deadline| deadline <= now)
+        .unwrap_or(false);
+      if expired {
+        ready.push_back(client_token);
+      }
+    }
+
+    let due = scheduler.due(&state.borrow());
+    for schedule in due {
+      match connections.vacant_entry() {
+        None => {
+          error!("no more room to run schedule {}", schedule);
+        }
+        Some(entry) => {
+          let index = entry.index();
+          let job = session::Session::scheduled(state.clone(), schedule, index);
+          entry.insert(Rc::new(RefCell::new(job)));
+          sessions += 1;
+          scheduler.started(index, schedule);
+          ready.push_back(index);
+        }
+      }
+    }
+
+    while let Some(client_token) = ready.pop_front() {
       let mut cont = session::ExecutionResult::Continue;
       if let Some(ref mut client) = connections.get_mut(client_token) {
         cont = client.borrow_mut().execute();
+        if let Some(deadline) = client.borrow().next_deadline() {
+          timers.push(Reverse((deadline, client_token)));
+        }
       } else {
-        println!("non existing token {:?} was marked as ready", client_token);
+        debug!("non existing token {:?} was marked as ready", client_token);
       }
 
       match cont {
         session::ExecutionResult::Close(tokens) => {
-          for t in tokens.iter() {
-            connections.remove(client_token);
+          // the session can be closed from one of its backends' tokens,
+          // its own token comes first
+          for token in tokens.iter() {
+            connections.remove(*token);
+          }
+          if let Some(&session_token) = tokens.first() {
+            scheduler.finished(session_token);
+            sessions -= 1;
           }
         },
         session::ExecutionResult::ConnectBackend(address) => {
@@ -103,25 +214,92 @@ pub fn server(config: Config) {
 
           match connections.vacant_entry() {
             None => {
-              println!("error: no more room for new connections");
+              client.borrow_mut().connect_failed(&address, "no more room for new connections");
+              ready.push_back(client_token);
             }
             Some(entry) => {
               let index = entry.index();
-              let stream = TcpStream::connect(&address).unwrap();
+              let stream = match TcpStream::connect(&address) {
+                Ok(stream) => stream,
+                Err(e) => {
+                  client.borrow_mut().connect_failed(&address, &e.to_string());
+                  ready.push_back(client_token);
+                  continue;
+                }
+              };
               poll.register(
                 &stream,
                 Token(index + 1),
                 Ready::readable() | Ready::writable() | Ready::from(UnixReady::hup() | UnixReady::error()),
                 PollOpt::edge(),
               );
-              client.borrow_mut().add_backend(stream, index);
+              client.borrow_mut().add_backend(stream, index, None);
 
               entry.insert(client);
             }
           }
         },
+        session::ExecutionResult::ConnectPooledBackend(app, address) => {
+          let client = connections.get(client_token).unwrap().clone();
+
+          match connections.vacant_entry() {
+            None => {
+              client.borrow_mut().connect_failed(&address, "no more room for new connections");
+              ready.push_back(client_token);
+            }
+            Some(entry) => {
+              let index = entry.index();
+              // an idle connection is already connected, so registering it
+              // will immediately generate the writable event the session waits for
+              let stream = match backend_pool.checkout(app, &address) {
+                Some(stream) => {
+                  debug!("reusing pooled connection to {}", address);
+                  stream
+                },
+                None => match TcpStream::connect(&address) {
+                  Ok(stream) => stream,
+                  Err(e) => {
+                    client.borrow_mut().connect_failed(&address, &e.to_string());
+                    ready.push_back(client_token);
+                    continue;
+                  }
+                },
+              };
+              poll.register(
+                &stream,
+                Token(index + 1),
+                Ready::readable() | Ready::writable() | Ready::from(UnixReady::hup() | UnixReady::error()),
+                PollOpt::edge(),
+              );
+              client.borrow_mut().add_backend(stream, index, Some(address));
+
+              entry.insert(client);
+            }
+          }
+        },
+        session::ExecutionResult::ReleaseBackend(backend_token) => {
+          let backend = connections
+            .get(client_token)
+            .and_then(|client| client.borrow_mut().take_backend(backend_token));
+          connections.remove(backend_token);
+
+          if let Some(backend) = backend {
+            poll.deregister(&backend.stream);
+            let app = connections.get(client_token).and_then(|client| client.borrow().app);
+            let pool_size = app.and_then(|app| state.borrow().applications[app].backend_pool_size);
+
+            if let (Some(app), Some(address), Some(pool_size)) = (app, backend.pooled, pool_size) {
+              backend_pool.checkin(app, address, backend.stream, pool_size);
+            }
+          }
+
+          // the guest got the result of tcp_close and can keep running
+          ready.push_back(client_token);
+        },
         _  => {}
       }
     }
+
+    state.borrow().metrics.worker(worker, sessions, connections.len(), connections.capacity());
   }
 }
@@ -0,0 +1,56 @@
+use mio::net::TcpStream;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+
+/// idle backend connections, per application and per destination
+///
+/// connections are given back by `tcp_close` and reused by the next
+/// `tcp_connect_pooled` to the same address from the same application
+pub struct BackendPool {
+  idle: HashMap<(usize, SocketAddr), Vec<TcpStream>>,
+}
+
+impl BackendPool {
+  pub fn new() -> BackendPool {
+    BackendPool { idle: HashMap::new() }
+  }
+
+  /// returns an idle connection that is still usable, dropping the dead ones
+  pub fn checkout(&mut self, app: usize, address: &SocketAddr) -> Option<TcpStream> {
+    if let Some(streams) = self.idle.get_mut(&(app, *address)) {
+      while let Some(stream) = streams.pop() {
+        if is_healthy(&stream) {
+          return Some(stream);
+        }
+        debug!("dropping stale pooled connection to {}", address);
+      }
+    }
+
+    None
+  }
+
+  /// stores a connection for later use, unless there are already `max_idle`
+  /// idle connections for that destination
+  pub fn checkin(&mut self, app: usize, address: SocketAddr, stream: TcpStream, max_idle: usize) {
+    let streams = self.idle.entry((app, address)).or_insert_with(Vec::new);
+    if streams.len() < max_idle && is_healthy(&stream) {
+      streams.push(stream);
+    }
+  }
+}
+
+/// an idle connection must have no pending error and nothing to read:
+/// unexpected data or a closed connection means we cannot reuse it
+fn is_healthy(stream: &TcpStream) -> bool {
+  match stream.take_error() {
+    Ok(None) => {}
+    _ => return false,
+  }
+
+  let mut buf = [0u8; 1];
+  match stream.peek(&mut buf) {
+    Err(ref e) if e.kind() == ErrorKind::WouldBlock => true,
+    _ => false,
+  }
+}
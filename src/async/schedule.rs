@@ -0,0 +1,62 @@
+//! starts the `[[schedules]]` functions when their time comes
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use config::ApplicationState;
+
+pub struct Scheduler {
+  /// next run of each schedule, None if the cron expression never matches
+  next_runs: Vec<Option<SystemTime>>,
+  /// session token -> schedule, for the runs that did not finish yet
+  running: HashMap<usize, usize>,
+}
+
+impl Scheduler {
+  pub fn new(state: &ApplicationState) -> Scheduler {
+    let now = SystemTime::now();
+    Scheduler {
+      next_runs: state.schedules.iter().map(|s| s.cron.next_after(now)).collect(),
+      running: HashMap::new(),
+    }
+  }
+
+  /// time until the next run
+  pub fn timeout(&self) -> Option<Duration> {
+    let now = SystemTime::now();
+    self
+      .next_runs
+      .iter()
+      .filter_map(|next| *next)
+      .min()
+      .map(|next| next.duration_since(now).unwrap_or(Duration::from_millis(0)))
+  }
+
+  /// schedules that must start now. A schedule still running from
+  /// its previous time skips this one
+  pub fn due(&mut self, state: &ApplicationState) -> Vec<usize> {
+    let now = SystemTime::now();
+    let mut res = Vec::new();
+
+    for (index, next) in self.next_runs.iter_mut().enumerate() {
+      if next.map(|next| next <= now).unwrap_or(false) {
+        *next = state.schedules[index].cron.next_after(now);
+        if self.running.values().any(|schedule| *schedule == index) {
+          warn!("schedule {} is still running, skipping this run", index);
+        } else {
+          res.push(index);
+        }
+      }
+    }
+
+    res
+  }
+
+  pub fn started(&mut self, token: usize, schedule: usize) {
+    self.running.insert(token, schedule);
+  }
+
+  pub fn finished(&mut self, token: usize) {
+    self.running.remove(&token);
+  }
+}
@@ -4,33 +4,57 @@ use mio::{Poll, Ready};
 use std::collections::HashMap;
 use std::iter::repeat;
 use std::rc::Rc;
-use std::io::{ErrorKind, Read, Write};
+use std::io::{self, ErrorKind, Read, Write};
 use std::cell::RefCell;
-use std::net::{SocketAddr, Shutdown};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
 use slab::Slab;
 
-use interpreter::WasmInstance;
+use access_log::Entry;
+use interpreter::{self, WasmInstance};
 use super::host;
-use config::ApplicationState;
+use config::{ApplicationState, Route, ScheduleRun};
+use errors::InvocationError;
+use logging::Context;
+use metrics::Backend;
+use registry;
+use wasi;
+use http;
 use httparse;
-use wasmi::{ExternVal, ImportsBuilder, ModuleInstance, TrapKind, RuntimeValue};
+use static_files;
+use wasmi::{ExternVal, TrapKind, RuntimeValue};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ExecutionResult {
   WouldBlock,
+  /// the session's token, then its backends'
   Close(Vec<usize>),
   Continue,
   ConnectBackend(SocketAddr),
+  /// (application index, address)
+  ConnectPooledBackend(usize, SocketAddr),
+  /// the guest closed this backend connection
+  ReleaseBackend(usize),
   //Register(usize),
   //Remove(Vec<usize>),
 }
 
+/// response body sent by the guest with `response_write_chunk`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Streaming {
+  /// false if the guest declared a Content-Length
+  pub chunked: bool,
+  pub finished: bool,
+}
+
 #[derive(Debug)]
 pub struct Stream {
   pub readiness: UnixReady,
   pub interest: UnixReady,
   pub stream: TcpStream,
   pub index: usize,
+  /// backend address if the connection can go back to the pool
+  pub pooled: Option<SocketAddr>,
 }
 
 pub struct Buf {
@@ -45,12 +69,30 @@ pub enum SessionState {
   WaitingForBackendConnect(usize),
   TcpRead(i32, u32, usize),
   TcpWrite(i32, Vec<u8>, usize),
+  /// (pointer to the poll entries, (fd, events) pairs)
+  Polling(u32, Vec<(i32, i32)>),
+  /// waiting for `deadline`
+  Sleeping,
+  /// waiting for a streamed chunk of that size to be sent to the client
+  ResponseWrite(u64),
+  /// sending the rest of the response, then closing the connection
+  Finishing,
   Executing,
   Done,
 }
 
+/// a scheduled function running without a client
+pub struct Job {
+  pub schedule: usize,
+  started: SystemTime,
+  timer: Instant,
+}
+
 pub struct Session {
-  client: Stream,
+  /// None for scheduled jobs
+  client: Option<Stream>,
+  /// position in the event loop's connections
+  token: usize,
   backends: HashMap<usize, Stream>,
   instance: Option<WasmInstance<host::State, host::AsyncHost>>,
   config: Rc<RefCell<ApplicationState>>,
@@ -58,18 +100,82 @@ pub struct Session {
   pub state: Option<SessionState>,
   method: Option<String>,
   path: Option<String>,
+  headers: Vec<(String, String)>,
+  body: Vec<u8>,
   env: Option<Rc<RefCell<host::State>>>,
+  pub app: Option<usize>,
+  /// the guest must be resumed at that time even if no event happened
+  pub deadline: Option<Instant>,
+  /// data waiting to be written to the client
+  output: Vec<u8>,
+  streaming: Option<Streaming>,
+  /// set while the application's error handler runs
+  handling_error: Option<InvocationError>,
+  pub job: Option<Job>,
+  /// when the connection was accepted
+  started: Instant,
+  /// the same, for the access log
+  received: SystemTime,
+  /// application or static directory that handled the request, for the metrics
+  route: Option<String>,
+  /// status of the response, once its head was written
+  status: Option<u16>,
+  /// time spent running the current invocation, without its waits
+  guest_time: Duration,
+  /// when the guest started waiting on a backend
+  io_started: Option<Instant>,
+  /// request ID, then the application once routed
+  context: Context,
+  /// address of the client, for the access log
+  peer: Option<SocketAddr>,
+  /// body bytes of the response sent so far
+  bytes: u64,
+  /// body bytes of the response queued by `queue_response`, counted
+  /// in `bytes` once flushed
+  queued_body: u64,
 }
 
 impl Session {
   pub fn new(config: Rc<RefCell<ApplicationState>>, stream: TcpStream, index: usize) -> Session {
+    let peer = stream.peer_addr().ok();
     let client = Stream {
       readiness: UnixReady::from(Ready::empty()),
       interest: UnixReady::from(Ready::readable()) | UnixReady::hup() | UnixReady::error(),
       stream,
       index,
+      pooled: None,
+    };
+
+    let mut session = Session::with_client(config, Some(client), index);
+    session.peer = peer;
+    session
+  }
+
+  /// starts the function of a `[[schedules]]` entry. Its result is recorded
+  /// in the schedule once it returns
+  pub fn scheduled(config: Rc<RefCell<ApplicationState>>, schedule: usize, index: usize) -> Session {
+    let mut session = Session::with_client(config.clone(), None, index);
+    session.job = Some(Job {
+      schedule,
+      started: SystemTime::now(),
+      timer: Instant::now(),
+    });
+
+    let res = {
+      let config = config.borrow();
+      let app_index = config.schedules[schedule].app_index;
+      session.app = Some(app_index);
+      let function = config.applications[app_index].function.clone();
+      session.instantiate(&config, app_index, &function, &[])
     };
+    if let Err(error) = res {
+      session.fail(error);
+    }
 
+    session
+  }
+
+  fn with_client(config: Rc<RefCell<ApplicationState>>, client: Option<Stream>, index: usize) -> Session {
     let capacity = 8192;
     let mut v = Vec::with_capacity(capacity);
     v.extend(repeat(0).take(capacity));
@@ -81,6 +187,7 @@ impl Session {
 
     Session {
       client,
+      token: index,
       backends: HashMap::new(),
       instance: None,
       config,
@@ -88,16 +195,35 @@ impl Session {
       state: Some(SessionState::WaitingForRequest),
       method: None,
       path: None,
+      headers: Vec::new(),
+      body: Vec::new(),
       env: None,
+      app: None,
+      deadline: None,
+      output: Vec::new(),
+      streaming: None,
+      handling_error: None,
+      job: None,
+      started: Instant::now(),
+      received: SystemTime::now(),
+      route: None,
+      status: None,
+      guest_time: Duration::from_secs(0),
+      io_started: None,
+      context: Context::new_request(),
+      peer: None,
+      bytes: 0,
+      queued_body: 0,
     }
   }
 
-  pub fn add_backend(&mut self, stream: TcpStream, index: usize) {
+  pub fn add_backend(&mut self, stream: TcpStream, index: usize, pooled: Option<SocketAddr>) {
     let s = Stream {
       readiness: UnixReady::from(Ready::empty()),
       interest: UnixReady::from(Ready::writable()) | UnixReady::hup() | UnixReady::error(),
       stream,
       index,
+      pooled,
     };
 
     self.backends.insert(index, s);
@@ -105,124 +231,570 @@ impl Session {
     self.state = Some(SessionState::WaitingForBackendConnect(index));
   }
 
+  /// the backend connection could not be opened, the guest gets -1
+  pub fn connect_failed(&mut self, address: &SocketAddr, reason: &str) {
+    warn!(self.context; "could not connect to {}: {}", address, reason);
+    self.backend_done(Backend::Connect);
+    self.instance.as_mut().map(|instance| instance.add_function_result(RuntimeValue::I32(-1)));
+    self.state = Some(SessionState::Executing);
+  }
+
+  pub fn take_backend(&mut self, index: usize) -> Option<Stream> {
+    self.backends.remove(&index)
+  }
+
   pub fn resume(&mut self)  -> ExecutionResult {
+    let started = Instant::now();
     let res = self.instance.as_mut().map(|instance| instance.resume()).unwrap();
-    println!("resume result: {:?}", res);
+    self.guest_time += started.elapsed();
+    debug!(self.context; "resume result: {:?}", res);
+    let res = match res {
+      Err(ref t) if wasi::is_clean_exit(t) => Ok(None),
+      res => res,
+    };
     match res {
-      Err(t) => match t.kind() {
-        TrapKind::Host(ref err) => {
+      Err(t) => {
+        if let TrapKind::Host(ref err) = *t.kind() {
           match err.as_ref().downcast_ref() {
             Some(host::AsyncHostError::Connecting(address)) => {
-              println!("returning connect to backend server: {}", address);
+              debug!(self.context; "connecting to backend server: {}", address);
+              self.io_started = Some(Instant::now());
               return ExecutionResult::ConnectBackend(address.clone());
             },
+            Some(host::AsyncHostError::ConnectingPooled(address)) => {
+              debug!(self.context; "connecting to pooled backend server: {}", address);
+              self.io_started = Some(Instant::now());
+              return ExecutionResult::ConnectPooledBackend(self.app.unwrap(), address.clone());
+            },
+            Some(host::AsyncHostError::TcpClose(fd)) => {
+              let fd = *fd as usize;
+              if self.backends.contains_key(&fd) {
+                self.instance.as_mut().map(|instance| instance.add_function_result(RuntimeValue::I32(0)));
+                return ExecutionResult::ReleaseBackend(fd);
+              } else {
+                self.instance.as_mut().map(|instance| instance.add_function_result(RuntimeValue::I32(-1)));
+                return ExecutionResult::Continue;
+              }
+            },
             Some(host::AsyncHostError::TcpWrite(fd, ptr, sz, written)) => {
+              if !self.backends.contains_key(&(*fd as usize)) {
+                return self.unknown_backend(*fd);
+              }
               self.backends.get_mut(&(*fd as usize)).map(|backend| backend.interest.insert(UnixReady::from(Ready::writable())));
-              let buf = self.env.as_mut().and_then(|env| env.borrow_mut().get_buf(*ptr, *sz as usize)).unwrap();
+              let buf = self.env.as_mut().and_then(|env| env.borrow().read_bytes(*ptr, *sz).ok()).unwrap();
               self.state = Some(SessionState::TcpWrite(*fd, buf, *written));
+              self.io_started = Some(Instant::now());
               return ExecutionResult::Continue;
             },
             Some(host::AsyncHostError::TcpRead(fd, ptr, sz)) => {
+              if !self.backends.contains_key(&(*fd as usize)) {
+                return self.unknown_backend(*fd);
+              }
               self.backends.get_mut(&(*fd as usize)).map(|backend| backend.interest.insert(UnixReady::from(Ready::readable())));
               self.state = Some(SessionState::TcpRead(*fd, *ptr, *sz as usize));
+              self.io_started = Some(Instant::now());
+              return ExecutionResult::Continue;
+            },
+            Some(host::AsyncHostError::Poll(ptr, count, timeout)) => {
+              let entries = self
+                .env
+                .as_mut()
+                .and_then(|env| env.borrow().get_poll_entries(*ptr, *count).ok())
+                .unwrap_or(Vec::new());
+
+              for &(fd, events) in entries.iter() {
+                if let Some(backend) = self.backends.get_mut(&(fd as usize)) {
+                  if events & host::POLL_READABLE != 0 {
+                    backend.interest.insert(UnixReady::from(Ready::readable()));
+                  }
+                  if events & host::POLL_WRITABLE != 0 {
+                    backend.interest.insert(UnixReady::from(Ready::writable()));
+                  }
+                }
+              }
+
+              // without a timeout, only the invocation's deadline applies
+              self.deadline = if *timeout >= 0 {
+                let limit = self.instance.as_ref().and_then(|instance| instance.limits.deadline);
+                Some(interpreter::wait_deadline(*timeout as u64, limit))
+              } else {
+                None
+              };
+              self.state = Some(SessionState::Polling(*ptr, entries));
+              return ExecutionResult::Continue;
+            },
+            Some(host::AsyncHostError::ResponseWrite(ptr, sz)) => {
+              let chunk = self.env.as_mut().and_then(|env| env.borrow().read_bytes(*ptr, *sz).ok()).unwrap();
+              if self.streaming.as_ref().map(|s| s.finished).unwrap_or(false) {
+                self.instance.as_mut().map(|instance| instance.add_function_result(RuntimeValue::I64(-1)));
+                return ExecutionResult::Continue;
+              }
+
+              self.start_streaming();
+              if chunk.len() > 0 {
+                if self.streaming.as_ref().map(|s| s.chunked).unwrap_or(false) {
+                  self.output.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                  self.output.extend_from_slice(&chunk);
+                  self.output.extend_from_slice(b"\r\n");
+                } else {
+                  self.output.extend_from_slice(&chunk);
+                }
+              }
+              self.want_writable();
+              self.state = Some(SessionState::ResponseWrite(*sz));
+              return ExecutionResult::Continue;
+            },
+            Some(host::AsyncHostError::ResponseFinish) => {
+              self.start_streaming();
+              self.finish_streaming();
+              self.want_writable();
               return ExecutionResult::Continue;
             },
-            _ => { panic!("got host error: {:?}", err) }
+            Some(host::AsyncHostError::Sleep(ms)) => {
+              let limit = self.instance.as_ref().and_then(|instance| instance.limits.deadline);
+              self.deadline = Some(interpreter::wait_deadline(*ms, limit));
+              self.state = Some(SessionState::Sleeping);
+              return ExecutionResult::Continue;
+            },
+            _ => {}
           }
-        },
-        _ => {
-          panic!("got trap: {:?}", t);
         }
+
+        let stack_trace = self.instance.as_ref().map(|instance| instance.stack_trace()).unwrap_or(Vec::new());
+        self.fail(InvocationError::from_trap(&t, stack_trace));
       },
-      Ok(_) => if self
-        .instance
-        .as_mut()
-        .map(|instance| {
-          println!(
-            "set up response: {:?}",
-            instance.state.borrow().prepared_response
-          );
-          instance
-            .state
-            .borrow()
-            .prepared_response
-            .status_code
-            .is_some() && instance.state.borrow().prepared_response.body.is_some()
-        })
-        .unwrap_or(false)
-      {
-        self.client.interest.insert(Ready::writable());
-        return ExecutionResult::Continue
+      Ok(_) => {
+        self.record_invocation();
+        if let (Some(app_index), Some(env)) = (self.app, self.env.as_ref()) {
+          self.config.borrow().release_instance(app_index, env);
+        }
+
+        // the head is sent, the connection is closed to show the truncation
+        if let Some(Err(error)) = self.env.as_ref().map(|env| env.borrow().check_streamed_length()) {
+          self.instance = None;
+          self.fail(error);
+          return ExecutionResult::Continue;
+        }
+
+        if self.job.is_some() {
+          self.finish_job(Ok(()));
+          return ExecutionResult::Continue;
+        }
+
+        if self.streaming.is_some() {
+          self.handling_error = None;
+          self.finish_streaming();
+          self.want_writable();
+          self.state = Some(SessionState::Finishing);
+          return ExecutionResult::Continue;
+        }
+
+        let response = self.env.as_ref().map(|env| env.borrow().prepared_response.clone()).unwrap();
+        debug!(self.context; "set up response: {:?}", response);
+        if response.status_code.is_none() {
+          self.fail(InvocationError::NoResponse);
+        } else {
+          self.handling_error = None;
+          self.queue_response(response);
+        }
       }
     }
 
     ExecutionResult::Continue
   }
 
+  /// records the execution time, fuel and memory of the invocation
+  /// once it returned or failed
+  fn record_invocation(&mut self) {
+    let (instance, app_index) = match (self.instance.as_ref(), self.app) {
+      (Some(instance), Some(app_index)) => (instance, app_index),
+      _ => return,
+    };
+
+    let config = self.config.borrow();
+    let app = &config.applications[app_index];
+    let fuel = app.fuel.and_then(|fuel| instance.limits.fuel.map(|left| fuel - left));
+    let pages = self
+      .env
+      .as_ref()
+      .and_then(|env| env.borrow().memory.as_ref().map(|memory| memory.current_size().0));
+    config.metrics.invocation(&app.name(), self.guest_time, fuel, pages);
+    self.guest_time = Duration::from_secs(0);
+  }
+
+  /// records how long the guest waited for a backend operation
+  fn backend_done(&mut self, operation: Backend) {
+    if let Some(started) = self.io_started.take() {
+      self.config.borrow().metrics.backend(operation, started.elapsed());
+    }
+  }
+
+  /// resumes the guest with -1, for an fd that is not an open backend
+  fn unknown_backend(&mut self, fd: i32) -> ExecutionResult {
+    warn!(self.context; "no backend connection for fd {}", fd);
+    self.instance.as_mut().map(|instance| instance.add_function_result(RuntimeValue::I64(-1)));
+    self.state = Some(SessionState::Executing);
+    ExecutionResult::Continue
+  }
+
+  /// records the response once it was sent, or the client went away
+  fn record_request(&self) {
+    if let (Some(_), Some(status)) = (self.client.as_ref(), self.status) {
+      let config = self.config.borrow();
+      let route = self.route.as_ref().map(|route| route.as_str()).unwrap_or("none");
+      config.metrics.request(route, status, self.started.elapsed());
+
+      if let Some(ref access_log) = config.access_log {
+        access_log.write(&Entry {
+          client: self.peer,
+          method: self.method.as_ref().map(|method| method.as_str()).unwrap_or("-"),
+          path: self.path.as_ref().map(|path| path.as_str()).unwrap_or("-"),
+          status,
+          bytes: self.bytes,
+          received: self.received,
+          duration: self.started.elapsed(),
+          user_agent: http::get_header(&self.headers, "User-Agent"),
+          referer: http::get_header(&self.headers, "Referer"),
+          app: self.context.app.as_ref().map(|app| app.as_str()),
+          request_id: self.context.request_id,
+        });
+      }
+    }
+  }
+
+  /// writes the status line and headers before the first chunk
+  fn start_streaming(&mut self) {
+    if self.streaming.is_some() {
+      return;
+    }
+
+    let response = self.env.as_ref().map(|env| env.borrow().prepared_response.clone()).unwrap();
+    let chunked = self.write_head(&response, None);
+    self.streaming = Some(Streaming { chunked, finished: false });
+  }
+
+  fn finish_streaming(&mut self) {
+    if let Some(ref mut streaming) = self.streaming {
+      if !streaming.finished {
+        streaming.finished = true;
+        if streaming.chunked {
+          self.output.extend_from_slice(b"0\r\n\r\n");
+        }
+      }
+    }
+  }
+
+  fn want_writable(&mut self) {
+    if let Some(ref mut client) = self.client {
+      client.interest.insert(Ready::writable());
+    }
+  }
+
+  /// writes as much of `output` as the client accepts.
+  /// Returns true once everything was written
+  fn flush_output(&mut self) -> io::Result<bool> {
+    let client = match self.client {
+      Some(ref mut client) => client,
+      None => {
+        self.output.clear();
+        return Ok(true);
+      }
+    };
+
+    while !self.output.is_empty() {
+      match client.stream.write(&self.output) {
+        Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "client closed the connection")),
+        Ok(sz) => {
+          self.output.drain(..sz);
+        }
+        Err(e) => {
+          if e.kind() == ErrorKind::WouldBlock {
+            client.readiness.remove(Ready::writable());
+            return Ok(false);
+          }
+          return Err(e);
+        }
+      }
+    }
+
+    // client events must not wake the session up while it waits on something else
+    client.interest.remove(Ready::writable());
+    Ok(true)
+  }
+
   pub fn create_instance(&mut self) -> ExecutionResult {
-    let method = self.method.as_ref().unwrap();
-    let path = self.path.as_ref().unwrap();
-    if let Some((func_name, module, ref opt_env)) = self.config.borrow().route(method, path) {
-      let mut env = host::State::new();
-      if let Some(h) = opt_env {
-        env.db.extend(
-          h.iter()
-            .map(|(ref k, ref v)| (k.to_string(), v.to_string())),
-        );
+    let method = self.method.clone().unwrap();
+    let path = self.path.clone().unwrap();
+    let config = self.config.clone();
+    let config = config.borrow();
+
+    match config.route(&method, &path) {
+      Some(Route::Application(app_index, app, _)) => {
+        self.app = Some(app_index);
+        self.route = Some(app.name());
+        if let Err(error) = self.instantiate(&config, app_index, &app.function, &[]) {
+          self.fail(error);
+        }
+      }
+      Some(Route::Static(dir, files, relative)) => {
+        self.route = Some(format!("static {}", dir.url_prefix));
+        let response = static_files::serve(dir, files, &method, &path, relative, &self.headers);
+        self.queue_response(response);
       }
+      None => {
+        info!(self.context; "route not found: {} {}", method, path);
+        self.send_response(404, b"Route not found\n".to_vec());
+      }
+    }
 
-      let env = Rc::new(RefCell::new(env));
-      self.env = Some(env.clone());
-      let resolver = host::StateResolver { inner: env.clone() };
+    ExecutionResult::Continue
+  }
 
-      let main = ModuleInstance::new(&module, &ImportsBuilder::new().with_resolver("env", &resolver))
-        .expect("Failed to instantiate module")
-        .assert_no_start();
+  /// prepares an instance that will run the exported function
+  fn instantiate(
+    &mut self,
+    config: &ApplicationState,
+    app_index: usize,
+    function: &str,
+    args: &[RuntimeValue],
+  ) -> Result<(), InvocationError> {
+    self.context = config.log_context(app_index, self.context.request_id);
+    let mut env = config.host_state(app_index);
+    env.log_context = self.context.clone();
+    if let (Some(method), Some(path)) = (self.method.clone(), self.path.clone()) {
+      env.request = registry::Request {
+        method,
+        path,
+        headers: self.headers.clone(),
+        body: self.body.clone(),
+      };
+    }
+    let env = Rc::new(RefCell::new(env));
+    self.env = Some(env.clone());
+    let mut limits = config.limits(app_index);
+    let main = config.instance(app_index, &env, &mut limits)?;
 
-      if let Some(ExternVal::Func(func_ref)) = main.export_by_name(func_name) {
-        let instance = WasmInstance::new(env, &func_ref, &[]);
-        self.instance = Some(instance);
-        ExecutionResult::Continue
-      } else {
-        println!("function not found");
-        self
-          .client
-          .stream
-          .write(b"HTTP/1.1 404 Not Found\r\nContent-length: 19\r\n\r\nFunction not found\n");
-        self.client.stream.shutdown(Shutdown::Both);
-        self.client.interest = UnixReady::from(Ready::empty());
-        ExecutionResult::Close(vec![self.client.index])
+    let func_ref = match main.export_by_name(function) {
+      Some(ExternVal::Func(func_ref)) => func_ref,
+      _ => return Err(InvocationError::MissingExport(function.to_string())),
+    };
+    if func_ref.signature().params().len() != args.len() {
+      return Err(InvocationError::Instantiation(format!(
+        "{} takes {} arguments instead of {}",
+        function,
+        func_ref.signature().params().len(),
+        args.len()
+      )));
+    }
+
+    let mut instance = WasmInstance::new(env, &func_ref, args);
+    instance.limits = limits;
+    self.instance = Some(instance);
+    self.state = Some(SessionState::Executing);
+    Ok(())
+  }
+
+  /// the invocation failed. The application's error handler gets a chance
+  /// to build the response, otherwise the configured error page is sent.
+  /// If the response was already started, the connection is closed without
+  /// terminating it so the client sees it as truncated
+  fn fail(&mut self, error: InvocationError) {
+    error!(self.context; "invocation error: {:?}", error);
+    self.record_invocation();
+    self.instance = None;
+    self.deadline = None;
+
+    if self.job.is_some() {
+      self.finish_job(Err(error));
+      return;
+    }
+
+    if self.streaming.is_some() {
+      self.want_writable();
+      self.state = Some(SessionState::Finishing);
+      return;
+    }
+
+    let app_index = match self.app {
+      Some(app_index) => app_index,
+      None => {
+        self.send_response(error.status(), error.message().as_bytes().to_vec());
+        return;
+      }
+    };
+
+    let config = self.config.clone();
+    let config = config.borrow();
+
+    // the error handler runs at most once, and the original error is
+    // reported if it fails too
+    if self.handling_error.is_none() {
+      if let Some(ref handler) = config.applications[app_index].error_handler {
+        match self.instantiate(&config, app_index, handler, &error.handler_args()) {
+          Ok(()) => {
+            self.handling_error = Some(error);
+            return;
+          }
+          Err(e) => error!(self.context; "could not run the error handler: {:?}", e),
+        }
       }
+    }
+
+    let error = self.handling_error.take().unwrap_or(error);
+    let response = config.error_response(app_index, &error);
+    self.queue_response(response);
+  }
+
+  /// records the result of a scheduled function, then closes the session
+  fn finish_job(&mut self, result: Result<(), InvocationError>) {
+    if let Some(ref job) = self.job {
+      let run = ScheduleRun {
+        started: job.started,
+        duration: job.timer.elapsed(),
+        result,
+      };
+      info!(self.context; "schedule {} finished: {:?}", job.schedule, run);
+      let mut config = self.config.borrow_mut();
+      let name = config.applications[config.schedules[job.schedule].app_index].name();
+      config.metrics.schedule_run(&name, run.started, run.duration, run.result.is_ok());
+      config.schedules[job.schedule].last_run = Some(run);
+    }
+
+    self.instance = None;
+    self.state = Some(SessionState::Finishing);
+  }
+
+  /// closes the session and the backend connections it still holds
+  fn close(&self) -> ExecutionResult {
+    let mut tokens = vec![self.token];
+    tokens.extend(self.backends.keys().cloned());
+    ExecutionResult::Close(tokens)
+  }
+
+  /// the next time the session must be executed even without events
+  pub fn next_deadline(&self) -> Option<Instant> {
+    let timeout = self.instance.as_ref().and_then(|instance| instance.limits.deadline);
+    match (self.deadline, timeout) {
+      (Some(a), Some(b)) => Some(if a < b { a } else { b }),
+      (a, b) => a.or(b),
+    }
+  }
+
+  /// true if the invocation timed out while waiting for I/O or time
+  fn timed_out(&self, state: &SessionState) -> bool {
+    let waiting = match *state {
+      SessionState::WaitingForBackendConnect(_)
+      | SessionState::TcpRead(..)
+      | SessionState::TcpWrite(..)
+      | SessionState::Polling(..)
+      | SessionState::Sleeping
+      | SessionState::ResponseWrite(_) => true,
+      _ => false,
+    };
+
+    waiting
+      && self
+        .instance
+        .as_ref()
+        .and_then(|instance| instance.limits.deadline)
+        .map(|deadline| deadline <= Instant::now())
+        .unwrap_or(false)
+  }
+
+  /// sends a plain text response generated by the server
+  fn send_response(&mut self, status: u16, body: Vec<u8>) {
+    let mut response = host::PreparedResponse::new();
+    response.status_code = Some(status);
+    response.headers.push(("Content-Type".to_string(), "text/plain".to_string()));
+    response.body = Some(body);
+    self.queue_response(response);
+  }
+
+  /// serializes a complete response in the output buffer, to be sent
+  /// when the client socket is writable. Responses to HEAD requests keep
+  /// the length of the body they would have sent
+  fn queue_response(&mut self, response: host::PreparedResponse) {
+    let status = response.status_code.unwrap_or(500);
+    let body = if http::status_has_body(status) {
+      response.body.clone().unwrap_or(Vec::new())
     } else {
-      println!("route not found");
+      Vec::new()
+    };
+
+    self.write_head(&response, Some(body.len()));
+    if self.method.as_ref().map(|m| m.as_str()) != Some("HEAD") {
+      self.output.extend_from_slice(&body);
+      self.queued_body = body.len() as u64;
+    }
+
+    self.want_writable();
+    self.state = Some(SessionState::Finishing);
+  }
+
+  /// serializes the status line and headers. Date and Content-Length are added if
+  /// the guest did not set them. Without a body length, the body will be chunked
+  /// unless there is a Content-Length header. Returns true if the body is chunked
+  fn write_head(&mut self, response: &host::PreparedResponse, body_length: Option<usize>) -> bool {
+    let status = response.status_code.unwrap_or(200);
+    self.status = Some(status);
+    let reason = response
+      .reason
+      .clone()
+      .unwrap_or_else(|| http::reason_phrase(status).to_string());
+
+    self.output.extend_from_slice(format!("HTTP/1.1 {} {}\r\n", status, reason).as_bytes());
+    for header in response.headers.iter() {
+      self.output.extend_from_slice(format!("{}: {}\r\n", header.0, header.1).as_bytes());
+    }
+
+    if !http::has_header(&response.headers, "Date") {
       self
-        .client
-        .stream
-        .write(b"HTTP/1.1 404 Not Found\r\nContent-length: 16\r\n\r\nRoute not found\n");
-      self.client.stream.shutdown(Shutdown::Both);
-      self.client.interest = UnixReady::from(Ready::empty());
-      ExecutionResult::Close(vec![self.client.index])
+        .output
+        .extend_from_slice(format!("Date: {}\r\n", http::http_date(SystemTime::now())).as_bytes());
     }
+
+    let mut chunked = false;
+    if http::status_has_body(status) && !http::has_header(&response.headers, "Content-Length") {
+      match body_length {
+        Some(length) => self
+          .output
+          .extend_from_slice(format!("Content-Length: {}\r\n", length).as_bytes()),
+        None => {
+          chunked = true;
+          self.output.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+        }
+      }
+    }
+    self.output.extend_from_slice(b"\r\n");
+
+    chunked
   }
 
   pub fn process_events(&mut self, token: usize, events: Ready) -> bool {
-    println!("client[{}]:  token {} got events {:?}", self.client.index, token, events);
-    if token == self.client.index {
-      self.client.readiness = self.client.readiness | UnixReady::from(events);
-
-      self.client.readiness & self.client.interest != UnixReady::from(Ready::empty())
+    trace!(self.context; "client[{}]: token {} got events {:?}", self.token, token, events);
+    if token == self.token {
+      match self.client {
+        Some(ref mut client) => {
+          client.readiness = client.readiness | UnixReady::from(events);
+          client.readiness & client.interest != UnixReady::from(Ready::empty())
+        }
+        None => false,
+      }
     } else {
       if let Some(ref mut stream) = self.backends.get_mut(&token) {
-        println!("state: {:?}", self.state);
+        trace!(self.context; "state: {:?}", self.state);
         if self.state == Some(SessionState::WaitingForBackendConnect(token)) {
           self.instance.as_mut().map(|instance| instance.add_function_result(RuntimeValue::I32(token as i32)));
           self.state = Some(SessionState::Executing);
+          if let Some(started) = self.io_started.take() {
+            self.config.borrow().metrics.backend(Backend::Connect, started.elapsed());
+          }
         }
 
         stream.readiness.insert(UnixReady::from(events));
         stream.readiness & stream.interest != UnixReady::from(Ready::empty())
       } else {
-        println!("non existing backend {} got events {:?}", token, events);
+        debug!(self.context; "non existing backend {} got events {:?}", token, events);
         false
       }
     }
@@ -230,22 +802,19 @@ impl Session {
 
   pub fn execute(&mut self) -> ExecutionResult {
     loop {
-      let front_readiness = self.client.readiness & self.client.interest;
+      let front_readable = self
+        .client
+        .as_ref()
+        .map(|client| (client.readiness & client.interest).is_readable())
+        .unwrap_or(false);
 
-      if front_readiness.is_readable() {
+      if front_readable {
         let res = self.front_readable();
         if res != ExecutionResult::Continue {
           return res;
         }
       }
 
-      if front_readiness.is_writable() {
-        let res = self.front_writable();
-        if res != ExecutionResult::Continue {
-          return res;
-        }
-      }
-
       let res = self.process();
       if res != ExecutionResult::Continue {
         return res;
@@ -256,25 +825,30 @@ impl Session {
 
   fn front_readable(&mut self) -> ExecutionResult {
     if self.state == Some(SessionState::WaitingForRequest) {
+      let client = match self.client {
+        Some(ref mut client) => client,
+        None => return ExecutionResult::Continue,
+      };
+
       loop {
         if self.buffer.offset + self.buffer.len == self.buffer.buf.len() {
           break;
         }
 
-        match self
-          .client
+        match client
           .stream
           .read(&mut self.buffer.buf[self.buffer.offset + self.buffer.len..])
         {
           Ok(0) => {
-            return ExecutionResult::Close(vec![self.client.index]);
+            // no backends while waiting for the request
+            return ExecutionResult::Close(vec![self.token]);
           }
           Ok(sz) => {
             self.buffer.len += sz;
           }
           Err(e) => {
             if e.kind() == ErrorKind::WouldBlock {
-              self.client.readiness.remove(Ready::readable());
+              client.readiness.remove(Ready::readable());
               break;
             }
           }
@@ -283,18 +857,23 @@ impl Session {
 
       ExecutionResult::Continue
     } else {
-      ExecutionResult::Close(vec![self.client.index])
+      self.close()
     }
   }
 
   fn process(&mut self) -> ExecutionResult {
-    println!("[{}] process", self.client.index);
+    trace!(self.context; "[{}] process", self.token);
 
     let state = self.state.take().unwrap();
+    if self.timed_out(&state) {
+      self.fail(InvocationError::Timeout);
+      return ExecutionResult::Continue;
+    }
+
     match state {
       SessionState::WaitingForRequest => {
 
-        let (method, path) = {
+        let parsed = {
           let mut headers = [httparse::Header {
             name: "",
             value: &[],
@@ -302,69 +881,118 @@ impl Session {
           let mut req = httparse::Request::new(&mut headers);
           match req.parse(&self.buffer.buf[self.buffer.offset..self.buffer.len]) {
             Err(e) => {
-              println!("http parsing error: {:?}", e);
-              self.state = Some(SessionState::WaitingForRequest);
-              return ExecutionResult::Close(vec![self.client.index]);
+              debug!(self.context; "http parsing error: {:?}", e);
+              Err(400)
             }
             Ok(httparse::Status::Partial) => {
               self.state = Some(SessionState::WaitingForRequest);
-              return ExecutionResult::Continue;
+              return ExecutionResult::WouldBlock;
             }
             Ok(httparse::Status::Complete(sz)) => {
-              self.buffer.offset += sz;
-              println!("got request: {:?}", req);
-              (
-                req.method.unwrap().to_string(),
-                req.path.unwrap().to_string(),
-              )
+              let headers: Vec<(String, String)> = req
+                .headers
+                .iter()
+                .map(|h| (h.name.to_string(), String::from_utf8_lossy(h.value).into_owned()))
+                .collect();
+              let start = self.buffer.offset + sz;
+              match http::content_length(&headers) {
+                Err(e) => {
+                  debug!(self.context; "{}", e);
+                  Err(400)
+                }
+                Ok(length) if length > http::MAX_REQUEST_BODY => Err(413),
+                Ok(length) if start + length > self.buffer.buf.len() => {
+                  // the buffer grows to hold the whole body after the headers,
+                  // then front_readable reads the rest
+                  self.buffer.buf.resize(start + length, 0);
+                  self.state = Some(SessionState::WaitingForRequest);
+                  return ExecutionResult::Continue;
+                }
+                Ok(length) if start + length > self.buffer.len => {
+                  self.state = Some(SessionState::WaitingForRequest);
+                  return ExecutionResult::WouldBlock;
+                }
+                Ok(length) => {
+                  debug!(self.context; "got request: {:?}", req);
+                  self.buffer.offset = start + length;
+                  self.body = self.buffer.buf[start..start + length].to_vec();
+                  self.headers = headers;
+                  Ok((
+                    req.method.unwrap().to_string(),
+                    req.path.unwrap().to_string(),
+                  ))
+                }
+              }
             }
           }
         };
 
-        self.client.interest.remove(Ready::readable());
+        if let Some(ref mut client) = self.client {
+          client.interest.remove(Ready::readable());
+        }
+        let (method, path) = match parsed {
+          Ok(request) => request,
+          Err(413) => {
+            self.send_response(413, b"Payload Too Large\n".to_vec());
+            return ExecutionResult::Continue;
+          }
+          Err(status) => {
+            self.send_response(status, b"Bad Request\n".to_vec());
+            return ExecutionResult::Continue;
+          }
+        };
+
         self.method = Some(method);
         self.path   = Some(path);
         self.state  = Some(SessionState::Executing);
         ExecutionResult::Continue
       },
       SessionState::Executing => {
+        self.state = Some(SessionState::Executing);
         if self.instance.is_none() {
           let res = self.create_instance();
-          if res != ExecutionResult::Continue {
-            self.state = Some(SessionState::Executing);
+          // no instance means an error response was queued
+          if self.instance.is_none() {
             return res;
           }
         }
 
-        println!("resuming");
-        self.state = Some(SessionState::Executing);
+        trace!(self.context; "resuming");
         self.resume()
       },
       SessionState::TcpRead(fd, ptr, sz) => {
-        let readiness = self.backends[&(fd as usize)].readiness & self.backends[&(fd as usize)].interest;
-        println!("tcpread({}): readiness: {:?}", fd, readiness);
+        let readiness = match self.backends.get(&(fd as usize)) {
+          Some(backend) => backend.readiness & backend.interest,
+          None => return self.unknown_backend(fd),
+        };
+        trace!(self.context; "tcpread({}): readiness: {:?}", fd, readiness);
         if readiness.is_readable() {
+          self.backend_done(Backend::Read);
           let mut buffer = Vec::with_capacity(sz as usize);
           buffer.extend(repeat(0).take(sz as usize));
           let mut read = 0usize;
 
           loop {
-            match self.backends.get_mut(&(fd as usize)).unwrap().stream.read(&mut buffer[read..]) {
+            let res = match self.backends.get_mut(&(fd as usize)) {
+              Some(backend) => backend.stream.read(&mut buffer[read..]),
+              None => return self.unknown_backend(fd),
+            };
+            match res {
               Ok(0) => {
-                println!("read 0");
+                trace!(self.context; "read 0");
                 self.backends.get_mut(&(fd as usize)).map(|backend| backend.readiness.remove(Ready::readable()));
-                self.env.as_mut().map(|env| env.borrow_mut().write_buf(ptr, &buffer[..read]));
+                self.env.as_mut().map(|env| env.borrow().write_bytes(ptr, &buffer[..read]));
                 self.instance.as_mut().map(|instance| instance.add_function_result(RuntimeValue::I64(read as i64)));
                 self.state = Some(SessionState::Executing);
                 return ExecutionResult::Continue;
               },
               Ok(sz) => {
                 read += sz;
-                println!("read {} bytes", read);
+                trace!(self.context; "read {} bytes", read);
 
                 if read == sz {
                   //FIXME: return result
-                  self.env.as_mut().map(|env| env.borrow_mut().write_buf(ptr, &buffer[..read]));
+                  self.env.as_mut().map(|env| env.borrow().write_bytes(ptr, &buffer[..read]));
                   self.instance.as_mut().map(|instance| instance.add_function_result(RuntimeValue::I64(read as i64)));
                   self.state = Some(SessionState::Executing);
                   return ExecutionResult::Continue;
@@ -372,15 +1000,15 @@ impl Session {
               },
               Err(e) => match e.kind() {
                 ErrorKind::WouldBlock => {
-                  println!("wouldblock");
+                  trace!(self.context; "wouldblock");
                   self.backends.get_mut(&(fd as usize)).map(|backend| backend.readiness.remove(Ready::readable()));
-                  self.env.as_mut().map(|env| env.borrow_mut().write_buf(ptr, &buffer[..read]));
+                  self.env.as_mut().map(|env| env.borrow().write_bytes(ptr, &buffer[..read]));
                   self.instance.as_mut().map(|instance| instance.add_function_result(RuntimeValue::I64(read as i64)));
                   self.state = Some(SessionState::Executing);
                   return ExecutionResult::Continue;
                 },
                 e => {
-                  println!("backend socket error: {:?}", e);
+                  warn!(self.context; "backend socket error: {:?}", e);
                   self.instance.as_mut().map(|instance| instance.add_function_result(RuntimeValue::I64(-1)));
                   self.state = Some(SessionState::Executing);
                   //FIXME
@@ -395,11 +1023,19 @@ impl Session {
         }
       },
       SessionState::TcpWrite(fd, buffer, mut written) => {
-        let readiness = self.backends[&(fd as usize)].readiness & self.backends[&(fd as usize)].interest;
+        let readiness = match self.backends.get(&(fd as usize)) {
+          Some(backend) => backend.readiness & backend.interest,
+          None => return self.unknown_backend(fd),
+        };
         if readiness.is_writable() {
           loop {
-            match self.backends.get_mut(&(fd as usize)).unwrap().stream.write(&buffer[written..]) {
+            let res = match self.backends.get_mut(&(fd as usize)) {
+              Some(backend) => backend.stream.write(&buffer[written..]),
+              None => return self.unknown_backend(fd),
+            };
+            match res {
               Ok(0) => {
+                self.backend_done(Backend::Write);
                 self.backends.get_mut(&(fd as usize)).map(|backend| backend.readiness.remove(Ready::writable()));
                 self.instance.as_mut().map(|instance| instance.add_function_result(RuntimeValue::I64(written as i64)));
                 self.state = Some(SessionState::Executing);
@@ -407,10 +1043,11 @@ impl Session {
               },
               Ok(sz) => {
                 written += sz;
-                println!("wrote {} bytes", sz);
+                trace!(self.context; "wrote {} bytes", sz);
 
                 if written == buffer.len() {
                   //FIXME: return result
+                  self.backend_done(Backend::Write);
                   self.state = Some(SessionState::Executing);
                   self.instance.as_mut().map(|instance| instance.add_function_result(RuntimeValue::I64(written as i64)));
                   return ExecutionResult::Continue;
@@ -418,13 +1055,14 @@ impl Session {
               },
               Err(e) => match e.kind() {
                 ErrorKind::WouldBlock => {
-                  println!("wouldblock");
+                  trace!(self.context; "wouldblock");
                   self.backends.get_mut(&(fd as usize)).map(|backend| backend.readiness.remove(Ready::writable()));
                   self.state = Some(SessionState::TcpWrite(fd, buffer, written));
                   return ExecutionResult::Continue;
                 },
                 e => {
-                  println!("backend socket error: {:?}", e);
+                  warn!(self.context; "backend socket error: {:?}", e);
+                  self.backend_done(Backend::Write);
                   self.instance.as_mut().map(|instance| instance.add_function_result(RuntimeValue::I64(-1)));
                   self.state = Some(SessionState::Executing);
                   //FIXME
@@ -443,36 +1081,98 @@ impl Session {
         //FIXME: handle error and hup
 
       },
-      SessionState::WaitingForBackendConnect(_) => {
-        panic!("should not have called execute() in WaitingForBackendConnect");
+      SessionState::Polling(ptr, entries) => {
+        let revents: Vec<i32> = entries
+          .iter()
+          .map(|&(fd, events)| match self.backends.get(&(fd as usize)) {
+            None => host::POLL_ERROR,
+            Some(backend) => {
+              let mut revents = 0;
+              if events & host::POLL_READABLE != 0 && backend.readiness.is_readable() {
+                revents |= host::POLL_READABLE;
+              }
+              if events & host::POLL_WRITABLE != 0 && backend.readiness.is_writable() {
+                revents |= host::POLL_WRITABLE;
+              }
+              if backend.readiness.is_hup() {
+                revents |= host::POLL_HUP;
+              }
+              if backend.readiness.is_error() {
+                revents |= host::POLL_ERROR;
+              }
+              revents
+            }
+          })
+          .collect();
+
+        let ready_count = revents.iter().filter(|r| **r != 0).count();
+        let timed_out = self.deadline.map(|deadline| deadline <= Instant::now()).unwrap_or(false);
+        trace!(self.context; "poll: {:?} -> {:?}, timed out: {}", entries, revents, timed_out);
+
+        if ready_count > 0 || timed_out {
+          self.env.as_mut().map(|env| env.borrow().set_poll_results(ptr, &revents));
+          self.instance.as_mut().map(|instance| instance.add_function_result(RuntimeValue::I32(ready_count as i32)));
+          self.deadline = None;
+          self.state = Some(SessionState::Executing);
+          ExecutionResult::Continue
+        } else {
+          self.state = Some(SessionState::Polling(ptr, entries));
+          ExecutionResult::WouldBlock
+        }
+      },
+      SessionState::Sleeping => {
+        if self.deadline.map(|deadline| deadline <= Instant::now()).unwrap_or(true) {
+          self.deadline = None;
+          self.state = Some(SessionState::Executing);
+          ExecutionResult::Continue
+        } else {
+          self.state = Some(SessionState::Sleeping);
+          ExecutionResult::WouldBlock
+        }
+      },
+      SessionState::ResponseWrite(sz) => match self.flush_output() {
+        Ok(true) => {
+          self.bytes += sz;
+          self.instance.as_mut().map(|instance| instance.add_function_result(RuntimeValue::I64(sz as i64)));
+          self.state = Some(SessionState::Executing);
+          ExecutionResult::Continue
+        }
+        Ok(false) => {
+          self.state = Some(SessionState::ResponseWrite(sz));
+          ExecutionResult::WouldBlock
+        }
+        Err(e) => {
+          debug!(self.context; "client socket error: {:?}", e);
+          self.record_request();
+          self.state = Some(SessionState::Done);
+          self.close()
+        }
+      },
+      SessionState::Finishing => match self.flush_output() {
+        Ok(false) => {
+          self.state = Some(SessionState::Finishing);
+          ExecutionResult::WouldBlock
+        }
+        res => {
+          if let Err(e) = res {
+            debug!(self.context; "client socket error: {:?}", e);
+          }
+          // the body ends the output, what is left of it was not sent
+          self.bytes += self.queued_body.saturating_sub(self.output.len() as u64);
+          self.queued_body = 0;
+          self.record_request();
+          self.state = Some(SessionState::Done);
+          self.close()
+        }
+      },
+      SessionState::WaitingForBackendConnect(token) => {
+        // woken up by a client event, the backend's event resumes the guest
+        self.state = Some(SessionState::WaitingForBackendConnect(token));
+        ExecutionResult::WouldBlock
       },
       SessionState::Done => {
         panic!("done");
       }
     }
   }
-
-  fn front_writable(&mut self) -> ExecutionResult {
-    println!("[{}] front writable", self.client.index);
-    let response = self
-      .instance
-      .as_mut()
-      .map(|instance| instance.state.borrow().prepared_response.clone())
-      .unwrap();
-
-    self
-      .client
-      .stream
-      .write_fmt(format_args!("HTTP/1.1 {} {}\r\n", response.status_code.unwrap(), response.reason.unwrap()));
-    for header in response.headers.iter() {
-      self
-        .client
-        .stream
-        .write_fmt(format_args!("{}: {}\r\n", header.0, header.1));
-    }
-    self.client.stream.write(b"\r\n");
-    self.client.stream.write(&response.body.unwrap()[..]);
-
-    ExecutionResult::Close(vec![self.client.index])
-  }
 }
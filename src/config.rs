@@ -1,53 +1,425 @@
-use interpreter::load_module;
-use std::collections::HashMap;
+use access_log::{self, AccessLog};
+use cron::Cron;
+use errors::InvocationError;
+use inspect;
+use instances::{self, Snapshot};
+use interpreter::{load_module, Limits};
+use kv;
+use logging::{self, Context, RateLimiter};
+use metrics::Metrics;
+use registry::{PreparedResponse, State};
+use secrets::{self, SecretConfig, SecretValue};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Read;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use toml;
-use wasmi::Module;
+use vfs::Vfs;
+use wasmi::{Module, ModuleRef};
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct WasmApp {
   pub file_path: String,
   pub method: String,
   pub url_path: String,
   pub function: String,
   pub env: Option<HashMap<String, String>>,
+  /// maximum number of idle connections kept per backend address
+  /// for `tcp_connect_pooled`. Pooled connections are not kept if absent
+  pub backend_pool_size: Option<usize>,
+  /// namespace in the key-value store. Applications without one
+  /// can only read their `env` values
+  pub kv_namespace: Option<String>,
+  pub kv_max_keys: Option<usize>,
+  /// maximum size of keys and values stored in the namespace
+  pub kv_max_bytes: Option<usize>,
+  /// names of the secrets this application can read with `secret_get`
+  pub secrets: Option<Vec<String>>,
+  /// export called with `(status: i32, kind: i32)` to build the response
+  /// when the function fails
+  pub error_handler: Option<String>,
+  /// file sent as HTML when the function fails and there is no error handler
+  pub error_page: Option<String>,
+  /// adds the failure cause and a stack trace to error responses
+  pub debug: Option<bool>,
+  pub timeout_ms: Option<u64>,
+  /// maximum number of function calls and loop iterations per invocation
+  pub fuel: Option<u64>,
+  /// directory or tar archive the application reads with the `fs_*`
+  /// functions, also preopened as `/` for WASI guests. Read only
+  pub mount: Option<String>,
+  /// "fresh" (the default) runs each request in a new instance, or one
+  /// restored from the snapshot. "persistent" instances keep their memory
+  /// from one request to the next
+  pub instance_mode: Option<String>,
+  /// persistent instances kept alive, per worker or thread. 1 by default
+  pub persistent_instances: Option<usize>,
+  /// a persistent instance is replaced after serving this many requests
+  pub max_requests_per_instance: Option<u64>,
+  /// a persistent instance is replaced once its memory grew past this size, in bytes
+  pub max_instance_memory: Option<usize>,
+  /// set for the applications created from `[[schedules]]`, which
+  /// have no route and no response
+  #[serde(skip)]
+  pub scheduled: bool,
 }
 
-#[derive(Deserialize, Debug)]
+/// a function run on a timer instead of a request
+#[derive(Deserialize, Debug, Clone)]
+pub struct Schedule {
+  /// cron expression, in UTC
+  pub cron: String,
+  pub file_path: String,
+  pub function: String,
+  pub env: Option<HashMap<String, String>>,
+  pub timeout_ms: Option<u64>,
+  pub fuel: Option<u64>,
+  /// like the application settings of the same names
+  pub kv_namespace: Option<String>,
+  pub kv_max_keys: Option<usize>,
+  pub kv_max_bytes: Option<usize>,
+  pub secrets: Option<Vec<String>>,
+  pub mount: Option<String>,
+}
+
+impl Schedule {
+  /// the application running the function, without a route
+  pub fn application(&self) -> WasmApp {
+    WasmApp {
+      file_path: self.file_path.clone(),
+      function: self.function.clone(),
+      env: self.env.clone(),
+      timeout_ms: self.timeout_ms,
+      fuel: self.fuel,
+      kv_namespace: self.kv_namespace.clone(),
+      kv_max_keys: self.kv_max_keys,
+      kv_max_bytes: self.kv_max_bytes,
+      secrets: self.secrets.clone(),
+      mount: self.mount.clone(),
+      scheduled: true,
+      ..Default::default()
+    }
+  }
+}
+
+/// files served without a WASM application
+#[derive(Deserialize, Debug, Clone)]
+pub struct StaticDir {
+  /// requests to this path and below are served from `directory`
+  pub url_prefix: String,
+  pub directory: String,
+  /// files sent for a directory, `index.html` by default
+  pub index: Option<Vec<String>>,
+}
+
+impl WasmApp {
+  /// identifies the application in metrics and logs
+  pub fn name(&self) -> String {
+    if self.scheduled {
+      format!("schedule {}", self.function)
+    } else {
+      format!("{} {}", self.method, self.url_path)
+    }
+  }
+
+  pub fn is_persistent(&self) -> bool {
+    self.instance_mode.as_ref().map(|mode| mode == "persistent").unwrap_or(false)
+  }
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct Config {
   pub listen_address: String,
+  /// number of event loops accepting connections, for the async server
+  pub workers: Option<usize>,
+  /// idle instances kept per module (and per worker or thread) to serve
+  /// the next requests. Instances are not reused if absent
+  pub instance_pool_size: Option<usize>,
+  /// address of the listener serving `/metrics` in the Prometheus text
+  /// format. Metrics are not exposed if absent
+  pub metrics_address: Option<String>,
+  /// "error", "warn", "info" (the default), "debug" or "trace"
+  pub log_level: Option<String>,
+  /// "logfmt" (the default) or "json"
+  pub log_format: Option<String>,
+  /// guest log lines written per second for each application, 100 by
+  /// default. Lines over the limit are dropped
+  pub guest_log_rate: Option<u32>,
+  /// file receiving one line per response, reopened on SIGUSR1
+  pub access_log: Option<String>,
+  /// "common", "combined" (the default) or "json"
+  pub access_log_format: Option<String>,
+  /// where the key-value store keeps its files
+  pub kv_directory: Option<String>,
+  pub secrets: Option<Vec<SecretConfig>>,
   pub applications: Vec<WasmApp>,
+  #[serde(rename = "static")]
+  pub static_dirs: Option<Vec<StaticDir>>,
+  pub schedules: Option<Vec<Schedule>>,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+  Read(String),
+  Invalid(String),
+}
+
+pub fn load(file: &str) -> Result<Config, LoadError> {
+  let mut contents = String::new();
+  File::open(file)
+    .and_then(|mut file| file.read_to_string(&mut contents))
+    .map_err(|e| LoadError::Read(format!("could not read {}: {}", file, e)))?;
+
+  toml::from_str(&contents).map_err(|e| LoadError::Invalid(format!("invalid configuration in {}: {}", file, e)))
+}
+
+/// the key-value store shared by the applications. Open it once: workers with
+/// their own store would not see each other's writes
+pub fn open_kv(config: &Config) -> Option<Arc<Mutex<kv::Store>>> {
+  config.kv_directory.as_ref().map(|directory| {
+    let store = kv::Store::new(directory).expect("could not open the key-value store");
+    Arc::new(Mutex::new(store))
+  })
+}
+
+/// the access log shared by the workers, if configured
+pub fn open_access_log(config: &Config) -> Result<Option<AccessLog>, String> {
+  let path = match config.access_log {
+    Some(ref path) => path,
+    None => return Ok(None),
+  };
+  // validated by check
+  let format = config.access_log_format.as_ref().and_then(|format| access_log::Format::parse(format));
+  AccessLog::open(path, format.unwrap_or(access_log::Format::Combined))
+    .map(Some)
+    .map_err(|e| format!("could not open the access log {}: {}", path, e))
+}
+
+/// everything that would stop the server from starting, or make an
+/// application fail on every request
+pub fn check(config: &Config) -> Vec<String> {
+  let mut problems = Vec::new();
+
+  if config.listen_address.parse::<SocketAddr>().is_err() {
+    problems.push(format!("invalid listen_address '{}'", config.listen_address));
+  }
+  if let Some(ref address) = config.metrics_address {
+    if address.parse::<SocketAddr>().is_err() {
+      problems.push(format!("invalid metrics_address '{}'", address));
+    }
+  }
+  if let Some(ref level) = config.log_level {
+    if logging::Level::parse(level).is_none() {
+      problems.push(format!("unknown log_level '{}'", level));
+    }
+  }
+  if let Some(ref format) = config.log_format {
+    if logging::Format::parse(format).is_none() {
+      problems.push(format!("unknown log_format '{}'", format));
+    }
+  }
+  if let Some(ref format) = config.access_log_format {
+    if access_log::Format::parse(format).is_none() {
+      problems.push(format!("unknown access_log_format '{}'", format));
+    }
+  }
+  if config.workers == Some(0) {
+    problems.push("workers must be at least 1".to_string());
+  }
+
+  let secrets = match secrets::load(config.secrets.as_ref().map(|s| &s[..]).unwrap_or(&[])) {
+    Ok(secrets) => secrets,
+    Err(e) => {
+      problems.push(e);
+      HashMap::new()
+    }
+  };
+
+  let mut routes = HashSet::new();
+  for app in config.applications.iter() {
+    let name = format!("application {} {}", app.method, app.url_path);
+    if !routes.insert((&app.method, &app.url_path)) {
+      problems.push(format!("{} is defined twice", name));
+    }
+
+    check_function(&name, &app.file_path, &app.function, FUNCTION_SIGNATURE, &mut problems);
+    if let Some(ref handler) = app.error_handler {
+      check_function(&name, &app.file_path, handler, ERROR_HANDLER_SIGNATURE, &mut problems);
+    }
+
+    check_resources(&name, app, &secrets, &mut problems);
+    if let Some(ref path) = app.error_page {
+      if let Err(e) = File::open(path) {
+        problems.push(format!("{}: could not open error page {}: {}", name, path, e));
+      }
+    }
+    match app.instance_mode.as_ref().map(|mode| mode.as_str()) {
+      None | Some("fresh") | Some("persistent") => {}
+      Some(mode) => problems.push(format!("{}: unknown instance_mode '{}'", name, mode)),
+    }
+    if app.persistent_instances == Some(0) {
+      problems.push(format!("{}: persistent_instances must be at least 1", name));
+    }
+  }
+
+  for schedule in config.schedules.iter().flat_map(|schedules| schedules.iter()) {
+    let name = format!("schedule {}", schedule.function);
+    if let Err(e) = Cron::parse(&schedule.cron) {
+      problems.push(format!("{}: {}", name, e));
+    }
+    check_function(&name, &schedule.file_path, &schedule.function, FUNCTION_SIGNATURE, &mut problems);
+    check_resources(&name, &schedule.application(), &secrets, &mut problems);
+  }
+
+  for dir in config.static_dirs.iter().flat_map(|dirs| dirs.iter()) {
+    if let Err(e) = Vfs::open(&dir.directory) {
+      problems.push(format!("static {}: {}", dir.url_prefix, e));
+    }
+  }
+
+  problems
+}
+
+/// the secrets, mount and kv namespace of an application or schedule
+fn check_resources(name: &str, app: &WasmApp, secrets: &HashMap<String, SecretValue>, problems: &mut Vec<String>) {
+  for secret in app.secrets.iter().flat_map(|names| names.iter()) {
+    if !secrets.contains_key(secret) {
+      problems.push(format!("{} uses undefined secret '{}'", name, secret));
+    }
+  }
+  if let Some(ref path) = app.mount {
+    if let Err(e) = Vfs::open(path) {
+      problems.push(format!("{}: {}", name, e));
+    }
+  }
+  if let Some(ref namespace) = app.kv_namespace {
+    if !kv::valid_namespace(namespace) {
+      problems.push(format!("{}: invalid kv_namespace '{}'", name, namespace));
+    }
+  }
 }
 
-pub fn load(file: &str) -> Option<Config> {
-  if let Ok(mut file) = File::open(file) {
-    let mut contents = String::new();
-    if let Ok(_) = file.read_to_string(&mut contents) {
-      return toml::from_str(&contents)
-        .map_err(|e| {
-          println!("configuration deserialization error: {:?}", e);
-          e
-        })
-        .ok();
+/// signature of the functions called for requests and schedules
+const FUNCTION_SIGNATURE: &str = "()";
+/// `(status, kind)`, see `InvocationError::handler_args`
+const ERROR_HANDLER_SIGNATURE: &str = "(i32, i32)";
+
+/// the export must exist with the given signature, and all the module's
+/// imports must be provided by the host
+fn check_function(name: &str, file_path: &str, function: &str, signature: &str, problems: &mut Vec<String>) {
+  let report = match inspect::inspect(file_path) {
+    Ok(report) => report,
+    Err(e) => {
+      problems.push(format!("{}: {}", name, e));
+      return;
     }
+  };
+
+  match report.function(function) {
+    None => problems.push(format!("{}: {} has no exported function {}", name, file_path, function)),
+    Some(export) if export.signature.as_ref().map(|s| s.as_str()) != Some(signature) => problems.push(format!(
+      "{}: {} has the signature {} instead of {}",
+      name,
+      function,
+      export.signature.as_ref().map(|s| s.as_str()).unwrap_or("?"),
+      signature
+    )),
+    Some(_) => {}
+  }
+
+  for import in report.unsupported_imports() {
+    problems.push(match import.status {
+      inspect::ImportStatus::WrongSignature(ref expected) => format!(
+        "{}: {} imports {}.{} as {}, the server provides {}",
+        name,
+        file_path,
+        import.module,
+        import.name,
+        import.signature.as_ref().map(|s| s.as_str()).unwrap_or("?"),
+        expected
+      ),
+      _ => format!(
+        "{}: {} imports {}.{}, which the server does not provide",
+        name, file_path, import.module, import.name
+      ),
+    });
   }
-  None
 }
 
 pub struct ApplicationState {
-  /// (method, url path) -> (function name, module path, env)
-  pub routes: HashMap<(String, String), (String, String, Option<HashMap<String, String>>)>,
+  /// (method, url path) -> index in `applications`
+  pub routes: HashMap<(String, String), usize>,
+  pub applications: Vec<WasmApp>,
   /// module path -> Module
   pub modules: HashMap<String, Module>,
+  pub kv: Option<Arc<Mutex<kv::Store>>>,
+  /// secret name -> value
+  pub secrets: HashMap<String, SecretValue>,
+  /// application index -> error page
+  pub error_pages: HashMap<usize, Vec<u8>>,
+  /// application index -> mounted file tree
+  pub mounts: HashMap<usize, Arc<Vfs>>,
+  pub static_dirs: Vec<(StaticDir, Vfs)>,
+  pub schedules: Vec<ScheduleState>,
+  /// application index -> memory and globals of a new instance, when
+  /// instances are pooled or the module has an `init` export
+  pub snapshots: HashMap<usize, Snapshot>,
+  pub instance_pool_size: usize,
+  /// shared with the other workers
+  pub metrics: Metrics,
+  /// application index -> guest log rate limit
+  pub log_limiters: Vec<RateLimiter>,
+  /// shared with the other workers
+  pub access_log: Option<AccessLog>,
+}
+
+pub struct ScheduleState {
+  pub cron: Cron,
+  /// index in `applications` of the function to run
+  pub app_index: usize,
+  pub last_run: Option<ScheduleRun>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduleRun {
+  pub started: SystemTime,
+  pub duration: Duration,
+  pub result: Result<(), InvocationError>,
+}
+
+/// what handles a request
+pub enum Route<'a> {
+  /// (application index, configuration, module)
+  Application(usize, &'a WasmApp, &'a Module),
+  /// (directory, its files, path relative to `url_prefix`)
+  Static(&'a StaticDir, &'a Vfs, &'a str),
 }
 
 impl ApplicationState {
-  pub fn new(config: &Config) -> ApplicationState {
+  /// `kv` is the store shared by every worker, from `open_kv`
+  pub fn new(config: &Config, kv: Option<Arc<Mutex<kv::Store>>>) -> ApplicationState {
     let mut routes = HashMap::new();
     let mut modules = HashMap::new();
 
-    for app in config.applications.iter() {
+    // scheduled functions run like applications without a route
+    let mut applications = config.applications.clone();
+    let mut schedules = Vec::new();
+    for schedule in config.schedules.iter().flat_map(|schedules| schedules.iter()) {
+      let cron = Cron::parse(&schedule.cron)
+        .unwrap_or_else(|e| panic!("invalid schedule for {}: {}", schedule.function, e));
+      schedules.push(ScheduleState {
+        cron,
+        app_index: applications.len(),
+        last_run: None,
+      });
+      applications.push(schedule.application());
+    }
+
+    for (index, app) in applications.iter().enumerate() {
       //FIXME: it might be good to not panic when we don't find the function in the module
       let module = load_module(&app.file_path, &app.function);
 
@@ -55,25 +427,234 @@ impl ApplicationState {
         modules.insert(app.file_path.clone(), module);
       }
 
-      routes.insert(
-        (app.method.clone(), app.url_path.clone()),
-        (app.function.clone(), app.file_path.clone(), app.env.clone()),
-      );
+      if !app.scheduled {
+        routes.insert((app.method.clone(), app.url_path.clone()), index);
+      }
+    }
+
+    let secrets = secrets::load(config.secrets.as_ref().map(|s| &s[..]).unwrap_or(&[])).expect("could not load secrets");
+    for app in applications.iter() {
+      for name in app.secrets.iter().flat_map(|names| names.iter()) {
+        if !secrets.contains_key(name) {
+          panic!("{} uses undefined secret '{}'", app.name(), name);
+        }
+      }
+    }
+
+    let mut error_pages = HashMap::new();
+    for (index, app) in config.applications.iter().enumerate() {
+      if let Some(ref path) = app.error_page {
+        let mut page = Vec::new();
+        File::open(path)
+          .and_then(|mut file| file.read_to_end(&mut page))
+          .expect(&format!("could not read error page {}", path));
+        error_pages.insert(index, page);
+      }
     }
 
-    ApplicationState {
+    let mut mounts = HashMap::new();
+    for (index, app) in applications.iter().enumerate() {
+      if let Some(ref path) = app.mount {
+        let vfs = Vfs::open(path).unwrap_or_else(|e| panic!("{}", e));
+        mounts.insert(index, Arc::new(vfs));
+      }
+    }
+
+    let static_dirs = config
+      .static_dirs
+      .iter()
+      .flat_map(|dirs| dirs.iter())
+      .map(|dir| {
+        let files = Vfs::open(&dir.directory).unwrap_or_else(|e| panic!("{}", e));
+        (dir.clone(), files)
+      })
+      .collect();
+
+    let log_rate = config.guest_log_rate.unwrap_or(100);
+    let log_limiters = applications.iter().map(|_| RateLimiter::new(log_rate)).collect();
+
+    let instance_pool_size = config.instance_pool_size.unwrap_or(0);
+
+    let mut state = ApplicationState {
       routes: routes,
+      applications: applications,
       modules: modules,
+      kv: kv,
+      secrets: secrets,
+      error_pages: error_pages,
+      mounts: mounts,
+      static_dirs: static_dirs,
+      schedules: schedules,
+      snapshots: HashMap::new(),
+      instance_pool_size: instance_pool_size,
+      metrics: Metrics::new(),
+      log_limiters: log_limiters,
+      access_log: None,
+    };
+    state.snapshots = state.take_snapshots();
+    state
+  }
+
+  /// runs each application's start function and `init` export once, with its
+  /// host state and limits. Snapshots are kept when instances are pooled or
+  /// the module has an `init` export, otherwise new instances run their start
+  /// function themselves
+  fn take_snapshots(&self) -> HashMap<usize, Snapshot> {
+    let mut snapshots = HashMap::new();
+    for (app_index, app) in self.applications.iter().enumerate() {
+      let env = Rc::new(RefCell::new(self.host_state(app_index)));
+      let mut limits = self.limits(app_index);
+      let snapshot = Snapshot::take(&self.modules[&app.file_path], &env, &mut limits)
+        .unwrap_or_else(|e| panic!("could not initialize {}: {:?}", app.name(), e));
+      if self.instance_pool_size > 0 || snapshot.initialized {
+        snapshots.insert(app_index, snapshot);
+      }
+    }
+    snapshots
+  }
+
+  /// an instance of the application's module, using `env` for its host
+  /// functions. A start function it runs counts against `limits`
+  pub fn instance(&self, app_index: usize, env: &Rc<RefCell<State>>, limits: &mut Limits) -> Result<ModuleRef, InvocationError> {
+    let app = &self.applications[app_index];
+    let path = &app.file_path;
+    let snapshot = self.snapshots.get(&app_index);
+    if app.is_persistent() {
+      instances::acquire_persistent(app_index, path, &self.modules[path], snapshot, env, limits)
+    } else {
+      instances::acquire(path, &self.modules[path], snapshot, env, limits)
+    }
+  }
+
+  /// keeps the instance for another request, once its function returned
+  pub fn release_instance(&self, app_index: usize, env: &Rc<RefCell<State>>) {
+    let app = &self.applications[app_index];
+    if app.is_persistent() {
+      let recycling = instances::Recycling {
+        max_idle: app.persistent_instances.unwrap_or(1),
+        max_requests: app.max_requests_per_instance,
+        max_memory: app.max_instance_memory,
+      };
+      instances::release_persistent(app_index, env, &recycling);
+    } else if self.instance_pool_size > 0 {
+      instances::release(&app.file_path, env, self.instance_pool_size);
+    }
+  }
+
+  /// host state for a new instance of the application
+  pub fn host_state(&self, app_index: usize) -> State {
+    let app = &self.applications[app_index];
+    let mut env = State::new();
+    env.kv = self.kv_handle(app_index);
+    env.secrets = self.secrets_for(app_index);
+    if let Some(ref h) = app.env {
+      env.db.extend(
+        h.iter()
+          .map(|(ref k, ref v)| (k.to_string(), v.to_string())),
+      );
+    }
+    env.wasi.args = vec![app.function.clone()];
+    env.has_response = !app.scheduled;
+    env.vfs = self.mounts.get(&app_index).cloned();
+    env.metrics = Some(self.metrics.clone());
+    env.log_context = self.log_context(app_index, None);
+    env.log_limiter = Some(self.log_limiters[app_index].clone());
+    env
+  }
+
+  /// identifies the application's records in the logs
+  pub fn log_context(&self, app_index: usize, request_id: Option<u64>) -> Context {
+    let app = &self.applications[app_index];
+    Context {
+      request_id,
+      app: Some(app.name()),
+      module: Some(app.file_path.clone()),
     }
   }
 
-  pub fn route(&self, method: &str, url: &str) -> Option<(&str, &Module, &Option<HashMap<String, String>>)> {
-    if let Some((func_name, module_path, ref opt_env)) = self.routes.get(&(method.to_string(), url.to_string())) {
-      if let Some(module) = self.modules.get(module_path) {
-        return Some((func_name, module, opt_env));
+  /// limits for an invocation starting now
+  pub fn limits(&self, app_index: usize) -> Limits {
+    let app = &self.applications[app_index];
+    Limits {
+      fuel: app.fuel,
+      deadline: app.timeout_ms.map(|ms| Instant::now() + Duration::from_millis(ms)),
+    }
+  }
+
+  /// response sent when the function and its error handler failed
+  pub fn error_response(&self, app_index: usize, error: &InvocationError) -> PreparedResponse {
+    let mut response = PreparedResponse::new();
+    response.status_code = Some(error.status());
+
+    if let Some(page) = self.error_pages.get(&app_index) {
+      response.headers.push(("Content-Type".to_string(), "text/html".to_string()));
+      response.body = Some(page.clone());
+    } else {
+      let body = if self.applications[app_index].debug.unwrap_or(false) {
+        error.details()
+      } else {
+        error.message().to_string()
+      };
+      response.headers.push(("Content-Type".to_string(), "text/plain".to_string()));
+      response.body = Some(body.into_bytes());
+    }
+
+    response
+  }
+
+  /// secrets declared by the application
+  pub fn secrets_for(&self, app_index: usize) -> HashMap<String, SecretValue> {
+    let mut res = HashMap::new();
+    if let Some(ref names) = self.applications[app_index].secrets {
+      for name in names.iter() {
+        if let Some(value) = self.secrets.get(name) {
+          res.insert(name.clone(), value.clone());
+        }
       }
     }
+    res
+  }
+
+  pub fn kv_handle(&self, app_index: usize) -> Option<kv::Handle> {
+    let app = &self.applications[app_index];
+    match (self.kv.as_ref(), app.kv_namespace.as_ref()) {
+      (Some(store), Some(namespace)) => Some(kv::Handle {
+        store: store.clone(),
+        namespace: namespace.clone(),
+        max_keys: app.kv_max_keys,
+        max_bytes: app.kv_max_bytes,
+      }),
+      _ => None,
+    }
+  }
+
+  /// applications match the exact method and path. Other GET and HEAD
+  /// requests go to the static directory with the longest matching prefix
+  pub fn route<'a>(&'a self, method: &str, url: &'a str) -> Option<Route<'a>> {
+    if let Some(&index) = self.routes.get(&(method.to_string(), url.to_string())) {
+      let app = &self.applications[index];
+      if let Some(module) = self.modules.get(&app.file_path) {
+        return Some(Route::Application(index, app, module));
+      }
+    }
+
+    if method != "GET" && method != "HEAD" {
+      return None;
+    }
 
-    None
+    let path = url.split('?').next().unwrap_or("");
+    self
+      .static_dirs
+      .iter()
+      .filter_map(|&(ref dir, ref files)| {
+        let prefix = dir.url_prefix.trim_right_matches('/');
+        if path.starts_with(prefix) && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/')) {
+          Some((prefix.len(), Route::Static(dir, files, &path[prefix.len()..])))
+        } else {
+          None
+        }
+      })
+      .max_by_key(|&(len, _)| len)
+      .map(|(_, route)| route)
   }
 }
@@ -0,0 +1,216 @@
+//! cron expressions for `[[schedules]]`
+//!
+//! the five usual fields are supported (minute, hour, day of month, month,
+//! day of week), with `*`, lists, ranges and steps, and the `@hourly`,
+//! `@daily`, `@weekly`, `@monthly` and `@yearly` shortcuts. Times are in UTC
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http::civil_from_days;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+  /// one bit per allowed value
+  minutes: u64,
+  hours: u64,
+  days: u64,
+  months: u64,
+  /// 0 is Sunday
+  weekdays: u64,
+  /// when both days of month and days of week are restricted,
+  /// a day matching either of them is selected
+  any_day: bool,
+  any_weekday: bool,
+}
+
+/// how far `next_after` looks for a matching time
+const SEARCH_DAYS: u64 = 5 * 366;
+
+impl Cron {
+  pub fn parse(expression: &str) -> Result<Cron, String> {
+    let expression = match expression.trim() {
+      "@hourly" => "0 * * * *",
+      "@daily" | "@midnight" => "0 0 * * *",
+      "@weekly" => "0 0 * * 0",
+      "@monthly" => "0 0 1 * *",
+      "@yearly" | "@annually" => "0 0 1 1 *",
+      e => e,
+    };
+
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    if fields.len() != 5 {
+      return Err(format!("expected 5 fields in '{}'", expression));
+    }
+
+    let mut weekdays = parse_field(fields[4], 0, 7)?;
+    // 7 is also Sunday
+    if weekdays & (1 << 7) != 0 {
+      weekdays = (weekdays | 1) & !(1 << 7);
+    }
+
+    Ok(Cron {
+      minutes: parse_field(fields[0], 0, 59)?,
+      hours: parse_field(fields[1], 0, 23)?,
+      days: parse_field(fields[2], 1, 31)?,
+      months: parse_field(fields[3], 1, 12)?,
+      weekdays,
+      any_day: fields[2].starts_with('*'),
+      any_weekday: fields[4].starts_with('*'),
+    })
+  }
+
+  /// the first matching minute strictly after `time`
+  pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let start = secs / 60 + 1;
+    let first_day = start / 1440;
+
+    for day in first_day..first_day + SEARCH_DAYS {
+      if !self.matches_day(day) {
+        continue;
+      }
+
+      let first_minute = if day == first_day { start % 1440 } else { 0 };
+      for minute in first_minute..1440 {
+        if self.hours & (1 << (minute / 60)) != 0 && self.minutes & (1 << (minute % 60)) != 0 {
+          return Some(UNIX_EPOCH + Duration::from_secs((day * 1440 + minute) * 60));
+        }
+      }
+    }
+
+    None
+  }
+
+  fn matches_day(&self, days: u64) -> bool {
+    let (_, month, day) = civil_from_days(days);
+    // the epoch was a Thursday
+    let weekday = (days + 4) % 7;
+
+    if self.months & (1 << month) == 0 {
+      return false;
+    }
+
+    let day_matches = self.days & (1 << day) != 0;
+    let weekday_matches = self.weekdays & (1 << weekday) != 0;
+    match (self.any_day, self.any_weekday) {
+      (false, false) => day_matches || weekday_matches,
+      _ => day_matches && weekday_matches,
+    }
+  }
+}
+
+/// returns a bit mask of the values in `min..=max` selected by the field
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+  let mut mask = 0u64;
+
+  for part in field.split(',') {
+    let (range, step) = match part.find('/') {
+      Some(i) => {
+        let step: u64 = part[i + 1..].parse().map_err(|_| format!("invalid step in '{}'", part))?;
+        if step == 0 {
+          return Err(format!("invalid step in '{}'", part));
+        }
+        (&part[..i], Some(step))
+      }
+      None => (part, None),
+    };
+
+    let (first, last) = if range == "*" {
+      (min, max)
+    } else if let Some(i) = range.find('-') {
+      let first = parse_value(&range[..i], min, max)?;
+      let last = parse_value(&range[i + 1..], min, max)?;
+      if last < first {
+        return Err(format!("invalid range '{}'", range));
+      }
+      (first, last)
+    } else {
+      let first = parse_value(range, min, max)?;
+      // `5/15` means every 15 starting at 5
+      (first, if step.is_some() { max } else { first })
+    };
+
+    let mut value = first;
+    while value <= last {
+      mask |= 1 << value;
+      value += step.unwrap_or(1);
+    }
+  }
+
+  Ok(mask)
+}
+
+fn parse_value(value: &str, min: u64, max: u64) -> Result<u64, String> {
+  match value.parse() {
+    Ok(v) if v >= min && v <= max => Ok(v),
+    _ => Err(format!("'{}' is not between {} and {}", value, min, max)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn at(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+  }
+
+  fn values(mask: u64) -> Vec<u64> {
+    (0..64).filter(|value| mask & (1 << value) != 0).collect()
+  }
+
+  #[test]
+  fn fields() {
+    assert_eq!(values(parse_field("*", 1, 12).unwrap()), (1..13).collect::<Vec<u64>>());
+    assert_eq!(values(parse_field("5/15", 0, 59).unwrap()), vec![5, 20, 35, 50]);
+    assert_eq!(values(parse_field("*/20", 0, 59).unwrap()), vec![0, 20, 40]);
+    assert_eq!(values(parse_field("1-5,10", 0, 59).unwrap()), vec![1, 2, 3, 4, 5, 10]);
+    assert_eq!(values(parse_field("10-20/5", 0, 59).unwrap()), vec![10, 15, 20]);
+  }
+
+  #[test]
+  fn invalid_fields() {
+    assert!(parse_field("*/0", 0, 59).is_err());
+    assert!(parse_field("5/0", 0, 59).is_err());
+    assert!(parse_field("5/x", 0, 59).is_err());
+    assert!(parse_field("5-1", 0, 59).is_err());
+    assert!(parse_field("60", 0, 59).is_err());
+    assert!(parse_field("0", 1, 31).is_err());
+    assert!(parse_field("1,,2", 0, 59).is_err());
+    assert!(Cron::parse("* * * *").is_err());
+  }
+
+  #[test]
+  fn sunday_is_0_or_7() {
+    assert_eq!(Cron::parse("0 0 * * 7").unwrap(), Cron::parse("0 0 * * 0").unwrap());
+    assert_eq!(Cron::parse("@weekly").unwrap(), Cron::parse("0 0 * * 7").unwrap());
+  }
+
+  #[test]
+  fn next_minute() {
+    let cron = Cron::parse("5/15 * * * *").unwrap();
+    // 2026-10-18 10:00 -> 10:05
+    assert_eq!(cron.next_after(at(1792317600)), Some(at(1792317900)));
+    // strictly after: 10:05 -> 10:20
+    assert_eq!(cron.next_after(at(1792317900)), Some(at(1792318800)));
+  }
+
+  #[test]
+  fn next_month_and_year() {
+    // 2026-01-31 23:59:30 -> 2026-02-01 00:00
+    assert_eq!(Cron::parse("@monthly").unwrap().next_after(at(1769903970)), Some(at(1769904000)));
+    // 2026-12-31 12:00 -> 2027-01-01 00:00
+    assert_eq!(Cron::parse("@yearly").unwrap().next_after(at(1798718400)), Some(at(1798761600)));
+    // 2027-03-01 -> 2028-02-29 12:30, the next leap day
+    assert_eq!(Cron::parse("30 12 29 2 *").unwrap().next_after(at(1803859200)), Some(at(1835440200)));
+    assert_eq!(Cron::parse("0 0 31 2 *").unwrap().next_after(at(1803859200)), None);
+  }
+
+  #[test]
+  fn day_of_month_or_week() {
+    // from Sunday 2026-10-18: the next Friday comes before the 13th
+    assert_eq!(Cron::parse("0 0 13 * 5").unwrap().next_after(at(1792317600)), Some(at(1792713600)));
+    assert_eq!(Cron::parse("0 0 13 * *").unwrap().next_after(at(1792317600)), Some(at(1794528000)));
+    assert_eq!(Cron::parse("0 0 * * 5").unwrap().next_after(at(1792317600)), Some(at(1792713600)));
+  }
+}
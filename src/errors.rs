@@ -0,0 +1,103 @@
+//! failures while handling a request, and the responses they map to
+
+use wasmi::{RuntimeValue, Trap, TrapKind};
+
+use interpreter::Interrupted;
+use registry::MemoryAccessError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvocationError {
+  /// the module could not be instantiated
+  Instantiation(String),
+  /// the module does not export the function
+  MissingExport(String),
+  /// (trap description, wasm stack trace)
+  Trap(String, Vec<String>),
+  /// the guest used all of its `fuel`
+  FuelExhausted,
+  /// the guest ran for longer than `timeout_ms`
+  Timeout,
+  /// the guest returned without setting a status
+  NoResponse,
+  /// (bytes sent, Content-Length) the guest streamed a shorter body than it declared
+  IncompleteBody(u64, u64),
+}
+
+impl InvocationError {
+  pub fn from_trap(trap: &Trap, stack_trace: Vec<String>) -> InvocationError {
+    match trap.kind() {
+      &TrapKind::Host(ref err) => {
+        if let Some(interrupted) = err.downcast_ref::<Interrupted>() {
+          return match *interrupted {
+            Interrupted::FuelExhausted => InvocationError::FuelExhausted,
+            Interrupted::Timeout => InvocationError::Timeout,
+          };
+        }
+        if let Some(err) = err.downcast_ref::<MemoryAccessError>() {
+          return InvocationError::Trap(err.0.clone(), stack_trace);
+        }
+        InvocationError::Trap(format!("host error: {}", err), stack_trace)
+      }
+      kind => InvocationError::Trap(format!("{:?}", kind), stack_trace),
+    }
+  }
+
+  pub fn status(&self) -> u16 {
+    match *self {
+      InvocationError::MissingExport(_) => 404,
+      InvocationError::Timeout => 504,
+      _ => 500,
+    }
+  }
+
+  /// passed to the error handler, with the status
+  pub fn code(&self) -> i32 {
+    match *self {
+      InvocationError::Instantiation(_) => 1,
+      InvocationError::MissingExport(_) => 2,
+      InvocationError::Trap(_, _) => 3,
+      InvocationError::FuelExhausted => 4,
+      InvocationError::Timeout => 5,
+      InvocationError::NoResponse => 6,
+      InvocationError::IncompleteBody(_, _) => 7,
+    }
+  }
+
+  /// arguments of the application's `error_handler` export
+  pub fn handler_args(&self) -> [RuntimeValue; 2] {
+    [RuntimeValue::I32(self.status() as i32), RuntimeValue::I32(self.code())]
+  }
+
+  /// response body shown to clients
+  pub fn message(&self) -> &'static str {
+    match *self {
+      InvocationError::Instantiation(_) => "the function could not be instantiated\n",
+      InvocationError::MissingExport(_) => "Function not found\n",
+      InvocationError::Trap(_, _) => "the function failed\n",
+      InvocationError::FuelExhausted => "the function ran out of fuel\n",
+      InvocationError::Timeout => "the function timed out\n",
+      InvocationError::NoResponse => "the function did not set a response\n",
+      InvocationError::IncompleteBody(_, _) => "the function did not send the whole response\n",
+    }
+  }
+
+  /// response body in debug mode, with the cause and the stack trace
+  pub fn details(&self) -> String {
+    let mut res = self.message().to_string();
+    match *self {
+      InvocationError::Instantiation(ref e) => res.push_str(&format!("\n{}\n", e)),
+      InvocationError::MissingExport(ref name) => res.push_str(&format!("\nno export named '{}'\n", name)),
+      InvocationError::IncompleteBody(sent, length) => {
+        res.push_str(&format!("\nsent {} bytes of the {} declared in Content-Length\n", sent, length))
+      }
+      InvocationError::Trap(ref e, ref stack_trace) => {
+        res.push_str(&format!("\n{}\n\nwasm stack trace:\n", e));
+        for frame in stack_trace.iter() {
+          res.push_str(&format!("  {}\n", frame));
+        }
+      }
+      _ => {}
+    }
+    res
+  }
+}
@@ -0,0 +1,168 @@
+//! HTTP helpers shared by the servers
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// largest request body accepted by both servers, answered with a 413 beyond it
+pub const MAX_REQUEST_BODY: usize = 1024 * 1024;
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// formats a time as an IMF-fixdate, like `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(time: SystemTime) -> String {
+  let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+  let days = secs / 86400;
+  let rem = secs % 86400;
+  let (year, month, day) = civil_from_days(days);
+
+  format!(
+    "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+    DAYS[(days % 7) as usize],
+    day,
+    MONTHS[(month - 1) as usize],
+    year,
+    rem / 3600,
+    (rem % 3600) / 60,
+    rem % 60
+  )
+}
+
+/// formats a time like the Common Log Format, `10/Oct/2000:13:55:36 +0000`
+pub fn common_log_date(time: SystemTime) -> String {
+  let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+  let rem = secs % 86400;
+  let (year, month, day) = civil_from_days(secs / 86400);
+
+  format!(
+    "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+    day,
+    MONTHS[(month - 1) as usize],
+    year,
+    rem / 3600,
+    (rem % 3600) / 60,
+    rem % 60
+  )
+}
+
+/// converts days since the epoch to a (year, month, day) civil date, see
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+  let z = days + 719468;
+  let era = z / 146097;
+  let doe = z - era * 146097;
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+  (year, month, day)
+}
+
+/// parses an IMF-fixdate. The obsolete formats are not supported
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+  let parts: Vec<&str> = date.trim().split(' ').collect();
+  if parts.len() != 6 || parts[5] != "GMT" {
+    return None;
+  }
+
+  let day: u64 = parts[1].parse().ok()?;
+  let month = MONTHS.iter().position(|m| *m == parts[2])? as u64 + 1;
+  let year: u64 = parts[3].parse().ok()?;
+  let time: Vec<u64> = parts[4].split(':').map(|t| t.parse().ok()).collect::<Option<_>>()?;
+  if time.len() != 3 || year < 1970 || day < 1 || day > 31 || time[0] > 23 || time[1] > 59 || time[2] > 60 {
+    return None;
+  }
+
+  // inverse of `civil_from_days`
+  let y = if month <= 2 { year - 1 } else { year };
+  let era = y / 400;
+  let yoe = y - era * 400;
+  let mp = if month > 2 { month - 3 } else { month + 9 };
+  let doy = (153 * mp + 2) / 5 + day - 1;
+  let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+  let days = (era * 146097 + doe).checked_sub(719468)?;
+
+  Some(UNIX_EPOCH + Duration::from_secs(days * 86400 + time[0] * 3600 + time[1] * 60 + time[2]))
+}
+
+/// decodes `%XX` escapes in a URL path. Invalid escapes are kept as is
+pub fn percent_decode(path: &str) -> Option<String> {
+  let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+
+  let bytes = path.as_bytes();
+  let mut res = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    if bytes[i] == b'%' && i + 2 < bytes.len() {
+      if let (Some(high), Some(low)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+        res.push(high << 4 | low);
+        i += 3;
+        continue;
+      }
+    }
+    res.push(bytes[i]);
+    i += 1;
+  }
+  String::from_utf8(res).ok()
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+  match status {
+    100 => "Continue",
+    101 => "Switching Protocols",
+    200 => "OK",
+    201 => "Created",
+    202 => "Accepted",
+    204 => "No Content",
+    206 => "Partial Content",
+    301 => "Moved Permanently",
+    302 => "Found",
+    303 => "See Other",
+    304 => "Not Modified",
+    307 => "Temporary Redirect",
+    308 => "Permanent Redirect",
+    400 => "Bad Request",
+    401 => "Unauthorized",
+    403 => "Forbidden",
+    404 => "Not Found",
+    405 => "Method Not Allowed",
+    408 => "Request Timeout",
+    413 => "Payload Too Large",
+    416 => "Range Not Satisfiable",
+    429 => "Too Many Requests",
+    500 => "Internal Server Error",
+    501 => "Not Implemented",
+    502 => "Bad Gateway",
+    503 => "Service Unavailable",
+    504 => "Gateway Timeout",
+    _ => "",
+  }
+}
+
+/// 1xx, 204 and 304 responses never have a body
+pub fn status_has_body(status: u16) -> bool {
+  !(status < 200 || status == 204 || status == 304)
+}
+
+pub fn has_header(headers: &[(String, String)], name: &str) -> bool {
+  headers.iter().any(|&(ref n, _)| n.eq_ignore_ascii_case(name))
+}
+
+/// the Content-Length of a request, 0 without the header
+pub fn content_length(headers: &[(String, String)]) -> Result<usize, String> {
+  match get_header(headers, "Content-Length") {
+    Some(length) => length
+      .trim()
+      .parse::<usize>()
+      .map_err(|_| format!("invalid Content-Length '{}'", length)),
+    None => Ok(0),
+  }
+}
+
+pub fn get_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+  headers
+    .iter()
+    .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+    .map(|&(_, ref v)| v.as_str())
+}
@@ -0,0 +1,394 @@
+//! describes a module's imports and exports, and checks its imports
+//! against the host functions. The report also shows what instantiating
+//! the module costs, as text or JSON
+
+use parity_wasm;
+use parity_wasm::elements::{self, External, Internal, Section, Type};
+use wasmi::ValueType;
+
+use json::json_string;
+use registry;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportStatus {
+  Supported,
+  Unsupported,
+  /// the host provides it with the signature given here
+  WrongSignature(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Import {
+  pub module: String,
+  pub name: String,
+  /// function, table, memory or global
+  pub kind: &'static str,
+  pub signature: Option<String>,
+  pub status: ImportStatus,
+}
+
+#[derive(Debug, Clone)]
+pub struct Export {
+  pub name: String,
+  pub kind: &'static str,
+  pub signature: Option<String>,
+  /// number of parameters, for functions
+  pub params: usize,
+}
+
+/// in pages for memories, in elements for tables
+#[derive(Debug, Clone)]
+pub struct Limits {
+  pub initial: u32,
+  pub maximum: Option<u32>,
+  pub imported: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct CustomSection {
+  pub name: String,
+  pub size: usize,
+}
+
+/// work done for every new instance
+#[derive(Debug, Clone, Default)]
+pub struct InstantiationCost {
+  /// linear memory allocated and zeroed
+  pub memory_bytes: u64,
+  /// copied from the data segments
+  pub data_bytes: u64,
+  pub globals: usize,
+  /// table slots filled from the element segments
+  pub table_elements: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+  pub imports: Vec<Import>,
+  pub exports: Vec<Export>,
+  /// number of functions defined by the module
+  pub functions: usize,
+  pub memories: Vec<Limits>,
+  pub tables: Vec<Limits>,
+  /// size of each data segment
+  pub data_segments: Vec<usize>,
+  pub custom_sections: Vec<CustomSection>,
+  pub cost: InstantiationCost,
+}
+
+impl Report {
+  pub fn function(&self, name: &str) -> Option<&Export> {
+    self.exports.iter().find(|e| e.kind == "function" && e.name == name)
+  }
+
+  pub fn unsupported_imports(&self) -> Vec<&Import> {
+    self.imports.iter().filter(|i| i.status != ImportStatus::Supported).collect()
+  }
+
+  pub fn print(&self) {
+    println!("functions: {}", self.functions);
+    for memory in self.memories.iter() {
+      println!("memory: {}", format_limits(memory, "pages"));
+    }
+    for table in self.tables.iter() {
+      println!("table: {}", format_limits(table, "elements"));
+    }
+    println!(
+      "data segments: {} ({} bytes)",
+      self.data_segments.len(),
+      self.data_segments.iter().sum::<usize>()
+    );
+    for section in self.custom_sections.iter() {
+      println!("custom section: {} ({} bytes)", section.name, section.size);
+    }
+    println!(
+      "instantiation: {} bytes of memory, {} bytes of data, {} globals, {} table elements",
+      self.cost.memory_bytes, self.cost.data_bytes, self.cost.globals, self.cost.table_elements
+    );
+
+    println!("imports:");
+    for import in self.imports.iter() {
+      let status = match import.status {
+        ImportStatus::Supported => "supported".to_string(),
+        ImportStatus::Unsupported => "unsupported".to_string(),
+        ImportStatus::WrongSignature(ref expected) => format!("wrong signature, expected {}", expected),
+      };
+      println!(
+        "  {}.{} ({}{}): {}",
+        import.module,
+        import.name,
+        import.kind,
+        import.signature.as_ref().map(|s| format!(" {}", s)).unwrap_or(String::new()),
+        status
+      );
+    }
+
+    println!("exports:");
+    for export in self.exports.iter() {
+      println!(
+        "  {} ({}{})",
+        export.name,
+        export.kind,
+        export.signature.as_ref().map(|s| format!(" {}", s)).unwrap_or(String::new())
+      );
+    }
+  }
+
+  pub fn to_json(&self) -> String {
+    let imports: Vec<String> = self
+      .imports
+      .iter()
+      .map(|import| {
+        let (status, expected) = match import.status {
+          ImportStatus::Supported => ("supported", None),
+          ImportStatus::Unsupported => ("unsupported", None),
+          ImportStatus::WrongSignature(ref expected) => ("wrong_signature", Some(expected)),
+        };
+        format!(
+          "{{\"module\":{},\"name\":{},\"kind\":{},\"signature\":{},\"status\":{},\"expected\":{}}}",
+          json_string(&import.module),
+          json_string(&import.name),
+          json_string(import.kind),
+          json_option(import.signature.as_ref()),
+          json_string(status),
+          json_option(expected)
+        )
+      })
+      .collect();
+
+    let exports: Vec<String> = self
+      .exports
+      .iter()
+      .map(|export| {
+        format!(
+          "{{\"name\":{},\"kind\":{},\"signature\":{}}}",
+          json_string(&export.name),
+          json_string(export.kind),
+          json_option(export.signature.as_ref())
+        )
+      })
+      .collect();
+
+    let memories: Vec<String> = self.memories.iter().map(json_limits).collect();
+    let tables: Vec<String> = self.tables.iter().map(json_limits).collect();
+    let data_segments: Vec<String> = self.data_segments.iter().map(|size| size.to_string()).collect();
+    let custom_sections: Vec<String> = self
+      .custom_sections
+      .iter()
+      .map(|section| format!("{{\"name\":{},\"size\":{}}}", json_string(&section.name), section.size))
+      .collect();
+
+    format!(
+      "{{\"imports\":[{}],\"exports\":[{}],\"functions\":{},\"memories\":[{}],\"tables\":[{}],\
+       \"data_segments\":[{}],\"custom_sections\":[{}],\"instantiation\":{{\"memory_bytes\":{},\
+       \"data_bytes\":{},\"globals\":{},\"table_elements\":{}}}}}",
+      imports.join(","),
+      exports.join(","),
+      self.functions,
+      memories.join(","),
+      tables.join(","),
+      data_segments.join(","),
+      custom_sections.join(","),
+      self.cost.memory_bytes,
+      self.cost.data_bytes,
+      self.cost.globals,
+      self.cost.table_elements
+    )
+  }
+}
+
+pub fn inspect(path: &str) -> Result<Report, String> {
+  let module = parity_wasm::deserialize_file(path).map_err(|e| format!("could not load {}: {:?}", path, e))?;
+  Ok(inspect_module(&module))
+}
+
+pub fn inspect_module(module: &elements::Module) -> Report {
+  let types: Vec<&elements::FunctionType> = module
+    .type_section()
+    .map(|section| {
+      section
+        .types()
+        .iter()
+        .map(|t| match *t {
+          Type::Function(ref f) => f,
+        })
+        .collect()
+    })
+    .unwrap_or(Vec::new());
+
+  // the function index space starts with the imported functions
+  let mut function_types: Vec<Option<&elements::FunctionType>> = Vec::new();
+  let mut imports = Vec::new();
+
+  for entry in module.import_section().map(|s| s.entries()).unwrap_or(&[]) {
+    let (kind, signature, status) = match *entry.external() {
+      External::Function(type_index) => {
+        let function_type = types.get(type_index as usize).cloned();
+        function_types.push(function_type);
+        let status = match (registry::find(entry.module(), entry.field()), function_type) {
+          (None, _) | (_, None) => ImportStatus::Unsupported,
+          (Some((_, host)), Some(function_type)) => {
+            let params: Vec<ValueType> = function_type.params().iter().map(value_type).collect();
+            let ret = function_type.return_type().as_ref().map(value_type);
+            if &params[..] == host.params && ret == host.ret {
+              ImportStatus::Supported
+            } else {
+              ImportStatus::WrongSignature(signature(host.params, host.ret))
+            }
+          }
+        };
+        ("function", function_type.map(format_type), status)
+      }
+      // the `env` resolver allocates the memory
+      External::Memory(_) if entry.module() == "env" => ("memory", None, ImportStatus::Supported),
+      External::Memory(_) => ("memory", None, ImportStatus::Unsupported),
+      External::Table(_) => ("table", None, ImportStatus::Unsupported),
+      External::Global(_) => ("global", None, ImportStatus::Unsupported),
+    };
+
+    imports.push(Import {
+      module: entry.module().to_string(),
+      name: entry.field().to_string(),
+      kind,
+      signature,
+      status,
+    });
+  }
+
+  for entry in module.function_section().map(|s| s.entries()).unwrap_or(&[]) {
+    function_types.push(types.get(entry.type_ref() as usize).cloned());
+  }
+
+  let exports = module
+    .export_section()
+    .map(|s| s.entries())
+    .unwrap_or(&[])
+    .iter()
+    .map(|entry| {
+      let (kind, function_type) = match *entry.internal() {
+        Internal::Function(index) => ("function", function_types.get(index as usize).cloned().and_then(|t| t)),
+        Internal::Table(_) => ("table", None),
+        Internal::Memory(_) => ("memory", None),
+        Internal::Global(_) => ("global", None),
+      };
+
+      Export {
+        name: entry.field().to_string(),
+        kind,
+        signature: function_type.map(format_type),
+        params: function_type.map(|t| t.params().len()).unwrap_or(0),
+      }
+    })
+    .collect();
+
+  let mut memories: Vec<Limits> = Vec::new();
+  let mut tables: Vec<Limits> = Vec::new();
+  for entry in module.import_section().map(|s| s.entries()).unwrap_or(&[]) {
+    match *entry.external() {
+      External::Memory(ref memory) => memories.push(limits(memory.limits(), true)),
+      External::Table(ref table) => tables.push(limits(table.limits(), true)),
+      _ => {}
+    }
+  }
+  for memory in module.memory_section().map(|s| s.entries()).unwrap_or(&[]) {
+    memories.push(limits(memory.limits(), false));
+  }
+  for table in module.table_section().map(|s| s.entries()).unwrap_or(&[]) {
+    tables.push(limits(table.limits(), false));
+  }
+
+  let data_segments: Vec<usize> = module
+    .data_section()
+    .map(|s| s.entries())
+    .unwrap_or(&[])
+    .iter()
+    .map(|segment| segment.value().len())
+    .collect();
+
+  let custom_sections = module
+    .sections()
+    .iter()
+    .filter_map(|section| match *section {
+      Section::Custom(ref custom) => Some(CustomSection {
+        name: custom.name().to_string(),
+        size: custom.payload().len(),
+      }),
+      _ => None,
+    })
+    .collect();
+
+  let cost = InstantiationCost {
+    memory_bytes: memories.iter().map(|m| m.initial as u64 * 65536).sum(),
+    data_bytes: data_segments.iter().map(|size| *size as u64).sum(),
+    globals: module.global_section().map(|s| s.entries().len()).unwrap_or(0),
+    table_elements: module
+      .elements_section()
+      .map(|s| s.entries().iter().map(|segment| segment.members().len()).sum())
+      .unwrap_or(0),
+  };
+
+  Report {
+    imports,
+    exports,
+    functions: module.function_section().map(|s| s.entries().len()).unwrap_or(0),
+    memories,
+    tables,
+    data_segments,
+    custom_sections,
+    cost,
+  }
+}
+
+fn limits(limits: &elements::ResizableLimits, imported: bool) -> Limits {
+  Limits {
+    initial: limits.initial(),
+    maximum: limits.maximum(),
+    imported,
+  }
+}
+
+fn format_limits(limits: &Limits, unit: &str) -> String {
+  format!(
+    "{} {}{}{}",
+    limits.initial,
+    unit,
+    limits.maximum.map(|max| format!(", up to {}", max)).unwrap_or(String::new()),
+    if limits.imported { ", imported" } else { "" }
+  )
+}
+
+fn json_limits(limits: &Limits) -> String {
+  format!(
+    "{{\"initial\":{},\"maximum\":{},\"imported\":{}}}",
+    limits.initial,
+    limits.maximum.map(|max| max.to_string()).unwrap_or("null".to_string()),
+    limits.imported
+  )
+}
+
+fn json_option(value: Option<&String>) -> String {
+  value.map(|v| json_string(v)).unwrap_or("null".to_string())
+}
+
+fn value_type(t: &elements::ValueType) -> ValueType {
+  match *t {
+    elements::ValueType::I32 => ValueType::I32,
+    elements::ValueType::I64 => ValueType::I64,
+    elements::ValueType::F32 => ValueType::F32,
+    elements::ValueType::F64 => ValueType::F64,
+  }
+}
+
+fn format_type(function_type: &elements::FunctionType) -> String {
+  let params: Vec<ValueType> = function_type.params().iter().map(value_type).collect();
+  signature(&params, function_type.return_type().as_ref().map(value_type))
+}
+
+/// like `(i32, i64) -> i64`
+fn signature(params: &[ValueType], ret: Option<ValueType>) -> String {
+  let params: Vec<String> = params.iter().map(|p| format!("{:?}", p).to_lowercase()).collect();
+  match ret {
+    Some(ret) => format!("({}) -> {}", params.join(", "), format!("{:?}", ret).to_lowercase()),
+    None => format!("({})", params.join(", ")),
+  }
+}
@@ -0,0 +1,288 @@
+//! instances kept ready for the next request
+//!
+//! each application's linear memory and mutable globals are copied once
+//! instantiated, after its start function and its `init` export ran with the
+//! application's host state and limits, in a snapshot. New instances start
+//! from them instead of running those functions again. Instances whose
+//! function returned go back to a pool, one per module and thread, and are
+//! restored from the application's snapshot before they are reused. Globals
+//! are read and written through the accessors added by `export_globals`.
+//!
+//! Persistent applications keep their own instances, whose memory is not
+//! restored: guests can cache data from one request to the next
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use wasmi::memory_units::Pages;
+use wasmi::{ExternVal, FuncRef, ImportsBuilder, MemoryRef, Module, ModuleInstance, ModuleRef, NopExternals, RuntimeValue};
+
+use errors::InvocationError;
+use interpreter::{global_getter, global_setter, Limits, WasmInstance, START};
+use registry::{self, State, StateResolver};
+use sync::SyncHost;
+use wasi::{self, WasiResolver};
+
+const PAGE_SIZE: usize = 65536;
+
+/// export run once before the snapshot, for expensive setup
+pub const INIT: &str = "init";
+
+/// linear memory and mutable globals of a new instance
+pub struct Snapshot {
+  /// None if the module has no memory
+  memory: Option<Vec<u8>>,
+  /// in the order of `global_getter`
+  globals: Vec<RuntimeValue>,
+  /// the module has an `init` export, instances must start from the snapshot
+  pub initialized: bool,
+}
+
+impl Snapshot {
+  /// instantiates the module and runs its `init` export, with the blocking
+  /// host and the application's state. The start function and `init` share
+  /// the limits
+  pub fn take(module: &Module, env: &Rc<RefCell<State>>, limits: &mut Limits) -> Result<Snapshot, InvocationError> {
+    let instance = instantiate(module, env, Some(&mut *limits))?;
+
+    let initialized = match instance.export_by_name(INIT) {
+      Some(ExternVal::Func(init)) => {
+        run_blocking(env, &init, limits)?;
+        true
+      }
+      _ => false,
+    };
+
+    let memory = match env.borrow().memory {
+      Some(ref memory) => {
+        let Pages(pages) = memory.current_size();
+        Some(memory.get(0, pages * PAGE_SIZE).map_err(|e| {
+          InvocationError::Instantiation(format!("could not copy the memory: {:?}", e))
+        })?)
+      }
+      None => None,
+    };
+
+    let mut globals = Vec::new();
+    while instance.export_by_name(&global_getter(globals.len())).is_some() {
+      match instance.invoke_export(&global_getter(globals.len()), &[], &mut NopExternals) {
+        Ok(Some(value)) => globals.push(value),
+        _ => return Err(InvocationError::Instantiation("could not copy the globals".to_string())),
+      }
+    }
+    Ok(Snapshot { memory, globals, initialized })
+  }
+
+  /// false if the memory grew larger than the snapshot, it cannot be shrunk back
+  fn restore(&self, instance: &ModuleRef, memory: Option<&MemoryRef>) -> bool {
+    let restored = match (self.memory.as_ref(), memory) {
+      (None, None) => true,
+      (Some(data), Some(memory)) => {
+        let Pages(pages) = memory.current_size();
+        let wanted = data.len() / PAGE_SIZE;
+        if pages > wanted || (pages < wanted && memory.grow(Pages(wanted - pages)).is_err()) {
+          return false;
+        }
+        memory.set(0, data).is_ok()
+      }
+      _ => false,
+    };
+
+    restored
+      && self
+        .globals
+        .iter()
+        .enumerate()
+        .all(|(n, value)| instance.invoke_export(&global_setter(n), &[*value], &mut NopExternals).is_ok())
+  }
+}
+
+struct Idle {
+  instance: ModuleRef,
+  memory: Option<MemoryRef>,
+}
+
+thread_local! {
+  /// module path -> idle instances
+  static POOL: RefCell<HashMap<String, Vec<Idle>>> = RefCell::new(HashMap::new());
+  /// application index -> idle persistent instances
+  static PERSISTENT: RefCell<HashMap<usize, Vec<Persistent>>> = RefCell::new(HashMap::new());
+}
+
+struct Persistent {
+  instance: ModuleRef,
+  memory: Option<MemoryRef>,
+  requests: u64,
+}
+
+/// when persistent instances are kept or replaced
+pub struct Recycling {
+  pub max_idle: usize,
+  pub max_requests: Option<u64>,
+  /// in bytes
+  pub max_memory: Option<usize>,
+}
+
+/// creates an instance whose host functions use `env`. With `start`, the
+/// start function runs within those limits with the blocking host, it should
+/// not wait on the network
+pub fn instantiate(module: &Module, env: &Rc<RefCell<State>>, start: Option<&mut Limits>) -> Result<ModuleRef, InvocationError> {
+  let resolver = StateResolver { inner: env.clone() };
+  let imports = ImportsBuilder::new()
+    .with_resolver("env", &resolver)
+    .with_resolver(wasi::MODULE, &WasiResolver);
+
+  // load_module moved the start function to the `START` export
+  let instance = ModuleInstance::new(module, &imports)
+    .map_err(|e| InvocationError::Instantiation(format!("{:?}", e)))?
+    .assert_no_start();
+  // the start function can already use the host functions
+  registry::attach_exported_memory(env, &instance);
+  env.borrow_mut().instance = Some(instance.clone());
+
+  if let Some(limits) = start {
+    if let Some(ExternVal::Func(start)) = instance.export_by_name(START) {
+      run_blocking(env, &start, limits)?;
+    }
+  }
+  Ok(instance)
+}
+
+/// runs a function without arguments with the blocking host. `limits` holds
+/// what was left of them once it returns
+fn run_blocking(env: &Rc<RefCell<State>>, function: &FuncRef, limits: &mut Limits) -> Result<(), InvocationError> {
+  env.borrow_mut().deadline = limits.deadline;
+  let mut instance: WasmInstance<State, SyncHost> = WasmInstance::new(env.clone(), function, &[]);
+  instance.limits = limits.clone();
+  let res = instance.resume();
+  *limits = instance.limits.clone();
+  match res {
+    Err(ref t) if !wasi::is_clean_exit(t) => Err(InvocationError::from_trap(t, instance.stack_trace())),
+    _ => Ok(()),
+  }
+}
+
+/// an idle instance of the module restored from its snapshot, or a new
+/// one. Without a snapshot, the new instance runs its start function within
+/// the invocation's limits
+pub fn acquire(
+  file_path: &str,
+  module: &Module,
+  snapshot: Option<&Snapshot>,
+  env: &Rc<RefCell<State>>,
+  limits: &mut Limits,
+) -> Result<ModuleRef, InvocationError> {
+  let snapshot = match snapshot {
+    Some(snapshot) => snapshot,
+    None => return instantiate(module, env, Some(limits)),
+  };
+
+  while let Some(idle) = POOL.with(|pool| pool.borrow_mut().get_mut(file_path).and_then(|idle| idle.pop())) {
+    if snapshot.restore(&idle.instance, idle.memory.as_ref()) {
+      let mut state = env.borrow_mut();
+      state.memory = idle.memory;
+      state.instance = Some(idle.instance.clone());
+      return Ok(idle.instance);
+    }
+  }
+
+  let instance = instantiate(module, env, None)?;
+  let restored = snapshot.restore(&instance, env.borrow().memory.as_ref());
+  if !restored {
+    return Err(InvocationError::Instantiation("could not restore the snapshot".to_string()));
+  }
+  Ok(instance)
+}
+
+/// keeps the instance used by `env` for later requests. Only call it
+/// once its function returned
+pub fn release(file_path: &str, env: &Rc<RefCell<State>>, max_idle: usize) {
+  let (instance, memory) = {
+    let mut state = env.borrow_mut();
+    match state.instance.take() {
+      Some(instance) => (instance, state.memory.clone()),
+      None => return,
+    }
+  };
+
+  POOL.with(|pool| {
+    let mut pool = pool.borrow_mut();
+    let idle = pool.entry(file_path.to_string()).or_insert_with(Vec::new);
+    if idle.len() < max_idle {
+      idle.push(Idle { instance, memory });
+    }
+  });
+}
+
+/// instantiates the modules ahead of the first requests
+pub fn prefill(modules: &HashMap<String, Module>, count: usize) {
+  for (file_path, module) in modules.iter() {
+    for _ in 0..count {
+      let env = Rc::new(RefCell::new(State::new()));
+      // restored from the snapshot when acquired
+      match instantiate(module, &env, None) {
+        Ok(_) => release(file_path, &env, count),
+        Err(e) => {
+          error!("could not instantiate {}: {:?}", file_path, e);
+          break;
+        }
+      }
+    }
+  }
+}
+
+/// an idle instance of a persistent application, with the memory left by its
+/// previous requests, or a new one
+pub fn acquire_persistent(
+  app_index: usize,
+  file_path: &str,
+  module: &Module,
+  snapshot: Option<&Snapshot>,
+  env: &Rc<RefCell<State>>,
+  limits: &mut Limits,
+) -> Result<ModuleRef, InvocationError> {
+  if let Some(idle) = PERSISTENT.with(|pool| pool.borrow_mut().get_mut(&app_index).and_then(|idle| idle.pop())) {
+    let mut state = env.borrow_mut();
+    state.memory = idle.memory;
+    state.instance = Some(idle.instance.clone());
+    state.instance_requests = idle.requests + 1;
+    return Ok(idle.instance);
+  }
+
+  let instance = acquire(file_path, module, snapshot, env, limits)?;
+  env.borrow_mut().instance_requests = 1;
+  Ok(instance)
+}
+
+/// keeps the persistent instance used by `env`, unless it served enough
+/// requests or its memory grew too large. Only call it once its function
+/// returned, a trap can leave the memory inconsistent
+pub fn release_persistent(app_index: usize, env: &Rc<RefCell<State>>, recycling: &Recycling) {
+  let (instance, memory, requests) = {
+    let mut state = env.borrow_mut();
+    match state.instance.take() {
+      Some(instance) => (instance, state.memory.clone(), state.instance_requests),
+      None => return,
+    }
+  };
+
+  if recycling.max_requests.map(|max| requests >= max).unwrap_or(false) {
+    info!(env.borrow().log_context; "recycling instance after {} requests", requests);
+    return;
+  }
+  if let (Some(max), Some(memory)) = (recycling.max_memory, memory.as_ref()) {
+    let Pages(pages) = memory.current_size();
+    if pages * PAGE_SIZE > max {
+      info!(env.borrow().log_context; "recycling instance, memory grew to {} bytes", pages * PAGE_SIZE);
+      return;
+    }
+  }
+
+  PERSISTENT.with(|pool| {
+    let mut pool = pool.borrow_mut();
+    let idle = pool.entry(app_index).or_insert_with(Vec::new);
+    if idle.len() < recycling.max_idle {
+      idle.push(Persistent { instance, memory, requests });
+    }
+  });
+}
@@ -1,11 +1,17 @@
 use parity_wasm;
-use parity_wasm::elements::{External, FunctionType, Internal, Type, ValueType};
+use parity_wasm::elements::{
+  self, ExportEntry, External, Func, FuncBody, FunctionType, ImportEntry, ImportSection, Internal, Opcode, Opcodes, Section, Type, ValueType,
+};
 use std::collections::VecDeque;
 use wasmi::{self, Module};
 use wasmi::{BlockFrameType, Externals, FuncInstance, FuncRef, FunctionContext, Interpreter, RunResult, RuntimeValue, Trap, TrapKind};
+use std::fmt;
 use std::marker;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::cmp;
+use std::time::{Duration, Instant};
+use wasmi::HostError;
 
 pub const DEFAULT_VALUE_STACK_LIMIT: usize = 16384;
 pub const DEFAULT_FRAME_STACK_LIMIT: usize = 16384;
@@ -20,9 +26,51 @@ pub trait Host {
   fn build(s: Rc<RefCell<Self::State>>) -> Self;
 }
 
+/// stops guests that run for too long. Both limits are checked
+/// between function calls and on every loop iteration, see `meter_loops`
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+  /// number of function calls and loop iterations left, host functions included
+  pub fuel: Option<u64>,
+  pub deadline: Option<Instant>,
+}
+
+/// longest wait for `sleep_ms` or a `poll` timeout, when the invocation has
+/// no deadline
+pub const MAX_WAIT_MS: u64 = 60 * 60 * 1000;
+
+/// when a guest wait of `ms` ends: after `MAX_WAIT_MS` at most, and not
+/// after `deadline`, the end of the invocation
+pub fn wait_deadline(ms: u64, deadline: Option<Instant>) -> Instant {
+  let now = Instant::now();
+  let end = now
+    .checked_add(Duration::from_millis(cmp::min(ms, MAX_WAIT_MS)))
+    .unwrap_or(now);
+  match deadline {
+    Some(deadline) if deadline < end => deadline,
+    _ => end,
+  }
+}
+
+/// returned as a host trap when a limit is reached
+#[derive(Debug, Clone, PartialEq)]
+pub enum Interrupted {
+  FuelExhausted,
+  Timeout,
+}
+
+impl fmt::Display for Interrupted {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    write!(f, "{:?}", self)
+  }
+}
+
+impl HostError for Interrupted {}
+
 pub struct WasmInstance<S, E: Externals + Host<State = S>> {
   pub state: Rc<RefCell<S>>,
   pub stack: VecDeque<FunctionContext>,
+  pub limits: Limits,
   _marker: marker::PhantomData<E>,
 }
 
@@ -33,6 +81,7 @@ impl<S, E: Externals + Host<State = S>> WasmInstance<S, E> {
     WasmInstance {
       state: state,
       stack,
+      limits: Limits::default(),
       _marker: marker::PhantomData,
     }
   }
@@ -41,17 +90,28 @@ impl<S, E: Externals + Host<State = S>> WasmInstance<S, E> {
     let mut host = E::build(self.state.clone());
     let mut interpreter = Interpreter::new(&mut host);
 
-    println!("WasmInstance::resume: stack\n{:?}", self.stack);
-    my_run_interpreter_loop(&mut interpreter, &mut self.stack)
+    trace!("WasmInstance::resume: stack\n{:?}", self.stack);
+    my_run_interpreter_loop(&mut interpreter, &mut self.stack, &mut self.limits)
+  }
+
+  /// call frames after a trap, innermost first
+  pub fn stack_trace(&self) -> Vec<String> {
+    self
+      .stack
+      .iter()
+      .rev()
+      .enumerate()
+      .map(|(i, context)| format!("#{} {:?}", i, context.function))
+      .collect()
   }
 
   pub fn add_function_result(&mut self, return_value: RuntimeValue) {
     self.stack.back_mut().map(|function_context| {
       function_context.value_stack_mut().push(return_value).expect("should have pushed the return value");
-      println!("adding return value to {:?} initialized: {}",
+      trace!("adding return value to {:?} initialized: {}",
         function_context.function, function_context.is_initialized);
     });
-    println!("added function result {:?}, stack len:{}", return_value, self.stack.len());
+    trace!("added function result {:?}, stack len:{}", return_value, self.stack.len());
   }
 }
 
@@ -73,11 +133,16 @@ pub fn create_stack(func: &FuncRef, args: &[RuntimeValue]) -> VecDeque<FunctionC
 pub fn my_run_interpreter_loop<E>(
   interpreter: &mut Interpreter<E>,
   function_stack: &mut VecDeque<FunctionContext>,
+  limits: &mut Limits,
 ) -> Result<Option<RuntimeValue>, Trap>
 where
   E: Externals,
 {
   loop {
+    if limits.deadline.map(|deadline| deadline <= Instant::now()).unwrap_or(false) {
+      return Err(Trap::new(TrapKind::Host(Box::new(Interrupted::Timeout))));
+    }
+
     let mut function_context = function_stack
       .pop_back()
       .expect("on loop entry - not empty; on loop continue - checking for emptiness; qed");
@@ -88,18 +153,24 @@ where
     if !function_context.is_initialized() {
       let return_type = function_context.return_type;
       function_context.initialize(&function_body.locals);
-      function_context
-        .push_frame(&function_body.labels, BlockFrameType::Function, return_type)
-        .map_err(Trap::new)?;
+      if let Err(e) = function_context.push_frame(&function_body.labels, BlockFrameType::Function, return_type) {
+        function_stack.push_back(function_context);
+        return Err(Trap::new(e));
+      }
     }
 
-    let function_return = interpreter
-      .do_run_function(
-        &mut function_context,
-        function_body.opcodes.elements(),
-        &function_body.labels,
-      )
-      .map_err(Trap::new)?;
+    // the context stays on the stack after a trap, for the stack trace
+    let function_return = match interpreter.do_run_function(
+      &mut function_context,
+      function_body.opcodes.elements(),
+      &function_body.labels,
+    ) {
+      Ok(function_return) => function_return,
+      Err(e) => {
+        function_stack.push_back(function_context);
+        return Err(Trap::new(e));
+      }
+    };
 
     match function_return {
       RunResult::Return(return_value) => match function_stack.back_mut() {
@@ -113,15 +184,24 @@ where
       },
       RunResult::NestedCall(nested_func) => {
         //println!("calling nested func, stack len={}", function_stack.len());
+        if let Some(ref mut fuel) = limits.fuel {
+          if *fuel == 0 {
+            function_stack.push_back(function_context);
+            return Err(Trap::new(TrapKind::Host(Box::new(Interrupted::FuelExhausted))));
+          }
+          *fuel -= 1;
+        }
+
         match FuncInstance::invoke_context(&nested_func, &mut function_context, interpreter.externals) {
           Err(t) => {
             if let TrapKind::Host(_) = t.kind() {
               //function_context.value_stack_mut().push(RuntimeValue::I32(42)).expect("should have pushed the return value");
               function_stack.push_back(function_context);
-              println!("got host trapkind");
+              trace!("got host trapkind");
               return Err(t);
             } else {
-              println!("resume got error: {:?}", t);
+              debug!("resume got error: {:?}", t);
+              function_stack.push_back(function_context);
               return Err(t);
             }
           },
@@ -140,8 +220,245 @@ where
   }
 }
 
+/// host function called at the start of every loop, see `meter_loops`
+pub const LOOP_CHECKPOINT: &str = "loop_checkpoint";
+
+/// adds a call to the host's `LOOP_CHECKPOINT` at the start of every loop.
+/// The interpreter only comes back to `my_run_interpreter_loop` on calls, so
+/// without it a guest spinning in a loop would never run out of fuel or time
+pub fn meter_loops(module: &mut elements::Module) {
+  let is_loop = |opcode: &Opcode| match *opcode {
+    Opcode::Loop(_) => true,
+    _ => false,
+  };
+  let has_loops = module
+    .code_section()
+    .map(|code| code.bodies().iter().any(|body| body.code().elements().iter().any(&is_loop)))
+    .unwrap_or(false);
+  if !has_loops {
+    return;
+  }
+
+  // a function body implies a type section
+  let type_index = match function_type(module, Vec::new(), None) {
+    None => return,
+    Some(index) => index,
+  };
+
+  // imported functions come first in the index space: the checkpoint takes
+  // the index after them, and the module's own functions move up by one
+  let imported = module
+    .import_section()
+    .map(|imports| {
+      imports
+        .entries()
+        .iter()
+        .filter(|entry| match *entry.external() {
+          External::Function(_) => true,
+          _ => false,
+        })
+        .count()
+    })
+    .unwrap_or(0) as u32;
+
+  let checkpoint = ImportEntry::new("env".to_string(), LOOP_CHECKPOINT.to_string(), External::Function(type_index));
+  if let Some(imports) = module.import_section_mut() {
+    imports.entries_mut().push(checkpoint);
+  } else {
+    let position = module
+      .sections()
+      .iter()
+      .position(|section| match *section {
+        Section::Type(_) => true,
+        _ => false,
+      })
+      .map(|position| position + 1)
+      .unwrap_or(0);
+    module
+      .sections_mut()
+      .insert(position, Section::Import(ImportSection::with_entries(vec![checkpoint])));
+  }
+
+  let shift = |index: &mut u32| {
+    if *index >= imported {
+      *index += 1;
+    }
+  };
+  for section in module.sections_mut() {
+    match *section {
+      Section::Code(ref mut code) => {
+        for body in code.bodies_mut() {
+          let opcodes = body.code_mut().elements_mut();
+          let mut metered = Vec::with_capacity(opcodes.len());
+          for mut opcode in opcodes.drain(..) {
+            if let Opcode::Call(ref mut index) = opcode {
+              shift(index);
+            }
+            let starts_loop = is_loop(&opcode);
+            metered.push(opcode);
+            if starts_loop {
+              metered.push(Opcode::Call(imported));
+            }
+          }
+          *opcodes = metered;
+        }
+      }
+      Section::Export(ref mut exports) => {
+        for entry in exports.entries_mut() {
+          if let Internal::Function(ref mut index) = *entry.internal_mut() {
+            shift(index);
+          }
+        }
+      }
+      Section::Element(ref mut elements) => {
+        for segment in elements.entries_mut() {
+          for index in segment.members_mut().iter_mut() {
+            shift(index);
+          }
+        }
+      }
+      Section::Start(ref mut index) => shift(index),
+      _ => {}
+    }
+  }
+}
+
+/// export taking the place of the module's start function
+pub const START: &str = "__start";
+
+/// replaces the start section with the `START` export. wasmi would run the
+/// start function itself when instantiating, without fuel or deadline
+pub fn export_start(module: &mut elements::Module) {
+  let start = match module.start_section() {
+    Some(index) => index,
+    None => return,
+  };
+  let export = ExportEntry::new(START.to_string(), Internal::Function(start));
+  match module.export_section_mut() {
+    Some(exports) => exports.entries_mut().push(export),
+    // load_module already required one
+    None => return,
+  }
+  module.sections_mut().retain(|section| match *section {
+    Section::Start(_) => false,
+    _ => true,
+  });
+}
+
+/// index of the function type in the type section, added if missing.
+/// None if the module has no type section
+fn function_type(module: &mut elements::Module, params: Vec<ValueType>, ret: Option<ValueType>) -> Option<u32> {
+  let types = module.type_section_mut()?;
+  let existing = types.types().iter().position(|t| match *t {
+    Type::Function(ref function_type) => function_type.params() == &params[..] && function_type.return_type() == ret,
+  });
+  match existing {
+    Some(index) => Some(index as u32),
+    None => {
+      types.types_mut().push(Type::Function(FunctionType::new(params, ret)));
+      Some((types.types().len() - 1) as u32)
+    }
+  }
+}
+
+/// exports reading the module's n-th mutable global, from 0
+pub fn global_getter(n: usize) -> String {
+  format!("__snapshot_get_global_{}", n)
+}
+
+/// exports writing the module's n-th mutable global, from 0
+pub fn global_setter(n: usize) -> String {
+  format!("__snapshot_set_global_{}", n)
+}
+
+/// adds a getter and a setter export for every mutable global the module
+/// defines, so snapshots can capture and restore them: wasmi only gives access
+/// to exported globals, and mutable globals cannot be exported
+pub fn export_globals(module: &mut elements::Module) {
+  let imported_globals = module
+    .import_section()
+    .map(|imports| {
+      imports
+        .entries()
+        .iter()
+        .filter(|entry| match *entry.external() {
+          External::Global(_) => true,
+          _ => false,
+        })
+        .count()
+    })
+    .unwrap_or(0);
+  let mutable: Vec<(u32, ValueType)> = module
+    .global_section()
+    .map(|globals| {
+      globals
+        .entries()
+        .iter()
+        .enumerate()
+        .filter(|&(_, entry)| entry.global_type().is_mutable())
+        .map(|(i, entry)| ((imported_globals + i) as u32, entry.global_type().content_type()))
+        .collect()
+    })
+    .unwrap_or(Vec::new());
+  if mutable.is_empty() || module.function_section().is_none() || module.code_section().is_none() || module.export_section().is_none() {
+    return;
+  }
+
+  // the accessors come after every function, no index moves
+  let imported_functions = module
+    .import_section()
+    .map(|imports| {
+      imports
+        .entries()
+        .iter()
+        .filter(|entry| match *entry.external() {
+          External::Function(_) => true,
+          _ => false,
+        })
+        .count()
+    })
+    .unwrap_or(0);
+  let mut next_function = (imported_functions + module.function_section().map(|f| f.entries().len()).unwrap_or(0)) as u32;
+
+  let mut functions = Vec::new();
+  let mut bodies = Vec::new();
+  let mut exports = Vec::new();
+  for (n, &(global, value_type)) in mutable.iter().enumerate() {
+    let getter_type = match function_type(module, Vec::new(), Some(value_type)) {
+      Some(index) => index,
+      None => return,
+    };
+    let setter_type = match function_type(module, vec![value_type], None) {
+      Some(index) => index,
+      None => return,
+    };
+
+    functions.push(Func::new(getter_type));
+    bodies.push(FuncBody::new(Vec::new(), Opcodes::new(vec![Opcode::GetGlobal(global), Opcode::End])));
+    exports.push(ExportEntry::new(global_getter(n), Internal::Function(next_function)));
+    next_function += 1;
+
+    functions.push(Func::new(setter_type));
+    bodies.push(FuncBody::new(
+      Vec::new(),
+      Opcodes::new(vec![Opcode::GetLocal(0), Opcode::SetGlobal(global), Opcode::End]),
+    ));
+    exports.push(ExportEntry::new(global_setter(n), Internal::Function(next_function)));
+    next_function += 1;
+  }
+
+  for section in module.sections_mut() {
+    match *section {
+      Section::Function(ref mut section) => section.entries_mut().extend(functions.drain(..)),
+      Section::Code(ref mut section) => section.bodies_mut().extend(bodies.drain(..)),
+      Section::Export(ref mut section) => section.entries_mut().extend(exports.drain(..)),
+      _ => {}
+    }
+  }
+}
+
 pub fn load_module(file: &str, func_name: &str) -> Module {
-  let module = parity_wasm::deserialize_file(file).expect("File to be deserialized");
+  let mut module = parity_wasm::deserialize_file(file).expect("File to be deserialized");
 
   // Extracts call arguments from command-line arguments
   let _args = {
@@ -227,5 +544,8 @@ pub fn load_module(file: &str, func_name: &str) -> Module {
       .collect::<Vec<RuntimeValue>>()
   };
 
+  meter_loops(&mut module);
+  export_globals(&mut module);
+  export_start(&mut module);
   wasmi::Module::from_parity_wasm_module(module).expect("Module to be valid")
 }
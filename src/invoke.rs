@@ -0,0 +1,214 @@
+//! runs one request through a function without starting a server, and
+//! prints the response, the guest's logs and the fuel it used
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use http;
+use instances::{self, Snapshot};
+use interpreter::{load_module, Limits};
+use registry::{PreparedResponse, Request, State};
+use sync;
+use vfs::Vfs;
+
+pub const USAGE: &str = "invoke <module.wasm> <export> [--method GET] [--path /] [--header name:value]... \
+                         [--body text|@file] [--env key=value]... [--mount directory|archive.tar] \
+                         [--fuel n] [--timeout-ms n]";
+
+pub struct Options {
+  pub file_path: String,
+  pub function: String,
+  pub request: Request,
+  /// values returned by `db_get`, like an application's `env`
+  pub env: HashMap<String, String>,
+  pub mount: Option<String>,
+  pub fuel: Option<u64>,
+  pub timeout_ms: Option<u64>,
+}
+
+impl Options {
+  /// parses the arguments following `invoke`
+  pub fn parse(args: &[String]) -> Result<Options, String> {
+    let mut positional = Vec::new();
+    let mut request = Request {
+      method: "GET".to_string(),
+      path: "/".to_string(),
+      ..Default::default()
+    };
+    let mut env = HashMap::new();
+    let mut mount = None;
+    let mut fuel = None;
+    let mut timeout_ms = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+      if !arg.starts_with("--") {
+        positional.push(arg.clone());
+        continue;
+      }
+
+      let value = args.next().ok_or_else(|| format!("missing value for {}", arg))?;
+      match arg.as_str() {
+        "--method" => request.method = value.to_uppercase(),
+        "--path" => request.path = value.clone(),
+        "--header" => {
+          let (name, value) = split_pair(value, ':')?;
+          request.headers.push((name, value));
+        }
+        "--body" => request.body = read_body(value)?,
+        "--env" => {
+          let (key, value) = split_pair(value, '=')?;
+          env.insert(key, value);
+        }
+        "--mount" => mount = Some(value.clone()),
+        "--fuel" => fuel = Some(value.parse().map_err(|_| format!("invalid fuel: {}", value))?),
+        "--timeout-ms" => timeout_ms = Some(value.parse().map_err(|_| format!("invalid timeout: {}", value))?),
+        _ => return Err(format!("unknown option {}", arg)),
+      }
+    }
+
+    if positional.len() != 2 {
+      return Err("expected a module and an export".to_string());
+    }
+
+    // as a client would send it
+    if !request.body.is_empty() && !http::has_header(&request.headers, "Content-Length") {
+      let length = request.body.len().to_string();
+      request.headers.push(("Content-Length".to_string(), length));
+    }
+
+    let function = positional.pop().unwrap();
+    let file_path = positional.pop().unwrap();
+    Ok(Options {
+      file_path,
+      function,
+      request,
+      env,
+      mount,
+      fuel,
+      timeout_ms,
+    })
+  }
+}
+
+/// `name:value` or `key=value`, with the spaces around the separator removed
+fn split_pair(arg: &str, separator: char) -> Result<(String, String), String> {
+  let mut parts = arg.splitn(2, separator);
+  match (parts.next(), parts.next()) {
+    (Some(name), Some(value)) if !name.trim().is_empty() => Ok((name.trim().to_string(), value.trim().to_string())),
+    _ => Err(format!("expected {}{}value, got '{}'", if separator == ':' { "name" } else { "key" }, separator, arg)),
+  }
+}
+
+/// `@path` reads the body from a file
+fn read_body(value: &str) -> Result<Vec<u8>, String> {
+  if value.starts_with('@') {
+    let path = &value[1..];
+    let mut body = Vec::new();
+    File::open(path)
+      .and_then(|mut file| file.read_to_end(&mut body))
+      .map_err(|e| format!("could not read {}: {}", path, e))?;
+    Ok(body)
+  } else {
+    Ok(value.as_bytes().to_vec())
+  }
+}
+
+/// runs the function with the blocking host, and prints the report.
+/// Returns false if the invocation failed
+pub fn run(options: Options) -> bool {
+  let module = load_module(&options.file_path, &options.function);
+
+  let vfs = match options.mount {
+    Some(ref mount) => match Vfs::open(mount) {
+      Ok(vfs) => Some(Arc::new(vfs)),
+      Err(e) => {
+        eprintln!("could not open {}: {}", mount, e);
+        return false;
+      }
+    },
+    None => None,
+  };
+
+  // `init` gets a state of its own, like with the servers, so the request
+  // instance does not inherit its memory, logs or response
+  let init_env = Rc::new(RefCell::new(host_state(&options, vfs.clone())));
+  let mut state = host_state(&options, vfs);
+  state.request = options.request.clone();
+  let env = Rc::new(RefCell::new(state));
+
+  // without a limit, fuel is only counted
+  let fuel = options.fuel.unwrap_or(u64::max_value());
+  let mut limits = Limits {
+    fuel: Some(fuel),
+    deadline: options
+      .timeout_ms
+      .and_then(|ms| Instant::now().checked_add(Duration::from_millis(ms))),
+  };
+
+  // like the servers, the function starts from the memory left by `init`,
+  // which gets limits of its own
+  let start = Instant::now();
+  let res = Snapshot::take(&module, &init_env, &mut limits.clone())
+    .and_then(|snapshot| instances::acquire(&options.file_path, &module, Some(&snapshot), &env, &mut limits))
+    .and_then(|main| sync::run(env.clone(), &main, &options.function, &[], &mut limits));
+  let elapsed = start.elapsed();
+
+  println!();
+  match res {
+    Ok(ref response) => print_response(response),
+    Err(ref error) => println!("invocation failed: {}", error.details()),
+  }
+
+  let init_logs = init_env.borrow_mut().logs.take().unwrap_or(Vec::new());
+  if !init_logs.is_empty() {
+    println!("\ninit logs:");
+    for line in init_logs {
+      println!("  {}", line);
+    }
+  }
+
+  println!("\nlogs:");
+  for line in env.borrow_mut().logs.take().unwrap_or(Vec::new()) {
+    println!("  {}", line);
+  }
+  println!("fuel used: {}", fuel - limits.fuel.unwrap_or(0));
+  println!(
+    "duration: {}.{:03}ms",
+    elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000,
+    elapsed.subsec_nanos() / 1000 % 1000
+  );
+
+  res.is_ok()
+}
+
+/// the host state both `init` and the function start from
+fn host_state(options: &Options, vfs: Option<Arc<Vfs>>) -> State {
+  let mut state = State::new();
+  state.db = options.env.clone();
+  state.wasi.args = vec![options.function.clone()];
+  state.logs = Some(Vec::new());
+  state.vfs = vfs;
+  state
+}
+
+fn print_response(response: &PreparedResponse) {
+  let status = response.status_code.unwrap_or(500);
+  let reason = response.reason.clone().unwrap_or_else(|| http::reason_phrase(status).to_string());
+  println!("HTTP/1.1 {} {}", status, reason);
+  for &(ref name, ref value) in response.headers.iter() {
+    println!("{}: {}", name, value);
+  }
+  println!();
+
+  let body = response.body.as_ref().map(|body| &body[..]).unwrap_or(&[]);
+  match ::std::str::from_utf8(body) {
+    Ok(text) => println!("{}", text),
+    Err(_) => println!("<{} bytes of binary data>", body.len()),
+  }
+}
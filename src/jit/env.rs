@@ -1,3 +1,4 @@
+use registry;
 use cretonne_wasm::{
   ModuleEnvironment, GlobalIndex, MemoryIndex, TableIndex,
   FunctionIndex, Table, Memory, Global, SignatureIndex,
@@ -105,7 +106,10 @@ impl<'data> ModuleEnvironment<'data> for Env {
       String::from(module),
       String::from(field),
     ));
-    println!("declared function import {}:{}", module, field);
+    debug!("declared function import {}:{}", module, field);
+    if registry::find(module, field).is_none() {
+      warn!("the host does not provide {}:{}", module, field);
+    }
   }
 
   fn get_num_func_imports(&self) -> usize {
@@ -143,7 +147,7 @@ impl<'data> ModuleEnvironment<'data> for Env {
   }
 
   fn declare_memory(&mut self, memory: Memory) {
-    println!("declaring new memory zone, min: {}, max: {:?}, shared: {}", memory.pages_count, memory.maximum,
+    debug!("declaring new memory zone, min: {}, max: {:?}, shared: {}", memory.pages_count, memory.maximum,
       memory.shared);
     self.info.memories.push(Exportable::new(memory));
   }
@@ -155,7 +159,7 @@ impl<'data> ModuleEnvironment<'data> for Env {
       offset: usize, 
       data: &'data [u8]
   ) {
-    println!("declaring data init for memory n°{}, base {:?}, offset {}, data: {:?}",
+    debug!("declaring data init for memory n°{}, base {:?}, offset {}, data: {:?}",
       memory_index, base, offset, data.len());
  }
 
@@ -164,7 +168,7 @@ impl<'data> ModuleEnvironment<'data> for Env {
       func_index: FunctionIndex,
       name: &'data str
   ) {
-    println!("exporting function n°{} at '{}'", func_index, name);
+    debug!("exporting function n°{} at '{}'", func_index, name);
     self.info.functions[func_index].export_names.push(
       String::from(name)
       )
@@ -8,7 +8,7 @@ mod env;
 
 pub fn server(config: Config) {
   for app in config.applications.iter() {
-    println!("loading {}:{} at '{} {}'", app.file_path, app.function, app.method, app.url_path);
+    info!("loading {}:{} at '{} {}'", app.file_path, app.function, app.method, app.url_path);
     if let Ok(mut file) = File::open(&app.file_path) {
       let mut data = Vec::new();
       file.read_to_end(&mut data);
@@ -0,0 +1,45 @@
+//! JSON output shared by `inspect`, the logs and the access log
+
+/// quotes and escapes a string as a JSON string
+pub fn json_string(value: &str) -> String {
+  let mut res = String::with_capacity(value.len() + 2);
+  res.push('"');
+  for c in value.chars() {
+    match c {
+      '"' => res.push_str("\\\""),
+      '\\' => res.push_str("\\\\"),
+      '\n' => res.push_str("\\n"),
+      '\r' => res.push_str("\\r"),
+      '\t' => res.push_str("\\t"),
+      c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+      c => res.push(c),
+    }
+  }
+  res.push('"');
+  res
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn plain() {
+    assert_eq!(json_string(""), "\"\"");
+    assert_eq!(json_string("GET /hello"), "\"GET /hello\"");
+  }
+
+  #[test]
+  fn quotes_and_backslashes() {
+    assert_eq!(json_string("say \"hi\""), "\"say \\\"hi\\\"\"");
+    assert_eq!(json_string("C:\\dir"), "\"C:\\\\dir\"");
+  }
+
+  #[test]
+  fn control_characters() {
+    assert_eq!(json_string("a\nb\r\tc"), "\"a\\nb\\r\\tc\"");
+    assert_eq!(json_string("\u{0}\u{1b}\u{1f}"), "\"\\u0000\\u001b\\u001f\"");
+    // DEL and non-ASCII characters are valid in JSON strings
+    assert_eq!(json_string("\u{7f}é✓"), "\"\u{7f}é✓\"");
+  }
+}
@@ -0,0 +1,487 @@
+//! persistent key-value store for applications
+//!
+//! each namespace is stored in its own append-only log file. A record is
+//! `op: u8 | key length: u32 | value length: u32 | key | value`, with
+//! lengths in little endian. The log is rewritten with only the live
+//! values once it contains more garbage than data. Records are appended
+//! before the values change in memory, and a record cut by a crash or a
+//! failed write is truncated away.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const OP_SET: u8 = 1;
+const OP_DELETE: u8 = 2;
+const HEADER_SIZE: usize = 9;
+
+/// logs smaller than this are never compacted
+const COMPACTION_THRESHOLD: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum KvError {
+  KeyQuota,
+  ByteQuota,
+  Io(io::Error),
+}
+
+impl From<io::Error> for KvError {
+  fn from(e: io::Error) -> KvError {
+    KvError::Io(e)
+  }
+}
+
+struct Namespace {
+  path: PathBuf,
+  file: File,
+  data: BTreeMap<String, Vec<u8>>,
+  /// size of keys and values currently stored
+  live_bytes: usize,
+  /// size of the log file
+  log_bytes: usize,
+}
+
+impl Namespace {
+  fn open(path: PathBuf) -> io::Result<Namespace> {
+    let file = OpenOptions::new().create(true).read(true).append(true).open(&path)?;
+    let (data, log_bytes) = replay(&mut BufReader::new(&file), &path)?;
+
+    // a truncated record at the end comes from an interrupted write, we drop
+    // it so that new records are appended at a record boundary
+    if file.metadata()?.len() > log_bytes as u64 {
+      warn!("kv: dropping the end of {:?} after {} bytes", path, log_bytes);
+      file.set_len(log_bytes as u64)?;
+    }
+
+    let live_bytes = data.iter().map(|(k, v)| k.len() + v.len()).sum();
+    let mut ns = Namespace {
+      file,
+      path,
+      data,
+      live_bytes,
+      log_bytes,
+    };
+    ns.compact_if_needed()?;
+    Ok(ns)
+  }
+
+  /// writes a record, before the change is applied to `data`. A partial
+  /// write is truncated
+  fn append(&mut self, op: u8, key: &str, value: &[u8]) -> io::Result<()> {
+    let mut record = Vec::with_capacity(HEADER_SIZE + key.len() + value.len());
+    encode_record(&mut record, op, key, value);
+    if let Err(e) = self.file.write_all(&record).and_then(|_| self.file.flush()) {
+      let _ = self.file.set_len(self.log_bytes as u64);
+      return Err(e);
+    }
+    self.log_bytes += record.len();
+    Ok(())
+  }
+
+  /// call once the change is applied to `data`. The record is already
+  /// written, so a failure here loses nothing
+  fn compact_if_needed(&mut self) -> io::Result<()> {
+    if self.log_bytes > COMPACTION_THRESHOLD && self.log_bytes > 2 * self.live_bytes {
+      self.compact()?;
+    }
+    Ok(())
+  }
+
+  fn compact(&mut self) -> io::Result<()> {
+    let mut tmp_path = self.path.clone();
+    tmp_path.set_extension("compact");
+
+    let mut log_bytes = 0;
+    {
+      let mut writer = BufWriter::new(File::create(&tmp_path)?);
+      let mut record = Vec::new();
+      for (key, value) in self.data.iter() {
+        record.clear();
+        encode_record(&mut record, OP_SET, key, value);
+        writer.write_all(&record)?;
+        log_bytes += record.len();
+      }
+      writer.flush()?;
+      writer.get_ref().sync_all()?;
+    }
+
+    fs::rename(&tmp_path, &self.path)?;
+    self.file = OpenOptions::new().append(true).open(&self.path)?;
+    self.log_bytes = log_bytes;
+    Ok(())
+  }
+}
+
+/// the values left by a log, and the size of its valid records
+fn replay<R: Read>(reader: &mut R, path: &Path) -> io::Result<(BTreeMap<String, Vec<u8>>, usize)> {
+  let mut data = BTreeMap::new();
+  let mut log_bytes = 0;
+
+  while let Some((op, key, value)) = read_record(reader)? {
+    let size = HEADER_SIZE + key.len() + value.len();
+    match op {
+      OP_SET => {
+        data.insert(key, value);
+      }
+      OP_DELETE => {
+        data.remove(&key);
+      }
+      _ => {
+        warn!("kv: unknown operation {} in {:?}, ignoring the rest of the log", op, path);
+        break;
+      }
+    }
+    log_bytes += size;
+  }
+
+  Ok((data, log_bytes))
+}
+
+fn encode_record(buf: &mut Vec<u8>, op: u8, key: &str, value: &[u8]) {
+  buf.push(op);
+  buf.extend_from_slice(&encode_u32(key.len() as u32));
+  buf.extend_from_slice(&encode_u32(value.len() as u32));
+  buf.extend_from_slice(key.as_bytes());
+  buf.extend_from_slice(value);
+}
+
+fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<(u8, String, Vec<u8>)>> {
+  let mut header = [0u8; HEADER_SIZE];
+  if !read_full(reader, &mut header)? {
+    return Ok(None);
+  }
+
+  let key_len = decode_u32(&header[1..5]) as usize;
+  let value_len = decode_u32(&header[5..9]) as usize;
+  let mut key = vec![0u8; key_len];
+  let mut value = vec![0u8; value_len];
+  if !read_full(reader, &mut key)? || !read_full(reader, &mut value)? {
+    return Ok(None);
+  }
+
+  match String::from_utf8(key) {
+    Ok(key) => Ok(Some((header[0], key, value))),
+    Err(_) => Ok(None),
+  }
+}
+
+/// returns false if the reader ended before filling the buffer
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+  let mut read = 0;
+  while read < buf.len() {
+    match reader.read(&mut buf[read..]) {
+      Ok(0) => return Ok(false),
+      Ok(sz) => read += sz,
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(true)
+}
+
+fn encode_u32(value: u32) -> [u8; 4] {
+  [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+fn decode_u32(buf: &[u8]) -> u32 {
+  (buf[0] as u32) | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24
+}
+
+/// namespaces are file names in the store's directory
+pub fn valid_namespace(name: &str) -> bool {
+  !name.is_empty() && name != "." && !name.contains("..") && !name.contains(&['/', '\\', '\0'][..])
+}
+
+pub struct Store {
+  directory: PathBuf,
+  namespaces: HashMap<String, Namespace>,
+}
+
+impl Store {
+  pub fn new(directory: &str) -> io::Result<Store> {
+    fs::create_dir_all(directory)?;
+
+    Ok(Store {
+      directory: PathBuf::from(directory),
+      namespaces: HashMap::new(),
+    })
+  }
+
+  fn namespace(&mut self, name: &str) -> io::Result<&mut Namespace> {
+    if !valid_namespace(name) {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid namespace '{}'", name)));
+    }
+    if !self.namespaces.contains_key(name) {
+      let mut path = self.directory.clone();
+      path.push(format!("{}.log", name));
+      let ns = Namespace::open(path)?;
+      self.namespaces.insert(name.to_string(), ns);
+    }
+
+    Ok(self.namespaces.get_mut(name).unwrap())
+  }
+}
+
+/// access to one namespace of the store, with the application's quotas
+#[derive(Clone)]
+pub struct Handle {
+  pub store: Arc<Mutex<Store>>,
+  pub namespace: String,
+  pub max_keys: Option<usize>,
+  pub max_bytes: Option<usize>,
+}
+
+impl Handle {
+  pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, KvError> {
+    let mut store = self.store.lock().unwrap();
+    let ns = store.namespace(&self.namespace)?;
+    Ok(ns.data.get(key).cloned())
+  }
+
+  pub fn set(&self, key: &str, value: &[u8]) -> Result<(), KvError> {
+    let mut store = self.store.lock().unwrap();
+    let ns = store.namespace(&self.namespace)?;
+
+    let (key_count, live_bytes) = match ns.data.get(key) {
+      Some(old) => (ns.data.len(), ns.live_bytes - key.len() - old.len()),
+      None => (ns.data.len() + 1, ns.live_bytes),
+    };
+    let live_bytes = live_bytes + key.len() + value.len();
+
+    if self.max_keys.map(|max| key_count > max).unwrap_or(false) {
+      return Err(KvError::KeyQuota);
+    }
+    if self.max_bytes.map(|max| live_bytes > max).unwrap_or(false) {
+      return Err(KvError::ByteQuota);
+    }
+
+    ns.append(OP_SET, key, value)?;
+    ns.data.insert(key.to_string(), value.to_vec());
+    ns.live_bytes = live_bytes;
+    ns.compact_if_needed()?;
+    Ok(())
+  }
+
+  /// returns true if the key existed
+  pub fn delete(&self, key: &str) -> Result<bool, KvError> {
+    let mut store = self.store.lock().unwrap();
+    let ns = store.namespace(&self.namespace)?;
+
+    let size = match ns.data.get(key) {
+      None => return Ok(false),
+      Some(old) => key.len() + old.len(),
+    };
+
+    ns.append(OP_DELETE, key, &[])?;
+    ns.data.remove(key);
+    ns.live_bytes -= size;
+    ns.compact_if_needed()?;
+    Ok(true)
+  }
+
+  pub fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, KvError> {
+    let mut store = self.store.lock().unwrap();
+    let ns = store.namespace(&self.namespace)?;
+
+    Ok(
+      ns.data
+        .range(prefix.to_string()..)
+        .take_while(|&(k, _)| k.starts_with(prefix))
+        .map(|(k, _)| k.clone())
+        .collect(),
+    )
+  }
+}
+
+/// encodes a key list for `db_list_prefix`: each key is preceded by its length as a little endian u32
+pub fn encode_key_list(keys: &[String]) -> Vec<u8> {
+  let mut buf = Vec::new();
+  for key in keys.iter() {
+    buf.extend_from_slice(&encode_u32(key.len() as u32));
+    buf.extend_from_slice(key.as_bytes());
+  }
+  buf
+}
+
+/// inverse of `encode_key_list`, None if a length goes past the end
+pub fn decode_key_list(buf: &[u8]) -> Option<Vec<String>> {
+  let mut keys = Vec::new();
+  let mut i = 0;
+  while i < buf.len() {
+    if i + 4 > buf.len() {
+      return None;
+    }
+    let len = decode_u32(&buf[i..]) as usize;
+    i += 4;
+    if len > buf.len() - i {
+      return None;
+    }
+    keys.push(String::from_utf8_lossy(&buf[i..i + len]).into_owned());
+    i += len;
+  }
+  Some(keys)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::env;
+  use std::io::Cursor;
+  use std::process;
+
+  /// an empty directory for one test
+  fn test_directory(name: &str) -> PathBuf {
+    let mut path = env::temp_dir();
+    path.push(format!("serverless-wasm-kv-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    path
+  }
+
+  fn handle(directory: &Path) -> Handle {
+    Handle {
+      store: Arc::new(Mutex::new(Store::new(directory.to_str().unwrap()).unwrap())),
+      namespace: "app".to_string(),
+      max_keys: None,
+      max_bytes: None,
+    }
+  }
+
+  #[test]
+  fn record_round_trip() {
+    let mut buf = Vec::new();
+    encode_record(&mut buf, OP_SET, "key", b"value");
+    encode_record(&mut buf, OP_DELETE, "other", b"");
+    assert_eq!(buf.len(), 2 * HEADER_SIZE + 3 + 5 + 5);
+    assert_eq!(&buf[..HEADER_SIZE], &[OP_SET, 3, 0, 0, 0, 5, 0, 0, 0]);
+
+    let mut reader = Cursor::new(buf);
+    assert_eq!(read_record(&mut reader).unwrap(), Some((OP_SET, "key".to_string(), b"value".to_vec())));
+    assert_eq!(read_record(&mut reader).unwrap(), Some((OP_DELETE, "other".to_string(), Vec::new())));
+    assert_eq!(read_record(&mut reader).unwrap(), None);
+  }
+
+  #[test]
+  fn replay_stops_at_a_truncated_record() {
+    let mut buf = Vec::new();
+    encode_record(&mut buf, OP_SET, "a", b"1");
+    encode_record(&mut buf, OP_SET, "b", b"2");
+    encode_record(&mut buf, OP_DELETE, "a", b"");
+    let valid = buf.len();
+    encode_record(&mut buf, OP_SET, "c", b"3");
+
+    for cut in valid..buf.len() {
+      let (data, log_bytes) = replay(&mut Cursor::new(&buf[..cut]), Path::new("test")).unwrap();
+      assert_eq!(log_bytes, valid);
+      assert_eq!(data.keys().collect::<Vec<_>>(), vec!["b"]);
+    }
+  }
+
+  #[test]
+  fn open_truncates_a_torn_tail() {
+    let directory = test_directory("torn");
+    let mut path = directory.clone();
+    path.push("app.log");
+
+    let mut buf = Vec::new();
+    encode_record(&mut buf, OP_SET, "a", b"1");
+    let valid = buf.len();
+    encode_record(&mut buf, OP_SET, "b", b"2");
+    File::create(&path).unwrap().write_all(&buf[..buf.len() - 1]).unwrap();
+
+    let kv = handle(&directory);
+    assert_eq!(kv.get("a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(kv.get("b").unwrap(), None);
+    assert_eq!(fs::metadata(&path).unwrap().len(), valid as u64);
+
+    // the next record starts at a record boundary
+    kv.set("c", b"3").unwrap();
+    let kv = handle(&directory);
+    assert_eq!(kv.get("a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(kv.get("c").unwrap(), Some(b"3".to_vec()));
+  }
+
+  #[test]
+  fn open_does_not_rewrite_a_valid_log() {
+    let directory = test_directory("no-compaction");
+    let kv = handle(&directory);
+    kv.set("a", b"1").unwrap();
+    kv.set("a", b"2").unwrap();
+
+    let mut path = directory.clone();
+    path.push("app.log");
+    let size = fs::metadata(&path).unwrap().len();
+    let kv = handle(&directory);
+    assert_eq!(kv.get("a").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(fs::metadata(&path).unwrap().len(), size);
+  }
+
+  #[test]
+  fn compaction_keeps_live_values() {
+    let directory = test_directory("compaction");
+    let kv = handle(&directory);
+    let value = vec![7u8; 64 * 1024];
+    for i in 0..40 {
+      kv.set("big", &value).unwrap();
+      kv.set(&format!("key{}", i), &[i as u8]).unwrap();
+    }
+    kv.delete("key0").unwrap();
+
+    let mut path = directory.clone();
+    path.push("app.log");
+    let size = fs::metadata(&path).unwrap().len() as usize;
+    assert!(size < COMPACTION_THRESHOLD + value.len() + 1024, "log was not compacted: {} bytes", size);
+
+    let kv = handle(&directory);
+    assert_eq!(kv.get("big").unwrap(), Some(value));
+    assert_eq!(kv.get("key0").unwrap(), None);
+    assert_eq!(kv.get("key39").unwrap(), Some(vec![39]));
+    assert_eq!(kv.list_prefix("key").unwrap().len(), 39);
+  }
+
+  #[test]
+  fn quotas() {
+    let directory = test_directory("quotas");
+    let mut kv = handle(&directory);
+    kv.max_keys = Some(2);
+    kv.max_bytes = Some(10);
+
+    kv.set("a", b"1234").unwrap();
+    kv.set("b", b"1").unwrap();
+    match kv.set("c", b"1") {
+      Err(KvError::KeyQuota) => {}
+      res => panic!("expected the key quota, got {:?}", res),
+    }
+    match kv.set("a", b"12345678") {
+      Err(KvError::ByteQuota) => {}
+      res => panic!("expected the byte quota, got {:?}", res),
+    }
+    // rejected values are not kept in memory
+    assert_eq!(kv.get("a").unwrap(), Some(b"1234".to_vec()));
+    assert_eq!(kv.get("c").unwrap(), None);
+  }
+
+  #[test]
+  fn namespaces_stay_in_the_directory() {
+    assert!(valid_namespace("app"));
+    assert!(valid_namespace("my-app.v2"));
+    for name in ["", ".", "..", "../app", "a/b", "/etc/passwd", "a\\b", "a\0b"].iter() {
+      assert!(!valid_namespace(name), "{:?} was accepted", name);
+    }
+
+    let directory = test_directory("namespaces");
+    let mut kv = handle(&directory);
+    kv.namespace = "../escape".to_string();
+    assert!(kv.set("a", b"1").is_err());
+  }
+
+  #[test]
+  fn key_list_round_trip() {
+    let keys = vec!["a".to_string(), String::new(), "longer key".to_string()];
+    assert_eq!(decode_key_list(&encode_key_list(&keys)), Some(keys));
+    assert_eq!(decode_key_list(&[5, 0, 0, 0, b'a']), None);
+    assert_eq!(decode_key_list(&[1, 0]), None);
+  }
+}
@@ -0,0 +1,309 @@
+//! leveled records written to stdout, one per line, as logfmt or JSON.
+//! Records carry the request, application and module they come from,
+//! and guest output is tagged with `source=guest`
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use http;
+use json::json_string;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+  Error,
+  Warn,
+  Info,
+  Debug,
+  Trace,
+}
+
+impl Level {
+  pub fn parse(level: &str) -> Option<Level> {
+    match level {
+      "error" => Some(Level::Error),
+      "warn" => Some(Level::Warn),
+      "info" => Some(Level::Info),
+      "debug" => Some(Level::Debug),
+      "trace" => Some(Level::Trace),
+      _ => None,
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match *self {
+      Level::Error => "error",
+      Level::Warn => "warn",
+      Level::Info => "info",
+      Level::Debug => "debug",
+      Level::Trace => "trace",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+  Logfmt,
+  Json,
+}
+
+impl Format {
+  pub fn parse(format: &str) -> Option<Format> {
+    match format {
+      "logfmt" => Some(Format::Logfmt),
+      "json" => Some(Format::Json),
+      _ => None,
+    }
+  }
+}
+
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+static FORMAT: AtomicUsize = AtomicUsize::new(Format::Logfmt as usize);
+static NEXT_REQUEST_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// sets the most verbose level written, and the format of the records
+pub fn init(level: Level, format: Format) {
+  LEVEL.store(level as usize, Ordering::Relaxed);
+  FORMAT.store(format as usize, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+  level as usize <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// what a record is about
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+  pub request_id: Option<u64>,
+  /// see `WasmApp::name`
+  pub app: Option<String>,
+  /// path of the application's module
+  pub module: Option<String>,
+}
+
+impl Context {
+  /// a context with a new request ID, unique in the process
+  pub fn new_request() -> Context {
+    Context {
+      request_id: Some(NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed) as u64),
+      ..Default::default()
+    }
+  }
+}
+
+/// keys written by the server, guest fields using them get a `guest_` prefix
+const RESERVED: [&str; 7] = ["ts", "level", "request_id", "app", "module", "source", "msg"];
+
+/// writes a record from the server
+pub fn record(level: Level, context: Option<&Context>, message: &str) {
+  write(level, context, None, message, &[]);
+}
+
+/// writes a line logged by a guest, with its own fields, see `RateLimiter`
+pub fn guest(level: Level, context: &Context, message: &str, fields: &[(String, String)]) {
+  if enabled(level) {
+    write(level, Some(context), Some("guest"), message, fields);
+  }
+}
+
+fn write(level: Level, context: Option<&Context>, source: Option<&str>, message: &str, extra: &[(String, String)]) {
+  let mut fields: Vec<(&str, Value)> = vec![
+    ("ts", Value::Str(timestamp(SystemTime::now()))),
+    ("level", Value::Str(level.name().to_string())),
+  ];
+  if let Some(context) = context {
+    if let Some(id) = context.request_id {
+      fields.push(("request_id", Value::Int(id)));
+    }
+    if let Some(ref app) = context.app {
+      fields.push(("app", Value::Str(app.clone())));
+    }
+    if let Some(ref module) = context.module {
+      fields.push(("module", Value::Str(module.clone())));
+    }
+  }
+  if let Some(source) = source {
+    fields.push(("source", Value::Str(source.to_string())));
+  }
+  fields.push(("msg", Value::Str(message.to_string())));
+
+  let extra: Vec<(String, &str)> = extra
+    .iter()
+    .map(|&(ref key, ref value)| {
+      let key = if RESERVED.contains(&key.as_str()) {
+        format!("guest_{}", key)
+      } else {
+        key.clone()
+      };
+      (key, value.as_str())
+    })
+    .collect();
+
+  let line = if FORMAT.load(Ordering::Relaxed) == Format::Json as usize {
+    let mut fields: Vec<String> = fields
+      .iter()
+      .map(|&(key, ref value)| match *value {
+        Value::Int(i) => format!("{}:{}", json_string(key), i),
+        Value::Str(ref s) => format!("{}:{}", json_string(key), json_string(s)),
+      })
+      .collect();
+    fields.extend(extra.iter().map(|&(ref key, value)| format!("{}:{}", json_string(key), json_string(value))));
+    format!("{{{}}}", fields.join(","))
+  } else {
+    let mut fields: Vec<String> = fields
+      .iter()
+      .map(|&(key, ref value)| match *value {
+        Value::Int(i) => format!("{}={}", key, i),
+        Value::Str(ref s) => format!("{}={}", key, logfmt_value(s)),
+      })
+      .collect();
+    fields.extend(extra.iter().map(|&(ref key, value)| format!("{}={}", logfmt_key(key), logfmt_value(value))));
+    fields.join(" ")
+  };
+
+  let stdout = io::stdout();
+  let mut out = stdout.lock();
+  let _ = writeln!(out, "{}", line);
+}
+
+enum Value {
+  Int(u64),
+  Str(String),
+}
+
+/// quoted if it contains spaces, quotes or `=`
+fn logfmt_value(value: &str) -> String {
+  if !value.is_empty() && !value.chars().any(|c| c <= ' ' || c == '"' || c == '=') {
+    return value.to_string();
+  }
+
+  let mut res = String::with_capacity(value.len() + 2);
+  res.push('"');
+  for c in value.chars() {
+    match c {
+      '"' => res.push_str("\\\""),
+      '\\' => res.push_str("\\\\"),
+      '\n' => res.push_str("\\n"),
+      c => res.push(c),
+    }
+  }
+  res.push('"');
+  res
+}
+
+/// guest keys cannot break the record: characters that would need
+/// quoting are replaced with `_`
+fn logfmt_key(key: &str) -> String {
+  if key.is_empty() {
+    return "_".to_string();
+  }
+  key.chars().map(|c| if c <= ' ' || c == '"' || c == '=' { '_' } else { c }).collect()
+}
+
+/// RFC 3339 in UTC, with milliseconds
+pub fn timestamp(time: SystemTime) -> String {
+  let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+  let secs = since_epoch.as_secs();
+  let (year, month, day) = http::civil_from_days(secs / 86400);
+  let rem = secs % 86400;
+  format!(
+    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+    year,
+    month,
+    day,
+    rem / 3600,
+    (rem % 3600) / 60,
+    rem % 60,
+    since_epoch.subsec_nanos() / 1_000_000
+  )
+}
+
+/// guest log lines allowed per second for an application, shared by the
+/// instances of that application in a worker
+#[derive(Clone)]
+pub struct RateLimiter {
+  inner: Arc<Mutex<Window>>,
+}
+
+struct Window {
+  rate: u32,
+  started: Instant,
+  count: u32,
+  dropped: u64,
+}
+
+impl RateLimiter {
+  pub fn new(rate: u32) -> RateLimiter {
+    RateLimiter {
+      inner: Arc::new(Mutex::new(Window {
+        rate,
+        started: Instant::now(),
+        count: 0,
+        dropped: 0,
+      })),
+    }
+  }
+
+  /// false if the line must be dropped. The number of dropped lines is
+  /// logged once the next second starts
+  pub fn allow(&self, context: &Context) -> bool {
+    let mut window = self.inner.lock().unwrap();
+    if window.started.elapsed() >= Duration::from_secs(1) {
+      if window.dropped > 0 {
+        record(
+          Level::Warn,
+          Some(context),
+          &format!("dropped {} guest log lines over the limit of {} per second", window.dropped, window.rate),
+        );
+      }
+      window.started = Instant::now();
+      window.count = 0;
+      window.dropped = 0;
+    }
+
+    if window.count < window.rate {
+      window.count += 1;
+      true
+    } else {
+      window.dropped += 1;
+      false
+    }
+  }
+}
+
+macro_rules! log {
+  ($level:expr, $context:expr, $($arg:tt)+) => {
+    if ::logging::enabled($level) {
+      ::logging::record($level, $context, &format!($($arg)+));
+    }
+  };
+}
+
+// `info!(context; "format", args)` adds the context to the record,
+// `info!("format", args)` is for records outside of a request
+
+macro_rules! error {
+  ($context:expr; $($arg:tt)+) => (log!(::logging::Level::Error, Some(&$context), $($arg)+));
+  ($($arg:tt)+) => (log!(::logging::Level::Error, None, $($arg)+));
+}
+
+macro_rules! warn {
+  ($context:expr; $($arg:tt)+) => (log!(::logging::Level::Warn, Some(&$context), $($arg)+));
+  ($($arg:tt)+) => (log!(::logging::Level::Warn, None, $($arg)+));
+}
+
+macro_rules! info {
+  ($context:expr; $($arg:tt)+) => (log!(::logging::Level::Info, Some(&$context), $($arg)+));
+  ($($arg:tt)+) => (log!(::logging::Level::Info, None, $($arg)+));
+}
+
+macro_rules! debug {
+  ($context:expr; $($arg:tt)+) => (log!(::logging::Level::Debug, Some(&$context), $($arg)+));
+  ($($arg:tt)+) => (log!(::logging::Level::Debug, None, $($arg)+));
+}
+
+macro_rules! trace {
+  ($context:expr; $($arg:tt)+) => (log!(::logging::Level::Trace, Some(&$context), $($arg)+));
+  ($($arg:tt)+) => (log!(::logging::Level::Trace, None, $($arg)+));
+}
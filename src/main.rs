@@ -1,4 +1,5 @@
 extern crate httparse;
+extern crate libc;
 extern crate mio;
 extern crate parity_wasm;
 extern crate rouille;
@@ -14,23 +15,249 @@ extern crate cretonne_simplejit;
 extern crate serde_derive;
 
 use std::env::args;
+use std::path::Path;
+use std::process;
 
+#[macro_use]
+mod logging;
+
+mod access_log;
 mod async;
 mod config;
+mod cron;
+mod errors;
+mod http;
+mod inspect;
+mod instances;
 mod interpreter;
+mod invoke;
+mod json;
+mod kv;
+mod metrics;
+mod registry;
+mod secrets;
+mod static_files;
 mod sync;
+mod vfs;
+mod wasi;
+// compiles modules, but cannot run them yet
+#[allow(dead_code)]
 mod jit;
 
+// exit codes, following sysexits.h
+/// `invoke`: the function failed
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 64;
+/// `inspect`: the module cannot be loaded or needs functions the host does not provide
+const EXIT_DATA_ERROR: i32 = 65;
+/// the configuration file or module does not exist
+const EXIT_NO_INPUT: i32 = 66;
+/// the server cannot listen on its address
+const EXIT_UNAVAILABLE: i32 = 69;
+/// the configuration is invalid
+const EXIT_CONFIG: i32 = 78;
+
+const USAGE: &str = "Usage:
+  {bin} serve <config_file> [--engine interp] [--mode async|sync] [--listen address] [--workers n]
+  {bin} check <config_file>
+  {bin} inspect <module.wasm> [--format text|json]
+  {bin} {invoke}";
+
 fn main() {
   let args: Vec<_> = args().collect();
-  if args.len() != 2 {
-    println!("Usage: {} <config_file>", args[0]);
-    return;
+  let code = match args.get(1).map(|s| s.as_str()) {
+    Some("serve") => serve(&args[0], &args[2..]),
+    Some("check") if args.len() == 3 => check(&args[2]),
+    Some("inspect") => inspect(&args[0], &args[2..]),
+    Some("invoke") => invoke(&args[0], &args[2..]),
+    _ => usage(&args[0], None),
+  };
+  process::exit(code);
+}
+
+fn usage(bin: &str, error: Option<String>) -> i32 {
+  if let Some(error) = error {
+    eprintln!("{}", error);
+  }
+  eprintln!("{}", USAGE.replace("{bin}", bin).replace("{invoke}", invoke::USAGE));
+  EXIT_USAGE
+}
+
+fn load_config(file: &str) -> Result<config::Config, i32> {
+  match config::load(file) {
+    Ok(config) => Ok(config),
+    Err(config::LoadError::Read(e)) => {
+      eprintln!("{}", e);
+      Err(EXIT_NO_INPUT)
+    }
+    Err(config::LoadError::Invalid(e)) => {
+      eprintln!("{}", e);
+      Err(EXIT_CONFIG)
+    }
+  }
+}
+
+fn check_config(config: &config::Config) -> Result<(), i32> {
+  let problems = config::check(config);
+  for problem in problems.iter() {
+    eprintln!("{}", problem);
+  }
+
+  if problems.is_empty() {
+    Ok(())
+  } else {
+    Err(EXIT_CONFIG)
+  }
+}
+
+fn serve(bin: &str, args: &[String]) -> i32 {
+  let mut file = None;
+  let mut engine = "interp".to_string();
+  let mut mode = "async".to_string();
+  let mut listen = None;
+  let mut workers = None;
+
+  let mut args = args.iter();
+  while let Some(arg) = args.next() {
+    if !arg.starts_with("--") {
+      if file.is_some() {
+        return usage(bin, Some(format!("unexpected argument {}", arg)));
+      }
+      file = Some(arg.clone());
+      continue;
+    }
+
+    let value = match args.next() {
+      Some(value) => value.clone(),
+      None => return usage(bin, Some(format!("missing value for {}", arg))),
+    };
+    match arg.as_str() {
+      "--engine" => engine = value,
+      "--mode" => mode = value,
+      "--listen" => listen = Some(value),
+      "--workers" => match value.parse::<usize>() {
+        Ok(n) => workers = Some(n),
+        Err(_) => return usage(bin, Some(format!("invalid number of workers: {}", value))),
+      },
+      _ => return usage(bin, Some(format!("unknown option {}", arg))),
+    }
+  }
+
+  let file = match file {
+    Some(file) => file,
+    None => return usage(bin, Some("missing configuration file".to_string())),
+  };
+  if engine != "interp" {
+    return usage(bin, Some(format!("unknown engine: {}", engine)));
+  }
+  if mode != "async" && mode != "sync" {
+    return usage(bin, Some(format!("unknown server mode: {}", mode)));
+  }
+
+  let mut config = match load_config(&file) {
+    Ok(config) => config,
+    Err(code) => return code,
+  };
+  if let Some(listen) = listen {
+    config.listen_address = listen;
+  }
+  if workers.is_some() {
+    config.workers = workers;
+  }
+  if let Err(code) = check_config(&config) {
+    return code;
+  }
+
+  // both were validated by check_config
+  let level = config.log_level.as_ref().and_then(|level| logging::Level::parse(level));
+  let format = config.log_format.as_ref().and_then(|format| logging::Format::parse(format));
+  logging::init(level.unwrap_or(logging::Level::Info), format.unwrap_or(logging::Format::Logfmt));
+
+  let res = match mode.as_str() {
+    "sync" => sync::server(config),
+    _ => async::server(config),
+  };
+
+  match res {
+    Ok(()) => 0,
+    Err(e) => {
+      eprintln!("{}", e);
+      EXIT_UNAVAILABLE
+    }
+  }
+}
+
+fn check(file: &str) -> i32 {
+  match load_config(file).and_then(|config| check_config(&config)) {
+    Ok(()) => {
+      println!("{}: OK", file);
+      0
+    }
+    Err(code) => code,
+  }
+}
+
+fn inspect(bin: &str, args: &[String]) -> i32 {
+  let (file, json) = match args {
+    [ref file] => (file, false),
+    [ref file, ref option, ref format] if option == "--format" && (format == "text" || format == "json") => {
+      (file, format == "json")
+    }
+    _ => return usage(bin, None),
+  };
+
+  if !Path::new(file).is_file() {
+    eprintln!("{} does not exist", file);
+    return EXIT_NO_INPUT;
+  }
+
+  match inspect::inspect(file) {
+    Ok(report) => {
+      if json {
+        println!("{}", report.to_json());
+      } else {
+        report.print();
+      }
+      if report.unsupported_imports().is_empty() {
+        0
+      } else {
+        EXIT_DATA_ERROR
+      }
+    }
+    Err(e) => {
+      eprintln!("{}", e);
+      EXIT_DATA_ERROR
+    }
+  }
+}
+
+fn invoke(bin: &str, args: &[String]) -> i32 {
+  let options = match invoke::Options::parse(args) {
+    Ok(options) => options,
+    Err(e) => return usage(bin, Some(e)),
+  };
+  if !Path::new(&options.file_path).is_file() {
+    eprintln!("{} does not exist", options.file_path);
+    return EXIT_NO_INPUT;
+  }
+  // load_module panics on a module it cannot run
+  match inspect::inspect(&options.file_path) {
+    Ok(ref report) if report.function(&options.function).is_some() => {}
+    Ok(_) => {
+      eprintln!("{} does not export a function named {}", options.file_path, options.function);
+      return EXIT_DATA_ERROR;
+    }
+    Err(e) => {
+      eprintln!("{}", e);
+      return EXIT_DATA_ERROR;
+    }
   }
 
-  if let Some(config) = config::load(&args[1]) {
-    async::server(config);
+  // the report already has the guest's logs
+  logging::init(logging::Level::Warn, logging::Format::Logfmt);
+  if invoke::run(options) {
+    0
   } else {
-    println!("invalid configuration");
+    EXIT_FAILURE
   }
 }
@@ -0,0 +1,267 @@
+//! counters and histograms shared by the workers, exposed in the
+//! Prometheus text format on their own listener
+
+use rouille;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// upper bounds of the buckets, in seconds
+const DURATION_BUCKETS: &[f64] = &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+/// fuel used: function calls, host functions included, and loop iterations
+const FUEL_BUCKETS: &[f64] = &[10.0, 100.0, 1e3, 1e4, 1e5, 1e6, 1e7, 1e8];
+/// 64kB pages
+const PAGE_BUCKETS: &[f64] = &[1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 100.0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Backend {
+  Connect,
+  Read,
+  Write,
+}
+
+impl Backend {
+  fn name(&self) -> &'static str {
+    match *self {
+      Backend::Connect => "connect",
+      Backend::Read => "read",
+      Backend::Write => "write",
+    }
+  }
+}
+
+struct Histogram {
+  bounds: &'static [f64],
+  /// observations per bucket, not cumulative
+  counts: Vec<u64>,
+  sum: f64,
+  count: u64,
+}
+
+impl Histogram {
+  fn new(bounds: &'static [f64]) -> Histogram {
+    Histogram {
+      bounds,
+      counts: vec![0; bounds.len()],
+      sum: 0.0,
+      count: 0,
+    }
+  }
+
+  fn observe(&mut self, value: f64) {
+    if let Some(i) = self.bounds.iter().position(|bound| value <= *bound) {
+      self.counts[i] += 1;
+    }
+    self.sum += value;
+    self.count += 1;
+  }
+
+  fn write(&self, out: &mut String, name: &str, labels: &str) {
+    let separator = if labels.is_empty() { "" } else { "," };
+    let mut cumulative = 0;
+    for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+      cumulative += count;
+      let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bound, cumulative);
+    }
+    let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, self.count);
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+  }
+}
+
+#[derive(Default)]
+struct Worker {
+  active_sessions: usize,
+  connections: usize,
+  capacity: usize,
+}
+
+struct LastRun {
+  /// seconds since the epoch
+  started: f64,
+  duration: f64,
+  success: bool,
+}
+
+#[derive(Default)]
+struct Registry {
+  /// (route, status) -> count
+  requests: HashMap<(String, u16), u64>,
+  /// per route
+  request_duration: HashMap<String, Histogram>,
+  /// per application
+  guest_time: HashMap<String, Histogram>,
+  fuel: HashMap<String, Histogram>,
+  memory_pages: HashMap<String, Histogram>,
+  backend: HashMap<Backend, Histogram>,
+  /// per async worker
+  workers: HashMap<usize, Worker>,
+  /// per schedule
+  schedules: HashMap<String, LastRun>,
+}
+
+fn observe<K: Eq + ::std::hash::Hash>(histograms: &mut HashMap<K, Histogram>, key: K, bounds: &'static [f64], value: f64) {
+  histograms.entry(key).or_insert_with(|| Histogram::new(bounds)).observe(value);
+}
+
+fn seconds(duration: Duration) -> f64 {
+  duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// escapes a label value
+fn label(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// cheap to clone, the handles share the same registry
+#[derive(Clone, Default)]
+pub struct Metrics {
+  inner: Arc<Mutex<Registry>>,
+}
+
+impl Metrics {
+  pub fn new() -> Metrics {
+    Metrics::default()
+  }
+
+  /// a response was sent. `route` is the application or static directory
+  /// that handled the request
+  pub fn request(&self, route: &str, status: u16, duration: Duration) {
+    let mut registry = self.inner.lock().unwrap();
+    *registry.requests.entry((route.to_string(), status)).or_insert(0) += 1;
+    observe(&mut registry.request_duration, route.to_string(), DURATION_BUCKETS, seconds(duration));
+  }
+
+  /// a guest function returned or failed. `guest_time` only counts the time
+  /// spent running it, not waiting for I/O. `fuel` is None without a limit
+  pub fn invocation(&self, app: &str, guest_time: Duration, fuel: Option<u64>, memory_pages: Option<usize>) {
+    let mut registry = self.inner.lock().unwrap();
+    observe(&mut registry.guest_time, app.to_string(), DURATION_BUCKETS, seconds(guest_time));
+    if let Some(fuel) = fuel {
+      observe(&mut registry.fuel, app.to_string(), FUEL_BUCKETS, fuel as f64);
+    }
+    if let Some(pages) = memory_pages {
+      observe(&mut registry.memory_pages, app.to_string(), PAGE_BUCKETS, pages as f64);
+    }
+  }
+
+  /// how long the guest waited for a backend connection to be established,
+  /// or for a read or write to complete
+  pub fn backend(&self, operation: Backend, duration: Duration) {
+    let mut registry = self.inner.lock().unwrap();
+    observe(&mut registry.backend, operation, DURATION_BUCKETS, seconds(duration));
+  }
+
+  /// sessions of an async worker, and the space they use in its connections slab
+  pub fn worker(&self, worker: usize, active_sessions: usize, connections: usize, capacity: usize) {
+    let mut registry = self.inner.lock().unwrap();
+    registry.workers.insert(
+      worker,
+      Worker {
+        active_sessions,
+        connections,
+        capacity,
+      },
+    );
+  }
+
+  /// a scheduled function returned or failed
+  pub fn schedule_run(&self, schedule: &str, started: SystemTime, duration: Duration, success: bool) {
+    let mut registry = self.inner.lock().unwrap();
+    registry.schedules.insert(
+      schedule.to_string(),
+      LastRun {
+        started: started.duration_since(UNIX_EPOCH).map(seconds).unwrap_or(0.0),
+        duration: seconds(duration),
+        success,
+      },
+    );
+  }
+
+  /// the Prometheus text format
+  pub fn render(&self) -> String {
+    let registry = self.inner.lock().unwrap();
+    let mut out = String::new();
+
+    out.push_str("# HELP serverless_requests_total Responses sent, by route and status.\n");
+    out.push_str("# TYPE serverless_requests_total counter\n");
+    for (&(ref route, status), count) in registry.requests.iter() {
+      let _ = writeln!(out, "serverless_requests_total{{route=\"{}\",status=\"{}\"}} {}", label(route), status, count);
+    }
+
+    let histograms: [(&str, &str, &HashMap<String, Histogram>); 4] = [
+      ("serverless_request_duration_seconds", "route", &registry.request_duration),
+      ("serverless_guest_execution_seconds", "app", &registry.guest_time),
+      ("serverless_guest_fuel_used", "app", &registry.fuel),
+      ("serverless_guest_memory_pages", "app", &registry.memory_pages),
+    ];
+    let help = [
+      "Time to handle a request, until its response was sent.",
+      "Time spent running guest code, per invocation.",
+      "Function calls and loop iterations per invocation, for applications with a fuel limit.",
+      "Guest memory size at the end of an invocation, in 64kB pages.",
+    ];
+    for (&(name, key, histograms), help) in histograms.iter().zip(help.iter()) {
+      let _ = writeln!(out, "# HELP {} {}", name, help);
+      let _ = writeln!(out, "# TYPE {} histogram", name);
+      for (value, histogram) in histograms.iter() {
+        histogram.write(&mut out, name, &format!("{}=\"{}\"", key, label(value)));
+      }
+    }
+
+    out.push_str("# HELP serverless_backend_duration_seconds Time waited for backend operations.\n");
+    out.push_str("# TYPE serverless_backend_duration_seconds histogram\n");
+    for (operation, histogram) in registry.backend.iter() {
+      let labels = format!("operation=\"{}\"", operation.name());
+      histogram.write(&mut out, "serverless_backend_duration_seconds", &labels);
+    }
+
+    let gauges: [(&str, &str, fn(&Worker) -> usize); 3] = [
+      ("serverless_active_sessions", "Client sessions and scheduled functions in progress.", |w| w.active_sessions),
+      ("serverless_connections", "Used entries of the connections slab.", |w| w.connections),
+      ("serverless_connections_capacity", "Size of the connections slab.", |w| w.capacity),
+    ];
+    for &(name, help, value) in gauges.iter() {
+      let _ = writeln!(out, "# HELP {} {}", name, help);
+      let _ = writeln!(out, "# TYPE {} gauge", name);
+      for (worker, w) in registry.workers.iter() {
+        let _ = writeln!(out, "{}{{worker=\"{}\"}} {}", name, worker, value(w));
+      }
+    }
+
+    let schedule_gauges: [(&str, &str, fn(&LastRun) -> f64); 3] = [
+      ("serverless_schedule_last_run_timestamp_seconds", "Start of the last run of each schedule.", |r| r.started),
+      ("serverless_schedule_last_run_duration_seconds", "Duration of the last run of each schedule.", |r| r.duration),
+      (
+        "serverless_schedule_last_run_success",
+        "1 if the last run of each schedule succeeded, 0 otherwise.",
+        |r| if r.success { 1.0 } else { 0.0 },
+      ),
+    ];
+    for &(name, help, value) in schedule_gauges.iter() {
+      let _ = writeln!(out, "# HELP {} {}", name, help);
+      let _ = writeln!(out, "# TYPE {} gauge", name);
+      for (schedule, run) in registry.schedules.iter() {
+        let _ = writeln!(out, "{}{{schedule=\"{}\"}} {}", name, label(schedule), value(run));
+      }
+    }
+
+    out
+  }
+}
+
+/// serves `GET /metrics` from its own thread. Returns if the address cannot be used
+pub fn serve(address: &str, metrics: Metrics) -> Result<(), String> {
+  let server = rouille::Server::new(address, move |request| {
+    if request.method() == "GET" && request.url() == "/metrics" {
+      rouille::Response::from_data("text/plain; version=0.0.4", metrics.render())
+    } else {
+      rouille::Response::empty_404()
+    }
+  }).map_err(|e| format!("could not listen on {}: {}", address, e))?;
+
+  thread::spawn(move || server.run());
+  Ok(())
+}
@@ -0,0 +1,1031 @@
+//! host functions exported to WASM applications
+//!
+//! every import is described once in `FUNCTIONS`, with its signature and
+//! handler. Functions that wait on the network or on time go through the
+//! `Io` trait: the sync server blocks in place, the async server suspends
+//! the instance and resumes it from its event loop. The JIT engine does not
+//! run modules yet, it only warns about imports missing from this table.
+
+use slab::Slab;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::io::Read;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use wasmi::memory_units::Pages;
+use wasmi::*;
+
+use errors::InvocationError;
+use http;
+use interpreter;
+use kv;
+use logging::{self, Context, Level, RateLimiter};
+use metrics::Metrics;
+use secrets::SecretValue;
+use vfs::{Vfs, VfsError, VfsFile};
+use wasi;
+
+pub type HostResult = Result<Option<RuntimeValue>, Trap>;
+pub type Handler = fn(&Rc<RefCell<State>>, &mut dyn Io, RuntimeArgs) -> HostResult;
+
+pub struct HostFunction {
+  pub name: &'static str,
+  pub params: &'static [ValueType],
+  pub ret: Option<ValueType>,
+  pub handler: Handler,
+}
+
+/// a host function's index is its position in this list
+pub static FUNCTIONS: [HostFunction; 30] = [
+  HostFunction {
+    name: "log",
+    params: &[ValueType::I32, ValueType::I64],
+    ret: None,
+    handler: log,
+  },
+  HostFunction {
+    name: "response_set_status_line",
+    params: &[ValueType::I32, ValueType::I32, ValueType::I64],
+    ret: None,
+    handler: response_set_status_line,
+  },
+  HostFunction {
+    name: "response_set_header",
+    params: &[ValueType::I32, ValueType::I64, ValueType::I32, ValueType::I64],
+    ret: None,
+    handler: response_set_header,
+  },
+  HostFunction {
+    name: "response_set_body",
+    params: &[ValueType::I32, ValueType::I64],
+    ret: None,
+    handler: response_set_body,
+  },
+  HostFunction {
+    name: "tcp_connect",
+    params: &[ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I32),
+    handler: tcp_connect,
+  },
+  HostFunction {
+    name: "tcp_read",
+    params: &[ValueType::I32, ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I64),
+    handler: tcp_read,
+  },
+  HostFunction {
+    name: "tcp_write",
+    params: &[ValueType::I32, ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I64),
+    handler: tcp_write,
+  },
+  HostFunction {
+    name: "db_get",
+    params: &[ValueType::I32, ValueType::I64, ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I64),
+    handler: db_get,
+  },
+  HostFunction {
+    name: "tcp_connect_pooled",
+    params: &[ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I32),
+    handler: tcp_connect_pooled,
+  },
+  HostFunction {
+    name: "tcp_close",
+    params: &[ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: tcp_close,
+  },
+  HostFunction {
+    name: "poll",
+    params: &[ValueType::I32, ValueType::I64, ValueType::I64],
+    ret: Some(ValueType::I32),
+    handler: poll,
+  },
+  HostFunction {
+    name: "clock_now",
+    params: &[ValueType::I32],
+    ret: Some(ValueType::I64),
+    handler: clock_now,
+  },
+  HostFunction {
+    name: "sleep_ms",
+    params: &[ValueType::I64],
+    ret: None,
+    handler: sleep_ms,
+  },
+  HostFunction {
+    name: "db_set",
+    params: &[ValueType::I32, ValueType::I64, ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I32),
+    handler: db_set,
+  },
+  HostFunction {
+    name: "db_delete",
+    params: &[ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I32),
+    handler: db_delete,
+  },
+  HostFunction {
+    name: "db_list_prefix",
+    params: &[ValueType::I32, ValueType::I64, ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I64),
+    handler: db_list_prefix,
+  },
+  HostFunction {
+    name: "secret_get",
+    params: &[ValueType::I32, ValueType::I64, ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I64),
+    handler: secret_get,
+  },
+  HostFunction {
+    name: "response_write_chunk",
+    params: &[ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I64),
+    handler: response_write_chunk,
+  },
+  HostFunction {
+    name: "response_finish",
+    params: &[],
+    ret: None,
+    handler: response_finish,
+  },
+  HostFunction {
+    name: "fs_open",
+    params: &[ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I32),
+    handler: fs_open,
+  },
+  HostFunction {
+    name: "fs_read",
+    params: &[ValueType::I32, ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I64),
+    handler: fs_read,
+  },
+  HostFunction {
+    name: "fs_close",
+    params: &[ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: fs_close,
+  },
+  HostFunction {
+    name: "fs_stat",
+    params: &[ValueType::I32, ValueType::I64, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: fs_stat,
+  },
+  HostFunction {
+    name: "fs_readdir",
+    params: &[ValueType::I32, ValueType::I64, ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I64),
+    handler: fs_readdir,
+  },
+  HostFunction {
+    name: "request_method",
+    params: &[ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I64),
+    handler: request_method,
+  },
+  HostFunction {
+    name: "request_path",
+    params: &[ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I64),
+    handler: request_path,
+  },
+  HostFunction {
+    name: "request_header",
+    params: &[ValueType::I32, ValueType::I64, ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I64),
+    handler: request_header,
+  },
+  HostFunction {
+    name: "request_body",
+    params: &[ValueType::I32, ValueType::I64],
+    ret: Some(ValueType::I64),
+    handler: request_body,
+  },
+  HostFunction {
+    name: "log_v2",
+    params: &[ValueType::I32, ValueType::I32, ValueType::I64, ValueType::I32, ValueType::I64],
+    ret: None,
+    handler: log_v2,
+  },
+  // added to every loop by `interpreter::meter_loops`
+  HostFunction {
+    name: interpreter::LOOP_CHECKPOINT,
+    params: &[],
+    ret: None,
+    handler: loop_checkpoint,
+  },
+];
+
+/// returns the index and description of a host function. WASI
+/// functions come after the `env` ones
+pub fn find(module: &str, name: &str) -> Option<(usize, &'static HostFunction)> {
+  let (base, table): (usize, &'static [HostFunction]) = match module {
+    "env" => (0, &FUNCTIONS),
+    wasi::MODULE => (FUNCTIONS.len(), &wasi::FUNCTIONS),
+    _ => return None,
+  };
+
+  table
+    .iter()
+    .enumerate()
+    .find(|&(_, f)| f.name == name)
+    .map(|(index, f)| (base + index, f))
+}
+
+fn function(index: usize) -> Option<&'static HostFunction> {
+  if index < FUNCTIONS.len() {
+    FUNCTIONS.get(index)
+  } else {
+    wasi::FUNCTIONS.get(index - FUNCTIONS.len())
+  }
+}
+
+/// checks an import against the registry
+pub fn resolve_func(module: &str, field_name: &str, signature: &Signature) -> Result<FuncRef, Error> {
+  let (index, function) = match find(module, field_name) {
+    Some(f) => f,
+    None => {
+      return Err(Error::Instantiation(format!(
+        "Export {} not found",
+        field_name
+      )))
+    }
+  };
+
+  if signature.params() != function.params || signature.return_type() != function.ret {
+    return Err(Error::Instantiation(format!(
+      "Export `{}` doesnt match expected type {:?}",
+      field_name, signature
+    )));
+  }
+
+  Ok(FuncInstance::alloc_host(signature.clone(), index))
+}
+
+/// WASI modules usually export their memory instead of importing it
+pub fn attach_exported_memory(state: &Rc<RefCell<State>>, instance: &ModuleRef) {
+  if state.borrow().memory.is_none() {
+    if let Some(ExternVal::Memory(memory)) = instance.export_by_name("memory") {
+      state.borrow_mut().memory = Some(memory);
+    }
+  }
+}
+
+/// functions that wait on sockets, time or the client
+pub trait Io {
+  fn tcp_connect(&mut self, state: &Rc<RefCell<State>>, address: &str, pooled: bool) -> HostResult;
+  fn tcp_read(&mut self, state: &Rc<RefCell<State>>, fd: i32, ptr: u32, size: u64) -> HostResult;
+  fn tcp_write(&mut self, state: &Rc<RefCell<State>>, fd: i32, ptr: u32, size: u64) -> HostResult;
+  fn tcp_close(&mut self, state: &Rc<RefCell<State>>, fd: i32) -> HostResult;
+  fn poll(&mut self, state: &Rc<RefCell<State>>, ptr: u32, count: u64, timeout_ms: i64) -> HostResult;
+  fn sleep_ms(&mut self, state: &Rc<RefCell<State>>, ms: u64) -> HostResult;
+  fn response_write_chunk(&mut self, state: &Rc<RefCell<State>>, ptr: u32, size: u64) -> HostResult;
+  fn response_finish(&mut self, state: &Rc<RefCell<State>>) -> HostResult;
+}
+
+#[derive(Clone, Debug)]
+pub struct PreparedResponse {
+  pub status_code: Option<u16>,
+  pub reason: Option<String>,
+  pub headers: Vec<(String, String)>,
+  pub body: Option<Vec<u8>>,
+}
+
+impl PreparedResponse {
+  pub fn new() -> PreparedResponse {
+    PreparedResponse {
+      status_code: None,
+      reason: None,
+      headers: Vec::new(),
+      body: None,
+    }
+  }
+}
+
+/// the request handled by the function, read with the `request_*` functions
+#[derive(Clone, Debug, Default)]
+pub struct Request {
+  pub method: String,
+  /// path and query string
+  pub path: String,
+  pub headers: Vec<(String, String)>,
+  pub body: Vec<u8>,
+}
+
+pub struct State {
+  pub memory: Option<MemoryRef>,
+  pub instance: Option<ModuleRef>,
+  /// requests served by `instance`, the current one included
+  pub instance_requests: u64,
+  pub request: Request,
+  pub prepared_response: PreparedResponse,
+  /// false for scheduled functions, which ignore the response functions
+  pub has_response: bool,
+  /// sockets opened by the blocking host
+  pub connections: Slab<TcpStream>,
+  pub db: HashMap<String, String>,
+  pub kv: Option<kv::Handle>,
+  /// secrets declared by the application
+  pub secrets: HashMap<String, SecretValue>,
+  /// reference point for the monotonic clock
+  pub started: Instant,
+  /// the application's `mount`
+  pub vfs: Option<Arc<Vfs>>,
+  /// files opened with `fs_open`
+  pub files: Slab<VfsFile>,
+  pub wasi: wasi::WasiState,
+  /// guest log lines are kept here when set, for `invoke`
+  pub logs: Option<Vec<String>>,
+  /// request, application and module of the guest's log records
+  pub log_context: Context,
+  pub log_limiter: Option<RateLimiter>,
+  /// backend latency is recorded here by the blocking host
+  pub metrics: Option<Metrics>,
+  /// end of the invocation, the blocking host does not wait past it
+  pub deadline: Option<Instant>,
+  /// body bytes written with `response_write_chunk`, None if the body
+  /// was not streamed
+  pub streamed: Option<u64>,
+}
+
+impl State {
+  pub fn new() -> State {
+    State {
+      memory: None,
+      instance: None,
+      instance_requests: 0,
+      request: Request::default(),
+      prepared_response: PreparedResponse::new(),
+      has_response: true,
+      connections: Slab::with_capacity(100),
+      db: HashMap::new(),
+      kv: None,
+      secrets: HashMap::new(),
+      started: Instant::now(),
+      vfs: None,
+      files: Slab::with_capacity(100),
+      wasi: wasi::WasiState::default(),
+      logs: None,
+      log_context: Context::default(),
+      log_limiter: None,
+      metrics: None,
+      deadline: None,
+      streamed: None,
+    }
+  }
+
+  /// length of the body set in the `Content-Length` header, 0 for
+  /// statuses without a body
+  pub fn declared_length(&self) -> Option<u64> {
+    let response = &self.prepared_response;
+    if !http::status_has_body(response.status_code.unwrap_or(200)) {
+      return Some(0);
+    }
+    http::get_header(&response.headers, "Content-Length").and_then(|length| length.trim().parse().ok())
+  }
+
+  /// once the function returned, a streamed body must be as long as it declared
+  pub fn check_streamed_length(&self) -> Result<(), InvocationError> {
+    match (self.streamed, self.declared_length()) {
+      (Some(sent), Some(length)) if sent < length => Err(InvocationError::IncompleteBody(sent, length)),
+      _ => Ok(()),
+    }
+  }
+
+  /// checks that `size` bytes starting at `ptr` are inside the guest's memory
+  fn check_range(&self, ptr: u32, size: u64) -> Result<(&MemoryRef, usize), Trap> {
+    let memory = match self.memory.as_ref() {
+      Some(memory) => memory,
+      None => return Err(memory_trap(format!("no memory attached to access {} bytes at {:#x}", size, ptr))),
+    };
+
+    let Pages(pages) = memory.current_size();
+    let available = pages as u64 * PAGE_SIZE;
+    match (ptr as u64).checked_add(size) {
+      Some(end) if end <= available && size <= usize::max_value() as u64 => Ok((memory, size as usize)),
+      _ => Err(memory_trap(format!(
+        "out of bounds access of {} bytes at {:#x}, memory size is {} bytes",
+        size, ptr, available
+      ))),
+    }
+  }
+
+  /// returns the size of a guest buffer if it is valid
+  pub fn check_buffer(&self, ptr: u32, size: u64) -> Result<usize, Trap> {
+    self.check_range(ptr, size).map(|(_, size)| size)
+  }
+
+  pub fn read_bytes(&self, ptr: u32, size: u64) -> Result<Vec<u8>, Trap> {
+    let (memory, size) = self.check_range(ptr, size)?;
+    memory
+      .get(ptr, size)
+      .map_err(|e| memory_trap(format!("could not read {} bytes at {:#x}: {:?}", size, ptr, e)))
+  }
+
+  pub fn read_str(&self, ptr: u32, size: u64) -> Result<String, Trap> {
+    String::from_utf8(self.read_bytes(ptr, size)?)
+      .map_err(|e| memory_trap(format!("invalid UTF-8 string at {:#x}: {}", ptr, e)))
+  }
+
+  pub fn write_bytes(&self, ptr: u32, data: &[u8]) -> Result<(), Trap> {
+    let (memory, _) = self.check_range(ptr, data.len() as u64)?;
+    memory
+      .set(ptr, data)
+      .map_err(|e| memory_trap(format!("could not write {} bytes at {:#x}: {:?}", data.len(), ptr, e)))
+  }
+
+  /// reads `count` poll entries and returns the (fd, events) pairs
+  pub fn get_poll_entries(&self, ptr: u32, count: u64) -> Result<Vec<(i32, i32)>, Trap> {
+    let size = count
+      .checked_mul(POLL_ENTRY_SIZE as u64)
+      .ok_or_else(|| memory_trap(format!("too many poll entries: {}", count)))?;
+    let buf = self.read_bytes(ptr, size)?;
+    Ok(
+      buf
+        .chunks(POLL_ENTRY_SIZE)
+        .map(|entry| (read_i32(&entry[0..4]), read_i32(&entry[4..8])))
+        .collect(),
+    )
+  }
+
+  /// writes the `revents` field of each poll entry
+  pub fn set_poll_results(&self, ptr: u32, revents: &[i32]) -> Result<(), Trap> {
+    for (i, r) in revents.iter().enumerate() {
+      let offset = ptr as u64 + (i * POLL_ENTRY_SIZE + 8) as u64;
+      if offset > u32::max_value() as u64 {
+        return Err(memory_trap(format!("poll entry {} is out of bounds", i)));
+      }
+      self.write_bytes(offset as u32, &write_i32(*r))?;
+    }
+    Ok(())
+  }
+}
+
+const PAGE_SIZE: u64 = 65536;
+
+/// a guest passed a buffer the host cannot use
+#[derive(Debug)]
+pub struct MemoryAccessError(pub String);
+
+impl ::std::fmt::Display for MemoryAccessError {
+  fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl HostError for MemoryAccessError {}
+
+fn memory_trap(message: String) -> Trap {
+  Trap::new(TrapKind::Host(Box::new(MemoryAccessError(message))))
+}
+
+/// poll entries are `(fd: i32, events: i32, revents: i32)`, in little endian
+pub const POLL_ENTRY_SIZE: usize = 12;
+pub const POLL_READABLE: i32 = 1;
+pub const POLL_WRITABLE: i32 = 2;
+pub const POLL_HUP: i32 = 4;
+pub const POLL_ERROR: i32 = 8;
+
+/// clocks for `clock_now`, which returns nanoseconds
+const CLOCK_WALL: i32 = 0;
+const CLOCK_MONOTONIC: i32 = 1;
+
+fn read_i32(buf: &[u8]) -> i32 {
+  (buf[0] as i32) | (buf[1] as i32) << 8 | (buf[2] as i32) << 16 | (buf[3] as i32) << 24
+}
+
+fn write_i32(value: i32) -> [u8; 4] {
+  [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+fn write_u64(value: u64) -> [u8; 8] {
+  let mut buf = [0u8; 8];
+  for (i, b) in buf.iter_mut().enumerate() {
+    *b = (value >> (8 * i)) as u8;
+  }
+  buf
+}
+
+/// `fs_stat` writes `(size: u64, modified: u64, kind: u32, padding: u32)`,
+/// with the modification time in seconds since the epoch
+pub const FS_STAT_SIZE: usize = 24;
+pub const FS_KIND_FILE: i32 = 1;
+pub const FS_KIND_DIRECTORY: i32 = 2;
+
+/// runs host functions for an instance, with `I` deciding how to wait
+pub struct HostImpl<I> {
+  pub inner: Rc<RefCell<State>>,
+  io: I,
+}
+
+impl<I: Io + Default> interpreter::Host for HostImpl<I> {
+  type State = State;
+
+  fn build(s: Rc<RefCell<Self::State>>) -> Self {
+    HostImpl {
+      inner: s,
+      io: I::default(),
+    }
+  }
+}
+
+impl<I: Io> Externals for HostImpl<I> {
+  fn invoke_index(&mut self, index: usize, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+    match function(index) {
+      Some(function) => (function.handler)(&self.inner, &mut self.io, args),
+      None => panic!("env doesn't provide function at index {}", index),
+    }
+  }
+}
+
+pub struct StateResolver {
+  pub inner: Rc<RefCell<State>>,
+}
+
+impl ModuleImportResolver for StateResolver {
+  fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, Error> {
+    resolve_func("env", field_name, signature)
+  }
+
+  fn resolve_memory(&self, _field_name: &str, memory_type: &MemoryDescriptor) -> Result<MemoryRef, Error> {
+    let memory = MemoryInstance::alloc(Pages(memory_type.initial() as usize), Some(Pages(100)))?;
+    self.inner.borrow_mut().memory = Some(memory.clone());
+    Ok(memory)
+  }
+}
+
+fn log(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let ptr: u32 = args.nth(0);
+  let sz: u64 = args.nth(1);
+
+  let v = state.borrow().read_str(ptr, sz)?;
+  guest_log(state, Level::Info, v, Vec::new());
+  Ok(None)
+}
+
+/// `log_v2(level, msg_ptr, msg_size, fields_ptr, fields_size)`. Levels go
+/// from 0 (error) to 4 (trace), others are logged as info. Fields are keys
+/// and values, each preceded by its length as a little endian u32
+fn log_v2(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let level: i32 = args.nth(0);
+  let msg_ptr: u32 = args.nth(1);
+  let msg_sz: u64 = args.nth(2);
+  let fields_ptr: u32 = args.nth(3);
+  let fields_sz: u64 = args.nth(4);
+
+  let level = match level {
+    0 => Level::Error,
+    1 => Level::Warn,
+    3 => Level::Debug,
+    4 => Level::Trace,
+    _ => Level::Info,
+  };
+  let message = state.borrow().read_str(msg_ptr, msg_sz)?;
+  let encoded = state.borrow().read_bytes(fields_ptr, fields_sz)?;
+
+  let fields = match kv::decode_key_list(&encoded) {
+    Some(ref list) if list.len() % 2 == 0 => list.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect(),
+    _ => {
+      warn!(state.borrow().log_context; "invalid fields encoding in log_v2, fields dropped");
+      Vec::new()
+    }
+  };
+  guest_log(state, level, message, fields);
+  Ok(None)
+}
+
+/// keeps the line for `invoke`, or writes it unless the application
+/// logged too much in the last second. Lines below the log level do not
+/// count against the rate limit
+pub fn guest_log(state: &Rc<RefCell<State>>, level: Level, message: String, fields: Vec<(String, String)>) {
+  let mut state = state.borrow_mut();
+  if let Some(ref mut logs) = state.logs {
+    let mut line = format!("[{}] {}", level.name(), message);
+    for &(ref key, ref value) in fields.iter() {
+      line.push_str(&format!(" {}={}", key, value));
+    }
+    logs.push(line);
+    return;
+  }
+
+  if !logging::enabled(level) {
+    return;
+  }
+  if state.log_limiter.as_ref().map(|limiter| limiter.allow(&state.log_context)).unwrap_or(true) {
+    logging::guest(level, &state.log_context, &message, &fields);
+  }
+}
+
+fn response_set_status_line(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let status: u32 = args.nth(0);
+  let ptr: u32 = args.nth(1);
+  let sz: u64 = args.nth(2);
+
+  let reason = state.borrow().read_str(ptr, sz)?;
+  if !state.borrow().has_response {
+    return Ok(None);
+  }
+
+  let mut state = state.borrow_mut();
+  state.prepared_response.status_code = Some(status as u16);
+  state.prepared_response.reason = Some(reason);
+  Ok(None)
+}
+
+fn response_set_header(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let ptr1: u32 = args.nth(0);
+  let sz1: u64 = args.nth(1);
+  let ptr2: u32 = args.nth(2);
+  let sz2: u64 = args.nth(3);
+
+  let header_name = state.borrow().read_str(ptr1, sz1)?;
+  let header_value = state.borrow().read_str(ptr2, sz2)?;
+  if !state.borrow().has_response {
+    return Ok(None);
+  }
+
+  state.borrow_mut().prepared_response.headers.push((header_name, header_value));
+  Ok(None)
+}
+
+fn response_set_body(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let ptr: u32 = args.nth(0);
+  let sz: u64 = args.nth(1);
+
+  let body = state.borrow().read_bytes(ptr, sz)?;
+  if !state.borrow().has_response {
+    return Ok(None);
+  }
+  state.borrow_mut().prepared_response.body = Some(body);
+  Ok(None)
+}
+
+fn response_write_chunk(state: &Rc<RefCell<State>>, io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let ptr: u32 = args.nth(0);
+  let sz: u64 = args.nth(1);
+
+  if !state.borrow().has_response {
+    state.borrow().check_buffer(ptr, sz)?;
+    return Ok(Some(RuntimeValue::I64(-1)));
+  }
+
+  // a chunk going past the declared Content-Length is rejected whole
+  let sent = state.borrow().streamed.unwrap_or(0);
+  let too_long = match state.borrow().declared_length() {
+    Some(length) => sent.checked_add(sz).map(|total| total > length).unwrap_or(true),
+    None => false,
+  };
+  if too_long {
+    state.borrow().check_buffer(ptr, sz)?;
+    return Ok(Some(RuntimeValue::I64(-1)));
+  }
+  state.borrow_mut().streamed = Some(sent + sz);
+  io.response_write_chunk(state, ptr, sz)
+}
+
+/// does nothing: calling it is what lets the interpreter check the limits
+fn loop_checkpoint(_state: &Rc<RefCell<State>>, _io: &mut dyn Io, _args: RuntimeArgs) -> HostResult {
+  Ok(None)
+}
+
+fn response_finish(state: &Rc<RefCell<State>>, io: &mut dyn Io, _args: RuntimeArgs) -> HostResult {
+  if !state.borrow().has_response {
+    return Ok(None);
+  }
+  io.response_finish(state)
+}
+
+fn tcp_connect(state: &Rc<RefCell<State>>, io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let ptr: u32 = args.nth(0);
+  let sz: u64 = args.nth(1);
+
+  let address = state.borrow().read_str(ptr, sz)?;
+  io.tcp_connect(state, &address, false)
+}
+
+fn tcp_connect_pooled(state: &Rc<RefCell<State>>, io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let ptr: u32 = args.nth(0);
+  let sz: u64 = args.nth(1);
+
+  let address = state.borrow().read_str(ptr, sz)?;
+  io.tcp_connect(state, &address, true)
+}
+
+fn tcp_read(state: &Rc<RefCell<State>>, io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let fd: i32 = args.nth(0);
+  let ptr: u32 = args.nth(1);
+  let sz: u64 = args.nth(2);
+
+  io.tcp_read(state, fd, ptr, sz)
+}
+
+fn tcp_write(state: &Rc<RefCell<State>>, io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let fd: i32 = args.nth(0);
+  let ptr: u32 = args.nth(1);
+  let sz: u64 = args.nth(2);
+
+  io.tcp_write(state, fd, ptr, sz)
+}
+
+fn tcp_close(state: &Rc<RefCell<State>>, io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let fd: i32 = args.nth(0);
+
+  io.tcp_close(state, fd)
+}
+
+fn poll(state: &Rc<RefCell<State>>, io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let ptr: u32 = args.nth(0);
+  let count: u64 = args.nth(1);
+  let timeout: i64 = args.nth(2);
+
+  io.poll(state, ptr, count, timeout)
+}
+
+fn clock_now(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let clock: i32 = args.nth(0);
+
+  let nanos = match clock {
+    CLOCK_WALL => SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_secs() as i64 * 1_000_000_000 + d.subsec_nanos() as i64)
+      .unwrap_or(-1),
+    CLOCK_MONOTONIC => {
+      let d = state.borrow().started.elapsed();
+      d.as_secs() as i64 * 1_000_000_000 + d.subsec_nanos() as i64
+    }
+    _ => -1,
+  };
+  Ok(Some(RuntimeValue::I64(nanos)))
+}
+
+fn sleep_ms(state: &Rc<RefCell<State>>, io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let ms: u64 = args.nth(0);
+
+  io.sleep_ms(state, ms)
+}
+
+/// copies as much of `value` as fits at `ptr`, and returns its full length
+fn write_value(state: &Rc<RefCell<State>>, ptr: u32, size: u64, value: &[u8]) -> HostResult {
+  let to_write = cmp::min(value.len() as u64, size) as usize;
+  state.borrow().write_bytes(ptr, &value[..to_write])?;
+  Ok(Some(RuntimeValue::I64(value.len() as i64)))
+}
+
+fn db_get(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let key_ptr: u32 = args.nth(0);
+  let key_sz: u64 = args.nth(1);
+  let value_ptr: u32 = args.nth(2);
+  let value_sz: u64 = args.nth(3);
+
+  let key = state.borrow().read_str(key_ptr, key_sz)?;
+  debug!(state.borrow().log_context; "requested value for key {}", key);
+
+  // values written by the application take precedence over the configuration
+  let stored = match state.borrow().kv.as_ref().map(|kv| kv.get(&key)) {
+    Some(Ok(value)) => value,
+    Some(Err(e)) => {
+      warn!(state.borrow().log_context; "kv error for key {}: {:?}", key, e);
+      None
+    }
+    None => None,
+  };
+  let value = stored.or_else(|| state.borrow().db.get(&key).map(|v| v.as_bytes().to_vec()));
+
+  match value {
+    None => Ok(Some(RuntimeValue::I64(-1))),
+    Some(value) => write_value(state, value_ptr, value_sz, &value),
+  }
+}
+
+fn db_set(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let key_ptr: u32 = args.nth(0);
+  let key_sz: u64 = args.nth(1);
+  let value_ptr: u32 = args.nth(2);
+  let value_sz: u64 = args.nth(3);
+
+  let key = state.borrow().read_str(key_ptr, key_sz)?;
+  let value = state.borrow().read_bytes(value_ptr, value_sz)?;
+
+  let res = match state.borrow().kv.as_ref() {
+    None => -1,
+    Some(kv) => match kv.set(&key, &value) {
+      Ok(()) => 0,
+      Err(kv::KvError::KeyQuota) | Err(kv::KvError::ByteQuota) => -2,
+      Err(kv::KvError::Io(e)) => {
+        warn!(state.borrow().log_context; "kv error for key {}: {:?}", key, e);
+        -3
+      }
+    },
+  };
+  Ok(Some(RuntimeValue::I32(res)))
+}
+
+fn db_delete(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let key_ptr: u32 = args.nth(0);
+  let key_sz: u64 = args.nth(1);
+
+  let key = state.borrow().read_str(key_ptr, key_sz)?;
+
+  let res = match state.borrow().kv.as_ref().map(|kv| kv.delete(&key)) {
+    None => -1,
+    Some(Ok(true)) => 1,
+    Some(Ok(false)) => 0,
+    Some(Err(e)) => {
+      warn!(state.borrow().log_context; "kv error for key {}: {:?}", key, e);
+      -3
+    }
+  };
+  Ok(Some(RuntimeValue::I32(res)))
+}
+
+fn db_list_prefix(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let prefix_ptr: u32 = args.nth(0);
+  let prefix_sz: u64 = args.nth(1);
+  let out_ptr: u32 = args.nth(2);
+  let out_sz: u64 = args.nth(3);
+
+  let prefix = state.borrow().read_str(prefix_ptr, prefix_sz)?;
+
+  let keys = match state.borrow().kv.as_ref().map(|kv| kv.list_prefix(&prefix)) {
+    None => return Ok(Some(RuntimeValue::I64(-1))),
+    Some(Ok(keys)) => keys,
+    Some(Err(e)) => {
+      warn!(state.borrow().log_context; "kv error for prefix {}: {:?}", prefix, e);
+      return Ok(Some(RuntimeValue::I64(-3)));
+    }
+  };
+
+  // like db_get, returns the full size so the guest can retry with a larger buffer
+  let encoded = kv::encode_key_list(&keys);
+  if encoded.len() as u64 <= out_sz {
+    state.borrow().write_bytes(out_ptr, &encoded)?;
+  }
+  Ok(Some(RuntimeValue::I64(encoded.len() as i64)))
+}
+
+fn secret_get(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let name_ptr: u32 = args.nth(0);
+  let name_sz: u64 = args.nth(1);
+  let value_ptr: u32 = args.nth(2);
+  let value_sz: u64 = args.nth(3);
+
+  let name = state.borrow().read_str(name_ptr, name_sz)?;
+
+  // secret names and values are never logged
+  let value = state.borrow().secrets.get(&name).cloned();
+  match value {
+    None => Ok(Some(RuntimeValue::I64(-1))),
+    Some(value) => write_value(state, value_ptr, value_sz, value.as_bytes()),
+  }
+}
+
+fn vfs(state: &Rc<RefCell<State>>) -> Result<Arc<Vfs>, VfsError> {
+  state.borrow().vfs.clone().ok_or(VfsError::NoMount)
+}
+
+fn fs_open(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let path_ptr: u32 = args.nth(0);
+  let path_sz: u64 = args.nth(1);
+
+  let path = state.borrow().read_str(path_ptr, path_sz)?;
+  let res = vfs(state)
+    .and_then(|vfs| vfs.open_file(&path))
+    .and_then(|file| {
+      state
+        .borrow_mut()
+        .files
+        .insert(file)
+        .map_err(|_| VfsError::TooManyFiles)
+    });
+
+  match res {
+    Ok(fd) => Ok(Some(RuntimeValue::I32(fd as i32))),
+    Err(e) => Ok(Some(RuntimeValue::I32(e.code()))),
+  }
+}
+
+fn fs_read(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let fd: i32 = args.nth(0);
+  let ptr: u32 = args.nth(1);
+  let sz: u64 = args.nth(2);
+
+  let size = state.borrow().check_buffer(ptr, sz)?;
+  let mut buf = vec![0u8; size];
+  let res = match state.borrow_mut().files.get_mut(fd as usize) {
+    None => Err(VfsError::BadDescriptor),
+    Some(file) => file.read(&mut buf).map_err(|_| VfsError::Io),
+  };
+
+  match res {
+    Ok(read) => {
+      state.borrow().write_bytes(ptr, &buf[..read])?;
+      Ok(Some(RuntimeValue::I64(read as i64)))
+    }
+    Err(e) => Ok(Some(RuntimeValue::I64(e.code() as i64))),
+  }
+}
+
+fn fs_close(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let fd: i32 = args.nth(0);
+
+  if state.borrow_mut().files.remove(fd as usize).is_some() {
+    Ok(Some(RuntimeValue::I32(0)))
+  } else {
+    Ok(Some(RuntimeValue::I32(VfsError::BadDescriptor.code())))
+  }
+}
+
+fn fs_stat(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let path_ptr: u32 = args.nth(0);
+  let path_sz: u64 = args.nth(1);
+  let out_ptr: u32 = args.nth(2);
+
+  let path = state.borrow().read_str(path_ptr, path_sz)?;
+  let stat = match vfs(state).and_then(|vfs| vfs.stat(&path)) {
+    Ok(stat) => stat,
+    Err(e) => return Ok(Some(RuntimeValue::I32(e.code()))),
+  };
+
+  let modified = stat
+    .modified
+    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+    .map(|d| d.as_secs())
+    .unwrap_or(0);
+  let kind = if stat.is_dir { FS_KIND_DIRECTORY } else { FS_KIND_FILE };
+
+  let mut buf = [0u8; FS_STAT_SIZE];
+  buf[0..8].copy_from_slice(&write_u64(stat.size));
+  buf[8..16].copy_from_slice(&write_u64(modified));
+  buf[16..20].copy_from_slice(&write_i32(kind));
+  state.borrow().write_bytes(out_ptr, &buf)?;
+  Ok(Some(RuntimeValue::I32(0)))
+}
+
+fn fs_readdir(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let path_ptr: u32 = args.nth(0);
+  let path_sz: u64 = args.nth(1);
+  let out_ptr: u32 = args.nth(2);
+  let out_sz: u64 = args.nth(3);
+
+  let path = state.borrow().read_str(path_ptr, path_sz)?;
+  let entries = match vfs(state).and_then(|vfs| vfs.read_dir(&path)) {
+    Ok(entries) => entries,
+    Err(e) => return Ok(Some(RuntimeValue::I64(e.code() as i64))),
+  };
+
+  // encoded like db_list_prefix, directory names end with a `/`
+  let names: Vec<String> = entries
+    .into_iter()
+    .map(|e| if e.is_dir { format!("{}/", e.name) } else { e.name })
+    .collect();
+  let encoded = kv::encode_key_list(&names);
+  if encoded.len() as u64 <= out_sz {
+    state.borrow().write_bytes(out_ptr, &encoded)?;
+  }
+  Ok(Some(RuntimeValue::I64(encoded.len() as i64)))
+}
+
+fn request_method(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let out_ptr: u32 = args.nth(0);
+  let out_sz: u64 = args.nth(1);
+
+  let method = state.borrow().request.method.clone();
+  write_value(state, out_ptr, out_sz, method.as_bytes())
+}
+
+fn request_path(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let out_ptr: u32 = args.nth(0);
+  let out_sz: u64 = args.nth(1);
+
+  let path = state.borrow().request.path.clone();
+  write_value(state, out_ptr, out_sz, path.as_bytes())
+}
+
+fn request_header(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let name_ptr: u32 = args.nth(0);
+  let name_sz: u64 = args.nth(1);
+  let out_ptr: u32 = args.nth(2);
+  let out_sz: u64 = args.nth(3);
+
+  let name = state.borrow().read_str(name_ptr, name_sz)?;
+  let value = http::get_header(&state.borrow().request.headers, &name).map(|v| v.to_string());
+  match value {
+    None => Ok(Some(RuntimeValue::I64(-1))),
+    Some(value) => write_value(state, out_ptr, out_sz, value.as_bytes()),
+  }
+}
+
+fn request_body(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let out_ptr: u32 = args.nth(0);
+  let out_sz: u64 = args.nth(1);
+
+  let body = state.borrow().request.body.clone();
+  write_value(state, out_ptr, out_sz, &body)
+}
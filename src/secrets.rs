@@ -0,0 +1,54 @@
+//! secret values, kept apart from `env` so that they never end up in logs
+//! and are only given to the applications that declare them
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Arc;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SecretConfig {
+  pub name: String,
+  /// file containing the secret value. A trailing newline is removed
+  pub file: String,
+}
+
+#[derive(Clone)]
+pub struct SecretValue(Arc<Vec<u8>>);
+
+impl SecretValue {
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.0
+  }
+}
+
+impl fmt::Debug for SecretValue {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "SecretValue(<redacted>)")
+  }
+}
+
+pub fn load(secrets: &[SecretConfig]) -> Result<HashMap<String, SecretValue>, String> {
+  let mut values = HashMap::new();
+
+  for secret in secrets.iter() {
+    let mut data = Vec::new();
+    File::open(&secret.file)
+      .and_then(|mut file| file.read_to_end(&mut data))
+      .map_err(|e| format!("could not read secret '{}' from {}: {}", secret.name, secret.file, e))?;
+
+    if data.last() == Some(&b'\n') {
+      data.pop();
+      if data.last() == Some(&b'\r') {
+        data.pop();
+      }
+    }
+
+    if values.insert(secret.name.clone(), SecretValue(Arc::new(data))).is_some() {
+      return Err(format!("secret '{}' is defined multiple times", secret.name));
+    }
+  }
+
+  Ok(values)
+}
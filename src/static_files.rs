@@ -0,0 +1,247 @@
+//! responses for the `[[static]]` directories, served without a WASM application
+
+use std::io::{Read, Seek, SeekFrom};
+use std::time::UNIX_EPOCH;
+
+use config::StaticDir;
+use http;
+use registry::PreparedResponse;
+use vfs::{Stat, Vfs, VfsError};
+
+/// builds the response for `relative`, a path below the directory's
+/// `url_prefix`, with the request's conditional and Range headers. Only
+/// the requested range is read, and nothing for HEAD requests
+pub fn serve(
+  dir: &StaticDir,
+  files: &Vfs,
+  method: &str,
+  url: &str,
+  relative: &str,
+  headers: &[(String, String)],
+) -> PreparedResponse {
+  let relative = match http::percent_decode(relative) {
+    Some(relative) => relative,
+    None => return text_response(400, "Bad Request\n"),
+  };
+
+  let (path, stat) = match files.stat(&relative) {
+    Ok(ref stat) if stat.is_dir => {
+      // relative links in the index file need the trailing slash
+      let path = url.split('?').next().unwrap_or("");
+      if !path.ends_with('/') {
+        let mut response = PreparedResponse::new();
+        response.status_code = Some(301);
+        response.headers.push(("Location".to_string(), format!("{}/", path)));
+        return response;
+      }
+
+      match find_index(dir, files, &relative) {
+        Some(index) => index,
+        None => return text_response(404, "File not found\n"),
+      }
+    }
+    Ok(stat) => (relative, stat),
+    Err(VfsError::NotFound) | Err(VfsError::InvalidPath) | Err(VfsError::NotDirectory) => {
+      return text_response(404, "File not found\n")
+    }
+    Err(e) => {
+      warn!("could not serve {}: {:?}", relative, e);
+      return text_response(500, "could not read the file\n");
+    }
+  };
+
+  let etag = format!("\"{:x}-{:x}\"", stat.size, modified_secs(&stat));
+  let mut response = PreparedResponse::new();
+  response.headers.push(("Content-Type".to_string(), mime_type(&path).to_string()));
+  response.headers.push(("ETag".to_string(), etag.clone()));
+  if let Some(modified) = stat.modified {
+    response.headers.push(("Last-Modified".to_string(), http::http_date(modified)));
+  }
+  response.headers.push(("Accept-Ranges".to_string(), "bytes".to_string()));
+
+  // answered from the metadata, the file is not opened
+  if not_modified(headers, &etag, &stat) {
+    response.status_code = Some(304);
+    return response;
+  }
+
+  // an If-Range that does not match the current version asks for the whole file
+  let range = http::get_header(headers, "Range").filter(|_| {
+    http::get_header(headers, "If-Range").map(|tag| tag == etag).unwrap_or(true)
+  });
+  let (start, length) = match range.map(|range| parse_range(range, stat.size)) {
+    Some(Ok((start, end))) => {
+      response.status_code = Some(206);
+      response
+        .headers
+        .push(("Content-Range".to_string(), format!("bytes {}-{}/{}", start, end, stat.size)));
+      (start, end - start + 1)
+    }
+    Some(Err(RangeError::Unsatisfiable)) => {
+      response.status_code = Some(416);
+      response
+        .headers
+        .push(("Content-Range".to_string(), format!("bytes */{}", stat.size)));
+      return response;
+    }
+    None | Some(Err(RangeError::Ignored)) => {
+      response.status_code = Some(200);
+      (0, stat.size)
+    }
+  };
+
+  // the length of the body that is left out
+  if method == "HEAD" {
+    response.headers.push(("Content-Length".to_string(), length.to_string()));
+    return response;
+  }
+
+  match read_range(files, &path, start, length) {
+    Ok(body) => response.body = Some(body),
+    Err(e) => {
+      warn!("could not read {}: {}", path, e);
+      return text_response(500, "could not read the file\n");
+    }
+  }
+
+  response
+}
+
+fn read_range(files: &Vfs, path: &str, start: u64, length: u64) -> Result<Vec<u8>, String> {
+  let mut file = files.open_file(path).map_err(|e| format!("{:?}", e))?;
+  file.seek(SeekFrom::Start(start)).map_err(|e| e.to_string())?;
+
+  let mut body = Vec::new();
+  file.take(length).read_to_end(&mut body).map_err(|e| e.to_string())?;
+  Ok(body)
+}
+
+/// a plain text response generated by the server
+pub fn text_response(status: u16, body: &str) -> PreparedResponse {
+  let mut response = PreparedResponse::new();
+  response.status_code = Some(status);
+  response.headers.push(("Content-Type".to_string(), "text/plain".to_string()));
+  response.body = Some(body.as_bytes().to_vec());
+  response
+}
+
+/// the first index file present in the directory
+fn find_index(dir: &StaticDir, files: &Vfs, directory: &str) -> Option<(String, Stat)> {
+  let default = ["index.html".to_string()];
+  let names = dir.index.as_ref().map(|names| &names[..]).unwrap_or(&default);
+
+  names.iter().filter_map(|name| {
+    let path = format!("{}/{}", directory, name);
+    match files.stat(&path) {
+      Ok(ref stat) if stat.is_dir => None,
+      Ok(stat) => Some((path, stat)),
+      Err(_) => None,
+    }
+  }).next()
+}
+
+fn modified_secs(stat: &Stat) -> u64 {
+  stat
+    .modified
+    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+    .map(|d| d.as_secs())
+    .unwrap_or(0)
+}
+
+/// If-None-Match takes precedence over If-Modified-Since
+fn not_modified(headers: &[(String, String)], etag: &str, stat: &Stat) -> bool {
+  if let Some(tags) = http::get_header(headers, "If-None-Match") {
+    // weak comparison, as required for GET and HEAD
+    return tags
+      .split(',')
+      .map(|tag| tag.trim())
+      .any(|tag| tag == "*" || tag.trim_left_matches("W/") == etag);
+  }
+
+  // dates have a one second precision
+  let since = http::get_header(headers, "If-Modified-Since").and_then(http::parse_http_date);
+  match (since, stat.modified) {
+    (Some(since), Some(_)) => {
+      since.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) >= modified_secs(stat)
+    }
+    _ => false,
+  }
+}
+
+#[derive(Debug, PartialEq)]
+enum RangeError {
+  /// the header is invalid or asks for multiple ranges, the whole file is sent
+  Ignored,
+  Unsatisfiable,
+}
+
+/// parses a single `bytes` range and returns the first and last byte offsets
+fn parse_range(header: &str, size: u64) -> Result<(u64, u64), RangeError> {
+  let spec = header.trim();
+  if !spec.starts_with("bytes=") || spec.contains(',') {
+    return Err(RangeError::Ignored);
+  }
+
+  let mut parts = spec["bytes=".len()..].splitn(2, '-');
+  let first = parts.next().unwrap_or("").trim();
+  let last = parts.next().ok_or(RangeError::Ignored)?.trim();
+
+  let (start, end) = if first.is_empty() {
+    // the last `n` bytes
+    let suffix: u64 = last.parse().map_err(|_| RangeError::Ignored)?;
+    if suffix == 0 {
+      return Err(RangeError::Unsatisfiable);
+    }
+    (size.saturating_sub(suffix), size.saturating_sub(1))
+  } else {
+    let start: u64 = first.parse().map_err(|_| RangeError::Ignored)?;
+    let end = if last.is_empty() {
+      size.saturating_sub(1)
+    } else {
+      let end: u64 = last.parse().map_err(|_| RangeError::Ignored)?;
+      if end < start {
+        return Err(RangeError::Ignored);
+      }
+      ::std::cmp::min(end, size.saturating_sub(1))
+    };
+    (start, end)
+  };
+
+  if start >= size {
+    return Err(RangeError::Unsatisfiable);
+  }
+  Ok((start, end))
+}
+
+/// Content-Type from the file extension
+fn mime_type(path: &str) -> &'static str {
+  let name = path.rsplit('/').next().unwrap_or("");
+  let extension = if name.contains('.') { name.rsplit('.').next() } else { None };
+
+  match extension.map(|e| e.to_lowercase()).as_ref().map(|e| e.as_str()) {
+    Some("html") | Some("htm") => "text/html; charset=utf-8",
+    Some("css") => "text/css; charset=utf-8",
+    Some("js") | Some("mjs") => "application/javascript; charset=utf-8",
+    Some("json") | Some("map") => "application/json",
+    Some("txt") | Some("toml") => "text/plain; charset=utf-8",
+    Some("csv") => "text/csv; charset=utf-8",
+    Some("xml") => "application/xml",
+    Some("svg") => "image/svg+xml",
+    Some("png") => "image/png",
+    Some("jpg") | Some("jpeg") => "image/jpeg",
+    Some("gif") => "image/gif",
+    Some("webp") => "image/webp",
+    Some("ico") => "image/x-icon",
+    Some("wasm") => "application/wasm",
+    Some("pdf") => "application/pdf",
+    Some("woff") => "font/woff",
+    Some("woff2") => "font/woff2",
+    Some("ttf") => "font/ttf",
+    Some("mp3") => "audio/mpeg",
+    Some("mp4") => "video/mp4",
+    Some("webm") => "video/webm",
+    Some("zip") => "application/zip",
+    Some("gz") => "application/gzip",
+    _ => "application/octet-stream",
+  }
+}
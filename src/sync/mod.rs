@@ -1,58 +1,255 @@
 use rouille;
-use wasmi::{Error, ExternVal, ImportsBuilder, ModuleInstance};
+use std::io::Read;
+use wasmi::{ExternVal, ModuleRef, RuntimeValue};
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::time::{Instant, SystemTime};
 
-use config::{ApplicationState, Config};
-use interpreter::WasmInstance;
+use access_log::Entry;
+use config::{open_access_log, open_kv, ApplicationState, Config, Route};
+use errors::InvocationError;
+use http;
+use interpreter::{Limits, WasmInstance};
+use logging::Context;
+use metrics;
+use registry::{PreparedResponse, Request, State};
+use static_files::{self, text_response};
+use wasi;
 
 mod host;
 
-pub fn server(config: Config) {
-  let state = ApplicationState::new(&config);
-
-  rouille::start_server(&config.listen_address, move |request| {
-    if let Some((func_name, module, ref opt_env)) = state.route(request.method(), &request.url()) {
-      let mut env = host::State::new();
-      if let Some(h) = opt_env {
-        env.db.extend(
-          h.iter()
-            .map(|(ref k, ref v)| (k.to_string(), v.to_string())),
-        );
-      }
-      let main = ModuleInstance::new(&module, &ImportsBuilder::new().with_resolver("env", &env))
-        .expect("Failed to instantiate module")
-        .assert_no_start();
-
-      let mut response = env.prepared_response.clone();
-      if let Some(ExternVal::Func(func_ref)) = main.export_by_name(func_name) {
-        let mut instance: WasmInstance<host::State, host::SyncHost> = WasmInstance::new(Rc::new(RefCell::new(env)), &func_ref, &[]);
-        let res = instance.resume().map_err(|t| Error::Trap(t));
-        println!("invocation result: {:?}", res);
-        response = instance.state.borrow().prepared_response.clone();
-      } else {
-        panic!("handle error here");
+pub use self::host::SyncHost;
+
+/// returns if the address cannot be used
+pub fn server(config: Config) -> Result<(), String> {
+  let mut state = ApplicationState::new(&config, open_kv(&config));
+  state.access_log = open_access_log(&config)?;
+  if !state.schedules.is_empty() {
+    warn!("schedules are only run by the async server");
+  }
+  // rouille runs requests on its own thread pool
+  if config.workers.is_some() {
+    warn!("workers is only used by the async server");
+  }
+
+  if let Some(ref address) = config.metrics_address {
+    metrics::serve(address, state.metrics.clone())?;
+  }
+
+  let server = rouille::Server::new(&config.listen_address, move |request| {
+    let started = Instant::now();
+    let received = SystemTime::now();
+    let mut context = Context::new_request();
+    let (route, response) = handle(&state, request, &mut context);
+    let status = response.status_code.unwrap_or(500);
+    state.metrics.request(&route, status, started.elapsed());
+
+    if let Some(ref access_log) = state.access_log {
+      let headers: Vec<(String, String)> = request
+        .headers()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+      // tiny_http leaves out the body of HEAD responses
+      let bytes = match response.body {
+        Some(ref body) if http::status_has_body(status) && request.method() != "HEAD" => body.len() as u64,
+        _ => 0,
       };
+      access_log.write(&Entry {
+        client: Some(*request.remote_addr()),
+        method: request.method(),
+        path: request.raw_url(),
+        status,
+        bytes,
+        received,
+        duration: started.elapsed(),
+        user_agent: http::get_header(&headers, "User-Agent"),
+        referer: http::get_header(&headers, "Referer"),
+        app: context.app.as_ref().map(|app| app.as_str()),
+        request_id: context.request_id,
+      });
+    }
+    to_rouille(response)
+  }).map_err(|e| format!("could not listen on {}: {}", config.listen_address, e))?;
 
-      if let host::PreparedResponse {
-        status_code: Some(status),
-        headers,
-        body: Some(body),
-      } = response
-      {
-        rouille::Response {
-          status_code: status,
-          headers: Vec::new(),
-          data: rouille::ResponseBody::from_data(body),
-          upgrade: None,
+  server.run();
+  Ok(())
+}
+
+/// the response, and the route that handled the request for the metrics.
+/// `context` gets the application once routed
+fn handle(state: &ApplicationState, request: &rouille::Request, context: &mut Context) -> (String, PreparedResponse) {
+  let url = request.url();
+  match state.route(request.method(), &url) {
+    Some(Route::Application(app_index, app, _)) => {
+      *context = state.log_context(app_index, context.request_id);
+      let context = &*context;
+      let headers: Vec<(String, String)> = request
+        .headers()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+      match http::content_length(&headers) {
+        Err(e) => {
+          debug!(*context; "{}", e);
+          return (app.name(), text_response(400, "Bad Request\n"));
+        }
+        Ok(length) if length > http::MAX_REQUEST_BODY => {
+          return (app.name(), text_response(413, "Payload Too Large\n"));
+        }
+        Ok(_) => {}
+      }
+      let mut body = Vec::new();
+      if let Some(data) = request.data() {
+        // a body without a Content-Length is limited too
+        if let Err(e) = data.take(http::MAX_REQUEST_BODY as u64 + 1).read_to_end(&mut body) {
+          info!(*context; "could not read the request body: {:?}", e);
+          return (app.name(), text_response(400, "Bad Request\n"));
+        }
+        if body.len() > http::MAX_REQUEST_BODY {
+          return (app.name(), text_response(413, "Payload Too Large\n"));
         }
-      } else {
-        rouille::Response::text("wasm failed").with_status_code(500)
       }
-    } else {
-      rouille::Response::empty_404()
+      let guest_request = Request {
+        method: request.method().to_string(),
+        path: request.raw_url().to_string(),
+        headers,
+        body,
+      };
+
+      let response = match invoke(state, app_index, &app.function, &[], &guest_request, context) {
+        Ok(response) => response,
+        Err(error) => {
+          error!(*context; "invocation error: {:?}", error);
+          app
+            .error_handler
+            .as_ref()
+            .and_then(|handler| {
+              invoke(state, app_index, handler, &error.handler_args(), &guest_request, context)
+                .map_err(|e| error!(*context; "error handler failed: {:?}", e))
+                .ok()
+            })
+            .unwrap_or_else(|| state.error_response(app_index, &error))
+        }
+      };
+      (app.name(), response)
+    }
+    Some(Route::Static(dir, files, relative)) => {
+      let headers: Vec<(String, String)> = request
+        .headers()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+      // tiny_http computes Content-Length from the body, and leaves it out
+      // of HEAD responses: the file is read as for GET
+      let response = static_files::serve(dir, files, "GET", &url, relative, &headers);
+      (format!("static {}", dir.url_prefix), response)
+    }
+    None => {
+      info!(*context; "route not found: {} {}", request.method(), url);
+      ("none".to_string(), text_response(404, "Route not found\n"))
+    }
+  }
+}
+
+/// tiny_http sets the body length and framing itself, and leaves out the
+/// body of HEAD responses. The reason phrase cannot be passed through, the
+/// standard one is used
+fn to_rouille(response: PreparedResponse) -> rouille::Response {
+  let status = response.status_code.unwrap_or(500);
+  let headers = response
+    .headers
+    .into_iter()
+    .filter(|&(ref name, _)| {
+      !name.eq_ignore_ascii_case("Content-Length") && !name.eq_ignore_ascii_case("Transfer-Encoding")
+    })
+    .map(|(name, value)| (name.into(), value.into()))
+    .collect();
+  let body = if http::status_has_body(status) {
+    response.body.unwrap_or(Vec::new())
+  } else {
+    Vec::new()
+  };
+
+  rouille::Response {
+    status_code: status,
+    headers: headers,
+    data: rouille::ResponseBody::from_data(body),
+    upgrade: None,
+  }
+}
+
+/// runs an exported function until it returns, and gives back the response it set
+fn invoke(
+  state: &ApplicationState,
+  app_index: usize,
+  function: &str,
+  args: &[RuntimeValue],
+  request: &Request,
+  context: &Context,
+) -> Result<PreparedResponse, InvocationError> {
+  let mut env = state.host_state(app_index);
+  env.request = request.clone();
+  env.log_context = context.clone();
+  let env = Rc::new(RefCell::new(env));
+  let mut limits = state.limits(app_index);
+  let started = Instant::now();
+  let main = state.instance(app_index, &env, &mut limits)?;
+  let res = run(env.clone(), &main, function, args, &mut limits);
+
+  // the blocking host functions count as execution time here
+  let app = &state.applications[app_index];
+  let fuel = app.fuel.and_then(|fuel| limits.fuel.map(|left| fuel - left));
+  let pages = env.borrow().memory.as_ref().map(|memory| memory.current_size().0);
+  state.metrics.invocation(&app.name(), started.elapsed(), fuel, pages);
+
+  // the function returned, even without a complete response
+  match res {
+    Ok(_) | Err(InvocationError::NoResponse) | Err(InvocationError::IncompleteBody(_, _)) => {
+      state.release_instance(app_index, &env)
+    }
+    _ => {}
+  }
+  res
+}
+
+/// runs the function of an instance created for `env`. `limits` holds
+/// what was left of them once it returns
+pub fn run(
+  env: Rc<RefCell<State>>,
+  main: &ModuleRef,
+  function: &str,
+  args: &[RuntimeValue],
+  limits: &mut Limits,
+) -> Result<PreparedResponse, InvocationError> {
+  let func_ref = match main.export_by_name(function) {
+    Some(ExternVal::Func(func_ref)) => func_ref,
+    _ => return Err(InvocationError::MissingExport(function.to_string())),
+  };
+  if func_ref.signature().params().len() != args.len() {
+    return Err(InvocationError::Instantiation(format!(
+      "{} takes {} arguments instead of {}",
+      function,
+      func_ref.signature().params().len(),
+      args.len()
+    )));
+  }
+
+  env.borrow_mut().deadline = limits.deadline;
+  let mut instance: WasmInstance<State, SyncHost> = WasmInstance::new(env, &func_ref, args);
+  instance.limits = limits.clone();
+  let res = instance.resume();
+  *limits = instance.limits.clone();
+  debug!(instance.state.borrow().log_context; "invocation result: {:?}", res);
+  if let Err(t) = res {
+    if !wasi::is_clean_exit(&t) {
+      return Err(InvocationError::from_trap(&t, instance.stack_trace()));
     }
-  });
+  }
+
+  let response = instance.state.borrow().prepared_response.clone();
+  if response.status_code.is_none() {
+    return Err(InvocationError::NoResponse);
+  }
+  instance.state.borrow().check_streamed_length()?;
+  Ok(response)
 }
 
 /*
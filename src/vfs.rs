@@ -0,0 +1,563 @@
+//! read-only file trees mounted by applications, from a directory
+//! or a tar archive loaded in memory
+//!
+//! guest paths are relative to the mount, `/` separated, and can never
+//! leave it, even through `..` or symbolic links
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VfsError {
+  /// the application has no `mount`
+  NoMount,
+  NotFound,
+  /// the path leaves the mount or is not valid
+  InvalidPath,
+  IsDirectory,
+  NotDirectory,
+  BadDescriptor,
+  TooManyFiles,
+  Io,
+}
+
+impl VfsError {
+  /// returned to the guest by the `fs_*` functions
+  pub fn code(&self) -> i32 {
+    match *self {
+      VfsError::NoMount => -1,
+      VfsError::NotFound => -2,
+      VfsError::InvalidPath => -3,
+      VfsError::IsDirectory => -4,
+      VfsError::NotDirectory => -5,
+      VfsError::BadDescriptor => -6,
+      VfsError::TooManyFiles => -7,
+      VfsError::Io => -8,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat {
+  pub is_dir: bool,
+  pub size: u64,
+  pub modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+  pub name: String,
+  pub is_dir: bool,
+}
+
+pub enum Vfs {
+  Directory(PathBuf),
+  Archive(Archive),
+}
+
+pub struct Archive {
+  data: Arc<Vec<u8>>,
+  /// normalized path -> entry. The root is ""
+  entries: HashMap<String, ArchiveEntry>,
+}
+
+struct ArchiveEntry {
+  /// None for directories
+  range: Option<(usize, usize)>,
+  modified: Option<SystemTime>,
+  children: BTreeSet<String>,
+}
+
+/// an open file, read from the disk or from the archive's data
+pub enum VfsFile {
+  Disk(File),
+  Memory {
+    data: Arc<Vec<u8>>,
+    start: usize,
+    end: usize,
+    position: usize,
+  },
+}
+
+/// turns a guest path into `a/b/c`. `..` cannot go above the root
+pub fn normalize(path: &str) -> Result<String, VfsError> {
+  let mut components: Vec<&str> = Vec::new();
+  for component in path.split('/') {
+    match component {
+      "" | "." => {}
+      ".." => if components.pop().is_none() {
+        return Err(VfsError::InvalidPath);
+      },
+      c if c.contains('\0') || c.contains('\\') => return Err(VfsError::InvalidPath),
+      c => components.push(c),
+    }
+  }
+  Ok(components.join("/"))
+}
+
+impl Vfs {
+  /// mounts a directory, or a tar archive that is loaded in memory
+  pub fn open(path: &str) -> Result<Vfs, String> {
+    let metadata = fs::metadata(path).map_err(|e| format!("could not open mount {}: {}", path, e))?;
+    if metadata.is_dir() {
+      let root = Path::new(path)
+        .canonicalize()
+        .map_err(|e| format!("could not open mount {}: {}", path, e))?;
+      return Ok(Vfs::Directory(root));
+    }
+
+    let mut data = Vec::new();
+    File::open(path)
+      .and_then(|mut file| file.read_to_end(&mut data))
+      .map_err(|e| format!("could not read archive {}: {}", path, e))?;
+    Archive::parse(data)
+      .map(Vfs::Archive)
+      .map_err(|e| format!("invalid archive {}: {}", path, e))
+  }
+
+  /// path on the disk, after checking that symbolic links do not leave the mount
+  fn disk_path(root: &Path, path: &str) -> Result<PathBuf, VfsError> {
+    let path = normalize(path)?;
+    let full = root.join(&path).canonicalize().map_err(|e| match e.kind() {
+      io::ErrorKind::NotFound => VfsError::NotFound,
+      _ => VfsError::Io,
+    })?;
+    if !full.starts_with(root) {
+      return Err(VfsError::InvalidPath);
+    }
+    Ok(full)
+  }
+
+  pub fn stat(&self, path: &str) -> Result<Stat, VfsError> {
+    match *self {
+      Vfs::Directory(ref root) => {
+        let metadata = fs::metadata(Vfs::disk_path(root, path)?).map_err(|_| VfsError::Io)?;
+        Ok(Stat {
+          is_dir: metadata.is_dir(),
+          size: if metadata.is_dir() { 0 } else { metadata.len() },
+          modified: metadata.modified().ok(),
+        })
+      }
+      Vfs::Archive(ref archive) => {
+        let entry = archive.entries.get(&normalize(path)?).ok_or(VfsError::NotFound)?;
+        Ok(Stat {
+          is_dir: entry.range.is_none(),
+          size: entry.range.map(|(start, end)| (end - start) as u64).unwrap_or(0),
+          modified: entry.modified,
+        })
+      }
+    }
+  }
+
+  pub fn open_file(&self, path: &str) -> Result<VfsFile, VfsError> {
+    match *self {
+      Vfs::Directory(ref root) => {
+        let full = Vfs::disk_path(root, path)?;
+        if full.is_dir() {
+          return Err(VfsError::IsDirectory);
+        }
+        File::open(full).map(VfsFile::Disk).map_err(|_| VfsError::Io)
+      }
+      Vfs::Archive(ref archive) => {
+        let entry = archive.entries.get(&normalize(path)?).ok_or(VfsError::NotFound)?;
+        match entry.range {
+          None => Err(VfsError::IsDirectory),
+          Some((start, end)) => Ok(VfsFile::Memory {
+            data: archive.data.clone(),
+            start,
+            end,
+            position: start,
+          }),
+        }
+      }
+    }
+  }
+
+  /// entries of a directory, sorted by name
+  pub fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, VfsError> {
+    match *self {
+      Vfs::Directory(ref root) => {
+        let full = Vfs::disk_path(root, path)?;
+        if !full.is_dir() {
+          return Err(VfsError::NotDirectory);
+        }
+
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(full).map_err(|_| VfsError::Io)? {
+          let entry = entry.map_err(|_| VfsError::Io)?;
+          entries.push(DirEntry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            is_dir: entry.path().is_dir(),
+          });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+      }
+      Vfs::Archive(ref archive) => {
+        let path = normalize(path)?;
+        let entry = archive.entries.get(&path).ok_or(VfsError::NotFound)?;
+        if entry.range.is_some() {
+          return Err(VfsError::NotDirectory);
+        }
+
+        Ok(
+          entry
+            .children
+            .iter()
+            .map(|name| {
+              let child = if path.is_empty() { name.clone() } else { format!("{}/{}", path, name) };
+              DirEntry {
+                name: name.clone(),
+                is_dir: archive.entries.get(&child).map(|e| e.range.is_none()).unwrap_or(false),
+              }
+            })
+            .collect(),
+        )
+      }
+    }
+  }
+}
+
+impl Archive {
+  /// reads a ustar archive, with GNU or pax long names. Links and special
+  /// files are ignored
+  fn parse(data: Vec<u8>) -> Result<Archive, String> {
+    let mut entries = HashMap::new();
+    entries.insert(String::new(), ArchiveEntry::directory(None));
+
+    let mut offset = 0;
+    let mut long_name: Option<String> = None;
+    while offset + 512 <= data.len() {
+      let header = &data[offset..offset + 512];
+      if header.iter().all(|b| *b == 0) {
+        break;
+      }
+
+      let size = parse_octal(&header[124..136]).ok_or(format!("invalid size at offset {}", offset))? as usize;
+      let modified = parse_octal(&header[136..148]).map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+      let typeflag = header[156];
+      let start = offset + 512;
+      let end = match start.checked_add(size) {
+        Some(end) if end <= data.len() => end,
+        _ => return Err(format!("truncated entry at offset {}", offset)),
+      };
+      let header_offset = offset;
+      // entries are padded to a whole block
+      offset = end + (512 - size % 512) % 512;
+
+      let kind = match typeflag {
+        // GNU long name, for the next entry
+        b'L' => {
+          long_name = Some(cstr(&data[start..end]));
+          continue;
+        }
+        // pax extended header, its path applies to the next entry
+        b'x' => {
+          if let Some(path) = pax_path(&data[start..end]).map_err(|e| format!("{} at offset {}", e, header_offset))? {
+            long_name = Some(path);
+          }
+          continue;
+        }
+        b'0' | 0 | b'7' => Some((start, end)),
+        b'5' => None,
+        _ => {
+          long_name = None;
+          continue;
+        }
+      };
+
+      let name = match long_name.take() {
+        Some(name) => name,
+        None => {
+          let name = cstr(&header[0..100]);
+          let prefix = if &header[257..262] == b"ustar" { cstr(&header[345..500]) } else { String::new() };
+          if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) }
+        }
+      };
+
+      let path = normalize(&name).map_err(|_| format!("invalid path {}", name))?;
+      if path.is_empty() {
+        continue;
+      }
+      Archive::add_parents(&mut entries, &path);
+      match kind {
+        Some(range) => entries.insert(path, ArchiveEntry { range: Some(range), modified, children: BTreeSet::new() }),
+        None => entries.insert(path, ArchiveEntry::directory(modified)),
+      };
+    }
+
+    Ok(Archive { data: Arc::new(data), entries })
+  }
+
+  /// creates the directories leading to `path` and links it to its parent
+  fn add_parents(entries: &mut HashMap<String, ArchiveEntry>, path: &str) {
+    let (parent, name) = match path.rfind('/') {
+      Some(i) => (&path[..i], &path[i + 1..]),
+      None => ("", path),
+    };
+
+    if !entries.contains_key(parent) {
+      Archive::add_parents(entries, parent);
+      entries.insert(parent.to_string(), ArchiveEntry::directory(None));
+    }
+    entries.get_mut(parent).map(|e| e.children.insert(name.to_string()));
+  }
+}
+
+impl ArchiveEntry {
+  fn directory(modified: Option<SystemTime>) -> ArchiveEntry {
+    ArchiveEntry {
+      range: None,
+      modified,
+      children: BTreeSet::new(),
+    }
+  }
+}
+
+/// the `path` in pax records, formatted as `<length> <key>=<value>\n`
+fn pax_path(records: &[u8]) -> Result<Option<String>, String> {
+  let mut path = None;
+  let mut rest = records;
+  while !rest.is_empty() {
+    let space = rest.iter().position(|b| *b == b' ').ok_or("invalid pax record")?;
+    let length: usize = str::from_utf8(&rest[..space])
+      .ok()
+      .and_then(|length| length.parse().ok())
+      .ok_or("invalid pax record length")?;
+    if length <= space + 1 || length > rest.len() || rest[length - 1] != b'\n' {
+      return Err("invalid pax record length".to_string());
+    }
+
+    let record = &rest[space + 1..length - 1];
+    let equal = record.iter().position(|b| *b == b'=').ok_or("invalid pax record")?;
+    if &record[..equal] == b"path" {
+      let value = str::from_utf8(&record[equal + 1..]).map_err(|_| "invalid pax path")?;
+      path = Some(value.to_string());
+    }
+    rest = &rest[length..];
+  }
+  Ok(path)
+}
+
+fn cstr(buf: &[u8]) -> String {
+  let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+  String::from_utf8_lossy(&buf[..end]).into_owned()
+}
+
+fn parse_octal(buf: &[u8]) -> Option<u64> {
+  let s = cstr(buf);
+  let s = s.trim();
+  if s.is_empty() {
+    return Some(0);
+  }
+  u64::from_str_radix(s, 8).ok()
+}
+
+impl Read for VfsFile {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match *self {
+      VfsFile::Disk(ref mut file) => file.read(buf),
+      VfsFile::Memory { ref data, end, ref mut position, .. } => {
+        let sz = ::std::cmp::min(buf.len(), end - *position);
+        buf[..sz].copy_from_slice(&data[*position..*position + sz]);
+        *position += sz;
+        Ok(sz)
+      }
+    }
+  }
+}
+
+impl Seek for VfsFile {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    match *self {
+      VfsFile::Disk(ref mut file) => file.seek(pos),
+      VfsFile::Memory { start, end, ref mut position, .. } => {
+        let new = match pos {
+          SeekFrom::Start(offset) => offset as i64,
+          SeekFrom::Current(offset) => (*position - start) as i64 + offset,
+          SeekFrom::End(offset) => (end - start) as i64 + offset,
+        };
+        if new < 0 {
+          return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the file"));
+        }
+        // like files, seeking after the end is allowed and reads nothing
+        *position = start + ::std::cmp::min(new as usize, end - start);
+        Ok(new as u64)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::env;
+  use std::io::Write;
+  use std::os::unix;
+  use std::process;
+
+  /// an empty directory for one test
+  fn test_directory(name: &str) -> PathBuf {
+    let mut path = env::temp_dir();
+    path.push(format!("serverless-wasm-vfs-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    path
+  }
+
+  fn tar_entry(archive: &mut Vec<u8>, name: &str, typeflag: u8, content: &[u8]) {
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[124..135].copy_from_slice(format!("{:011o}", content.len()).as_bytes());
+    header[136..147].copy_from_slice(b"00000000000");
+    header[156] = typeflag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(content);
+    let padded = archive.len() + (512 - content.len() % 512) % 512;
+    archive.resize(padded, 0);
+  }
+
+  fn tar_end(archive: &mut Vec<u8>) {
+    let len = archive.len();
+    archive.resize(len + 1024, 0);
+  }
+
+  fn read_all(vfs: &Vfs, path: &str) -> Result<String, VfsError> {
+    let mut content = String::new();
+    vfs.open_file(path)?.read_to_string(&mut content).unwrap();
+    Ok(content)
+  }
+
+  #[test]
+  fn normalize_stays_in_the_mount() {
+    assert_eq!(normalize("a/./b//c/"), Ok("a/b/c".to_string()));
+    assert_eq!(normalize("a/../b"), Ok("b".to_string()));
+    assert_eq!(normalize("/etc/passwd"), Ok("etc/passwd".to_string()));
+    assert_eq!(normalize(""), Ok("".to_string()));
+    assert_eq!(normalize(".."), Err(VfsError::InvalidPath));
+    assert_eq!(normalize("a/../.."), Err(VfsError::InvalidPath));
+    assert_eq!(normalize("/../etc/passwd"), Err(VfsError::InvalidPath));
+    assert_eq!(normalize("a\\..\\b"), Err(VfsError::InvalidPath));
+    assert_eq!(normalize("a\0b"), Err(VfsError::InvalidPath));
+  }
+
+  #[test]
+  fn directory_mount() {
+    let directory = test_directory("directory");
+    let mount = directory.join("mount");
+    fs::create_dir_all(mount.join("sub")).unwrap();
+    File::create(mount.join("sub/file.txt")).unwrap().write_all(b"inside").unwrap();
+    File::create(directory.join("secret.txt")).unwrap().write_all(b"outside").unwrap();
+
+    let vfs = Vfs::open(mount.to_str().unwrap()).unwrap();
+    assert_eq!(read_all(&vfs, "sub/file.txt"), Ok("inside".to_string()));
+    assert_eq!(read_all(&vfs, "/sub/file.txt"), Ok("inside".to_string()));
+    assert_eq!(read_all(&vfs, "../secret.txt").err(), Some(VfsError::InvalidPath));
+    assert_eq!(read_all(&vfs, "sub/../../secret.txt").err(), Some(VfsError::InvalidPath));
+    assert_eq!(read_all(&vfs, "missing").err(), Some(VfsError::NotFound));
+    assert_eq!(read_all(&vfs, "sub").err(), Some(VfsError::IsDirectory));
+    assert_eq!(
+      vfs.read_dir("/").unwrap(),
+      vec![DirEntry {
+        name: "sub".to_string(),
+        is_dir: true,
+      }]
+    );
+  }
+
+  #[test]
+  fn symlinks_cannot_leave_a_directory_mount() {
+    let directory = test_directory("symlinks");
+    let mount = directory.join("mount");
+    fs::create_dir_all(&mount).unwrap();
+    File::create(mount.join("file.txt")).unwrap().write_all(b"inside").unwrap();
+    File::create(directory.join("secret.txt")).unwrap().write_all(b"outside").unwrap();
+    unix::fs::symlink(directory.join("secret.txt"), mount.join("escape")).unwrap();
+    unix::fs::symlink("..", mount.join("parent")).unwrap();
+    unix::fs::symlink("file.txt", mount.join("alias")).unwrap();
+
+    let vfs = Vfs::open(mount.to_str().unwrap()).unwrap();
+    assert_eq!(read_all(&vfs, "escape").err(), Some(VfsError::InvalidPath));
+    assert_eq!(vfs.stat("escape").err(), Some(VfsError::InvalidPath));
+    assert_eq!(read_all(&vfs, "parent/secret.txt").err(), Some(VfsError::InvalidPath));
+    assert_eq!(vfs.read_dir("parent").err(), Some(VfsError::InvalidPath));
+    // links inside the mount are followed
+    assert_eq!(read_all(&vfs, "alias"), Ok("inside".to_string()));
+  }
+
+  #[test]
+  fn archive_mount() {
+    let mut archive = Vec::new();
+    tar_entry(&mut archive, "dir/", b'5', b"");
+    tar_entry(&mut archive, "dir/file.txt", b'0', b"hello");
+    tar_entry(&mut archive, "other/nested.txt", b'0', b"world");
+    tar_end(&mut archive);
+
+    let vfs = Vfs::Archive(Archive::parse(archive).unwrap());
+    assert_eq!(read_all(&vfs, "dir/file.txt"), Ok("hello".to_string()));
+    assert_eq!(read_all(&vfs, "/other/nested.txt"), Ok("world".to_string()));
+    assert_eq!(read_all(&vfs, "dir/../../dir/file.txt").err(), Some(VfsError::InvalidPath));
+    assert_eq!(read_all(&vfs, "dir").err(), Some(VfsError::IsDirectory));
+    assert_eq!(vfs.stat("dir/file.txt").unwrap().size, 5);
+    let names: Vec<String> = vfs.read_dir("").unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, vec!["dir".to_string(), "other".to_string()]);
+  }
+
+  #[test]
+  fn archive_entries_cannot_leave_the_mount() {
+    for name in ["../evil.txt", "dir/../../evil.txt", "dir\\evil.txt"].iter() {
+      let mut archive = Vec::new();
+      tar_entry(&mut archive, name, b'0', b"evil");
+      tar_end(&mut archive);
+      assert!(Archive::parse(archive).is_err(), "{} was accepted", name);
+    }
+
+    // an absolute name is relative to the mount
+    let mut archive = Vec::new();
+    tar_entry(&mut archive, "/etc/passwd", b'0', b"root");
+    tar_end(&mut archive);
+    let vfs = Vfs::Archive(Archive::parse(archive).unwrap());
+    assert_eq!(read_all(&vfs, "etc/passwd"), Ok("root".to_string()));
+  }
+
+  #[test]
+  fn archive_long_names() {
+    let long: String = "directory/".repeat(20) + "file.txt";
+
+    let mut archive = Vec::new();
+    tar_entry(&mut archive, "././@LongLink", b'L', long.as_bytes());
+    tar_entry(&mut archive, &long[..100], b'0', b"gnu");
+    let record = format!("path={}\n", long.replace("file", "pax"));
+    // the length includes its own digits
+    let record = format!("{} {}", record.len() + 4, record);
+    tar_entry(&mut archive, "PaxHeaders/pax.txt", b'x', record.as_bytes());
+    tar_entry(&mut archive, "pax.txt", b'0', b"pax");
+    tar_end(&mut archive);
+
+    let vfs = Vfs::Archive(Archive::parse(archive).unwrap());
+    assert_eq!(read_all(&vfs, &long), Ok("gnu".to_string()));
+    assert_eq!(read_all(&vfs, &long.replace("file", "pax")), Ok("pax".to_string()));
+    assert_eq!(read_all(&vfs, "pax.txt").err(), Some(VfsError::NotFound));
+  }
+
+  #[test]
+  fn archive_with_a_pax_traversal_fails() {
+    let mut archive = Vec::new();
+    tar_entry(&mut archive, "PaxHeaders/x", b'x', b"20 path=../evil.txt\n");
+    tar_entry(&mut archive, "x", b'0', b"evil");
+    tar_end(&mut archive);
+    assert!(Archive::parse(archive).is_err());
+
+    let mut archive = Vec::new();
+    tar_entry(&mut archive, "PaxHeaders/x", b'x', b"99 path=x\n");
+    tar_entry(&mut archive, "x", b'0', b"evil");
+    tar_end(&mut archive);
+    assert!(Archive::parse(archive).is_err());
+  }
+}
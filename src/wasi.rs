@@ -0,0 +1,704 @@
+//! `wasi_snapshot_preview1` imports, so that `wasm32-wasi` builds run unchanged
+//!
+//! standard output and error go to the log, the environment comes from the
+//! application's `env` table, and the application's `mount` is preopened
+//! as `/`, read only. Other files and sockets are not available
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use wasmi::*;
+
+use logging::Level;
+use registry::{self, HostFunction, HostResult, Io, State};
+use vfs::{self, Stat, VfsError, VfsFile};
+
+pub const MODULE: &str = "wasi_snapshot_preview1";
+
+pub static FUNCTIONS: [HostFunction; 20] = [
+  HostFunction {
+    name: "args_get",
+    params: &[ValueType::I32, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: args_get,
+  },
+  HostFunction {
+    name: "args_sizes_get",
+    params: &[ValueType::I32, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: args_sizes_get,
+  },
+  HostFunction {
+    name: "environ_get",
+    params: &[ValueType::I32, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: environ_get,
+  },
+  HostFunction {
+    name: "environ_sizes_get",
+    params: &[ValueType::I32, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: environ_sizes_get,
+  },
+  HostFunction {
+    name: "clock_res_get",
+    params: &[ValueType::I32, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: clock_res_get,
+  },
+  HostFunction {
+    name: "clock_time_get",
+    params: &[ValueType::I32, ValueType::I64, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: clock_time_get,
+  },
+  HostFunction {
+    name: "random_get",
+    params: &[ValueType::I32, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: random_get,
+  },
+  HostFunction {
+    name: "proc_exit",
+    params: &[ValueType::I32],
+    ret: None,
+    handler: proc_exit,
+  },
+  HostFunction {
+    name: "sched_yield",
+    params: &[],
+    ret: Some(ValueType::I32),
+    handler: sched_yield,
+  },
+  HostFunction {
+    name: "fd_write",
+    params: &[ValueType::I32, ValueType::I32, ValueType::I32, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: fd_write,
+  },
+  HostFunction {
+    name: "fd_read",
+    params: &[ValueType::I32, ValueType::I32, ValueType::I32, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: fd_read,
+  },
+  HostFunction {
+    name: "fd_close",
+    params: &[ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: fd_close,
+  },
+  HostFunction {
+    name: "fd_seek",
+    params: &[ValueType::I32, ValueType::I64, ValueType::I32, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: fd_seek,
+  },
+  HostFunction {
+    name: "fd_fdstat_get",
+    params: &[ValueType::I32, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: fd_fdstat_get,
+  },
+  HostFunction {
+    name: "fd_fdstat_set_flags",
+    params: &[ValueType::I32, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: fd_fdstat_set_flags,
+  },
+  HostFunction {
+    name: "fd_filestat_get",
+    params: &[ValueType::I32, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: fd_filestat_get,
+  },
+  HostFunction {
+    name: "fd_prestat_get",
+    params: &[ValueType::I32, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: fd_prestat_get,
+  },
+  HostFunction {
+    name: "fd_prestat_dir_name",
+    params: &[ValueType::I32, ValueType::I32, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: fd_prestat_dir_name,
+  },
+  HostFunction {
+    name: "path_open",
+    params: &[
+      ValueType::I32,
+      ValueType::I32,
+      ValueType::I32,
+      ValueType::I32,
+      ValueType::I32,
+      ValueType::I64,
+      ValueType::I64,
+      ValueType::I32,
+      ValueType::I32,
+    ],
+    ret: Some(ValueType::I32),
+    handler: path_open,
+  },
+  HostFunction {
+    name: "path_filestat_get",
+    params: &[ValueType::I32, ValueType::I32, ValueType::I32, ValueType::I32, ValueType::I32],
+    ret: Some(ValueType::I32),
+    handler: path_filestat_get,
+  },
+];
+
+const ESUCCESS: i32 = 0;
+const EBADF: i32 = 8;
+const EINVAL: i32 = 28;
+const EIO: i32 = 29;
+const EISDIR: i32 = 31;
+const ENOENT: i32 = 44;
+const ENOTDIR: i32 = 54;
+const EROFS: i32 = 69;
+const ESPIPE: i32 = 70;
+const ENOTCAPABLE: i32 = 76;
+
+const FILETYPE_CHARACTER_DEVICE: u8 = 2;
+const FILETYPE_DIRECTORY: u8 = 3;
+const FILETYPE_REGULAR_FILE: u8 = 4;
+
+const OFLAGS_CREAT: i32 = 1;
+const OFLAGS_DIRECTORY: i32 = 2;
+const OFLAGS_EXCL: i32 = 4;
+const OFLAGS_TRUNC: i32 = 8;
+const FDFLAGS_APPEND: i32 = 1;
+const RIGHTS_FD_WRITE: i64 = 1 << 6;
+
+/// the preopened directory
+const ROOT_FD: u32 = 3;
+const ROOT_NAME: &str = "/";
+
+/// a file or directory opened by the guest, relative to the mount
+struct OpenFile {
+  path: String,
+  /// None for directories
+  file: Option<VfsFile>,
+}
+
+#[derive(Default)]
+pub struct WasiState {
+  pub args: Vec<String>,
+  files: HashMap<u32, OpenFile>,
+  next_fd: u32,
+}
+
+impl WasiState {
+  fn insert(&mut self, file: OpenFile) -> u32 {
+    if self.next_fd <= ROOT_FD {
+      self.next_fd = ROOT_FD + 1;
+    }
+    let fd = self.next_fd;
+    self.next_fd += 1;
+    self.files.insert(fd, file);
+    fd
+  }
+}
+
+/// path of a directory descriptor, relative to the mount
+fn directory(state: &State, fd: u32) -> Result<String, i32> {
+  if state.vfs.is_none() {
+    return Err(EBADF);
+  }
+  if fd == ROOT_FD {
+    return Ok(String::new());
+  }
+  match state.wasi.files.get(&fd) {
+    Some(&OpenFile { ref path, file: None }) => Ok(path.clone()),
+    Some(_) => Err(ENOTDIR),
+    None => Err(EBADF),
+  }
+}
+
+/// joins a guest path to a directory, refusing paths that leave the mount
+fn resolve(directory: &str, path: &str) -> Result<String, i32> {
+  if path.starts_with('/') {
+    return Err(ENOTCAPABLE);
+  }
+  vfs::normalize(&format!("{}/{}", directory, path)).map_err(|_| ENOTCAPABLE)
+}
+
+fn vfs_errno(error: VfsError) -> i32 {
+  match error {
+    VfsError::NotFound => ENOENT,
+    VfsError::InvalidPath => ENOTCAPABLE,
+    VfsError::IsDirectory => EISDIR,
+    VfsError::NotDirectory => ENOTDIR,
+    VfsError::NoMount | VfsError::BadDescriptor => EBADF,
+    VfsError::TooManyFiles | VfsError::Io => EIO,
+  }
+}
+
+/// stat of a path relative to the mount
+fn stat(state: &State, path: &str) -> Result<Stat, i32> {
+  match state.vfs.as_ref() {
+    Some(vfs) => vfs.stat(path).map_err(vfs_errno),
+    None => Err(EBADF),
+  }
+}
+
+/// returned as a host trap by `proc_exit`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcExit(pub i32);
+
+impl ::std::fmt::Display for ProcExit {
+  fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
+    write!(f, "proc_exit({})", self.0)
+  }
+}
+
+impl HostError for ProcExit {}
+
+/// `proc_exit(0)` ends the function like a return
+pub fn is_clean_exit(trap: &Trap) -> bool {
+  match trap.kind() {
+    &TrapKind::Host(ref err) => err.downcast_ref::<ProcExit>() == Some(&ProcExit(0)),
+    _ => false,
+  }
+}
+
+pub struct WasiResolver;
+
+impl ModuleImportResolver for WasiResolver {
+  fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, Error> {
+    registry::resolve_func(MODULE, field_name, signature)
+  }
+}
+
+fn errno(value: i32) -> HostResult {
+  Ok(Some(RuntimeValue::I32(value)))
+}
+
+fn write_u32(state: &Rc<RefCell<State>>, ptr: u32, value: u32) -> Result<(), Trap> {
+  let bytes = [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8];
+  state.borrow().write_bytes(ptr, &bytes)
+}
+
+fn write_u64(state: &Rc<RefCell<State>>, ptr: u32, value: u64) -> Result<(), Trap> {
+  write_u32(state, ptr, value as u32)?;
+  write_u32(state, ptr.wrapping_add(4), (value >> 32) as u32)
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+  (buf[0] as u32) | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24
+}
+
+/// reads an array of `(buf: u32, len: u32)` structures
+fn read_iovecs(state: &Rc<RefCell<State>>, ptr: u32, count: u32) -> Result<Vec<(u32, u32)>, Trap> {
+  let buf = state.borrow().read_bytes(ptr, count as u64 * 8)?;
+  Ok(
+    buf
+      .chunks(8)
+      .map(|iovec| (read_u32(&iovec[0..4]), read_u32(&iovec[4..8])))
+      .collect(),
+  )
+}
+
+/// writes NUL terminated strings at `buf` and pointers to them at `ptrs`
+fn write_strings(state: &Rc<RefCell<State>>, strings: &[String], ptrs: u32, buf: u32) -> HostResult {
+  let mut offset = buf;
+  for (i, s) in strings.iter().enumerate() {
+    write_u32(state, ptrs.wrapping_add(4 * i as u32), offset)?;
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.push(0);
+    state.borrow().write_bytes(offset, &bytes)?;
+    offset = offset.wrapping_add(bytes.len() as u32);
+  }
+  errno(ESUCCESS)
+}
+
+fn write_sizes(state: &Rc<RefCell<State>>, strings: &[String], count_ptr: u32, size_ptr: u32) -> HostResult {
+  let size: usize = strings.iter().map(|s| s.len() + 1).sum();
+  write_u32(state, count_ptr, strings.len() as u32)?;
+  write_u32(state, size_ptr, size as u32)?;
+  errno(ESUCCESS)
+}
+
+fn environment(state: &Rc<RefCell<State>>) -> Vec<String> {
+  let state = state.borrow();
+  let mut env: Vec<String> = state.db.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+  env.sort();
+  env
+}
+
+fn args_get(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let argv: u32 = args.nth(0);
+  let argv_buf: u32 = args.nth(1);
+
+  let list = state.borrow().wasi.args.clone();
+  write_strings(state, &list, argv, argv_buf)
+}
+
+fn args_sizes_get(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let argc_ptr: u32 = args.nth(0);
+  let size_ptr: u32 = args.nth(1);
+
+  let list = state.borrow().wasi.args.clone();
+  write_sizes(state, &list, argc_ptr, size_ptr)
+}
+
+fn environ_get(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let environ: u32 = args.nth(0);
+  let environ_buf: u32 = args.nth(1);
+
+  let list = environment(state);
+  write_strings(state, &list, environ, environ_buf)
+}
+
+fn environ_sizes_get(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let count_ptr: u32 = args.nth(0);
+  let size_ptr: u32 = args.nth(1);
+
+  let list = environment(state);
+  write_sizes(state, &list, count_ptr, size_ptr)
+}
+
+fn clock_res_get(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let id: u32 = args.nth(0);
+  let ptr: u32 = args.nth(1);
+
+  if id > 3 {
+    return errno(EINVAL);
+  }
+  write_u64(state, ptr, 1)?;
+  errno(ESUCCESS)
+}
+
+fn clock_time_get(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let id: u32 = args.nth(0);
+  let ptr: u32 = args.nth(2);
+
+  let nanos = match id {
+    0 => SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64)
+      .unwrap_or(0),
+    // the process and thread CPU time clocks use the monotonic clock
+    1 | 2 | 3 => {
+      let d = state.borrow().started.elapsed();
+      d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64
+    }
+    _ => return errno(EINVAL),
+  };
+  write_u64(state, ptr, nanos)?;
+  errno(ESUCCESS)
+}
+
+fn random_get(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let ptr: u32 = args.nth(0);
+  let len: u32 = args.nth(1);
+
+  let size = state.borrow().check_buffer(ptr, len as u64)?;
+  let mut buf = vec![0u8; size];
+  if File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut buf)).is_err() {
+    return errno(EIO);
+  }
+  state.borrow().write_bytes(ptr, &buf)?;
+  errno(ESUCCESS)
+}
+
+fn proc_exit(_state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let code: i32 = args.nth(0);
+
+  Err(Trap::new(TrapKind::Host(Box::new(ProcExit(code)))))
+}
+
+fn sched_yield(_state: &Rc<RefCell<State>>, _io: &mut dyn Io, _args: RuntimeArgs) -> HostResult {
+  errno(ESUCCESS)
+}
+
+fn fd_write(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let fd: u32 = args.nth(0);
+  let iovs: u32 = args.nth(1);
+  let iovs_len: u32 = args.nth(2);
+  let nwritten_ptr: u32 = args.nth(3);
+
+  let name = match fd {
+    1 => "stdout",
+    2 => "stderr",
+    _ if directory(&state.borrow(), fd).is_ok() => return errno(EISDIR),
+    _ if state.borrow().wasi.files.contains_key(&fd) => return errno(EROFS),
+    _ => return errno(EBADF),
+  };
+
+  let mut data = Vec::new();
+  for (ptr, len) in read_iovecs(state, iovs, iovs_len)? {
+    data.extend(state.borrow().read_bytes(ptr, len as u64)?);
+  }
+  let level = if fd == 2 { Level::Warn } else { Level::Info };
+  let text = String::from_utf8_lossy(&data);
+  registry::guest_log(state, level, format!("{}: {}", name, text.trim_right_matches('\n')), Vec::new());
+
+  write_u32(state, nwritten_ptr, data.len() as u32)?;
+  errno(ESUCCESS)
+}
+
+fn fd_read(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let fd: u32 = args.nth(0);
+  let iovs: u32 = args.nth(1);
+  let iovs_len: u32 = args.nth(2);
+  let nread_ptr: u32 = args.nth(3);
+
+  // standard input is always empty
+  if fd == 0 {
+    write_u32(state, nread_ptr, 0)?;
+    return errno(ESUCCESS);
+  }
+
+  let iovecs = read_iovecs(state, iovs, iovs_len)?;
+  let mut total = 0;
+  for (ptr, len) in iovecs {
+    let size = state.borrow().check_buffer(ptr, len as u64)?;
+    let mut buf = vec![0u8; size];
+    let res = match state.borrow_mut().wasi.files.get_mut(&fd) {
+      Some(&mut OpenFile { file: Some(ref mut file), .. }) => file.read(&mut buf).map_err(|_| EIO),
+      Some(_) => Err(EISDIR),
+      None => Err(EBADF),
+    };
+    let sz = match res {
+      Ok(sz) => sz,
+      Err(e) => return errno(e),
+    };
+
+    state.borrow().write_bytes(ptr, &buf[..sz])?;
+    total += sz;
+    if sz < size {
+      break;
+    }
+  }
+
+  write_u32(state, nread_ptr, total as u32)?;
+  errno(ESUCCESS)
+}
+
+fn fd_close(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let fd: u32 = args.nth(0);
+
+  if state.borrow_mut().wasi.files.remove(&fd).is_some() {
+    errno(ESUCCESS)
+  } else {
+    errno(EBADF)
+  }
+}
+
+fn fd_seek(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let fd: u32 = args.nth(0);
+  let offset: i64 = args.nth(1);
+  let whence: u32 = args.nth(2);
+  let newoffset_ptr: u32 = args.nth(3);
+
+  let position = match whence {
+    0 if offset >= 0 => SeekFrom::Start(offset as u64),
+    1 => SeekFrom::Current(offset),
+    2 => SeekFrom::End(offset),
+    _ => return errno(EINVAL),
+  };
+
+  let res = match state.borrow_mut().wasi.files.get_mut(&fd) {
+    Some(&mut OpenFile { file: Some(ref mut file), .. }) => file.seek(position).map_err(|_| EINVAL),
+    Some(_) => Err(EISDIR),
+    None if fd <= 2 => Err(ESPIPE),
+    None => Err(EBADF),
+  };
+
+  match res {
+    Ok(offset) => {
+      write_u64(state, newoffset_ptr, offset)?;
+      errno(ESUCCESS)
+    }
+    Err(e) => errno(e),
+  }
+}
+
+fn fd_fdstat_get(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let fd: u32 = args.nth(0);
+  let ptr: u32 = args.nth(1);
+
+  let filetype = if fd <= 2 {
+    FILETYPE_CHARACTER_DEVICE
+  } else if directory(&state.borrow(), fd).is_ok() {
+    FILETYPE_DIRECTORY
+  } else if state.borrow().wasi.files.contains_key(&fd) {
+    FILETYPE_REGULAR_FILE
+  } else {
+    return errno(EBADF);
+  };
+
+  // (filetype: u8, flags: u16, rights_base: u64, rights_inheriting: u64).
+  // All rights are advertised, the functions check what is allowed
+  let mut stat = [0u8; 24];
+  stat[0] = filetype;
+  for b in stat[8..24].iter_mut() {
+    *b = 0xff;
+  }
+  state.borrow().write_bytes(ptr, &stat)?;
+  errno(ESUCCESS)
+}
+
+fn fd_fdstat_set_flags(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let fd: u32 = args.nth(0);
+
+  if fd <= 2 || directory(&state.borrow(), fd).is_ok() || state.borrow().wasi.files.contains_key(&fd) {
+    errno(ESUCCESS)
+  } else {
+    errno(EBADF)
+  }
+}
+
+/// writes a 64 bytes filestat structure
+fn write_filestat(state: &Rc<RefCell<State>>, ptr: u32, filetype: u8, stat: Option<&Stat>) -> HostResult {
+  let nanos = |time: Option<SystemTime>| {
+    time
+      .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+      .map(|d| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64)
+      .unwrap_or(0)
+  };
+
+  state.borrow().write_bytes(ptr, &[0u8; 64])?;
+  state.borrow().write_bytes(ptr.wrapping_add(16), &[filetype])?;
+  write_u64(state, ptr.wrapping_add(24), 1)?;
+  if let Some(stat) = stat {
+    write_u64(state, ptr.wrapping_add(32), stat.size)?;
+    write_u64(state, ptr.wrapping_add(40), nanos(stat.modified))?;
+    write_u64(state, ptr.wrapping_add(48), nanos(stat.modified))?;
+    write_u64(state, ptr.wrapping_add(56), nanos(stat.modified))?;
+  }
+  errno(ESUCCESS)
+}
+
+fn filetype(stat: &Stat) -> u8 {
+  if stat.is_dir {
+    FILETYPE_DIRECTORY
+  } else {
+    FILETYPE_REGULAR_FILE
+  }
+}
+
+fn fd_filestat_get(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let fd: u32 = args.nth(0);
+  let ptr: u32 = args.nth(1);
+
+  if fd <= 2 {
+    return write_filestat(state, ptr, FILETYPE_CHARACTER_DEVICE, None);
+  }
+
+  let res = {
+    let state = state.borrow();
+    let path = match (directory(&state, fd), state.wasi.files.get(&fd)) {
+      (Ok(path), _) => path,
+      (_, Some(file)) => file.path.clone(),
+      _ => return errno(EBADF),
+    };
+    stat(&state, &path)
+  };
+
+  match res {
+    Ok(stat) => write_filestat(state, ptr, filetype(&stat), Some(&stat)),
+    Err(e) => errno(e),
+  }
+}
+
+fn fd_prestat_get(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let fd: u32 = args.nth(0);
+  let ptr: u32 = args.nth(1);
+
+  if fd != ROOT_FD || state.borrow().vfs.is_none() {
+    return errno(EBADF);
+  }
+
+  // (tag: u8, name_len: u32), the tag 0 is a directory
+  write_u32(state, ptr, 0)?;
+  write_u32(state, ptr.wrapping_add(4), ROOT_NAME.len() as u32)?;
+  errno(ESUCCESS)
+}
+
+fn fd_prestat_dir_name(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let fd: u32 = args.nth(0);
+  let ptr: u32 = args.nth(1);
+  let len: u32 = args.nth(2);
+
+  if fd != ROOT_FD || state.borrow().vfs.is_none() {
+    return errno(EBADF);
+  }
+  if (len as usize) < ROOT_NAME.len() {
+    return errno(EINVAL);
+  }
+
+  state.borrow().write_bytes(ptr, ROOT_NAME.as_bytes())?;
+  errno(ESUCCESS)
+}
+
+fn path_open(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let dirfd: u32 = args.nth(0);
+  let path_ptr: u32 = args.nth(2);
+  let path_len: u32 = args.nth(3);
+  let oflags: i32 = args.nth(4);
+  let rights_base: i64 = args.nth(5);
+  let fdflags: i32 = args.nth(7);
+  let fd_ptr: u32 = args.nth(8);
+
+  let path = state.borrow().read_str(path_ptr, path_len as u64)?;
+
+  // the mount is read only
+  if oflags & (OFLAGS_CREAT | OFLAGS_EXCL | OFLAGS_TRUNC) != 0 || fdflags & FDFLAGS_APPEND != 0
+    || rights_base & RIGHTS_FD_WRITE != 0
+  {
+    return errno(EROFS);
+  }
+
+  let res = {
+    let state = state.borrow();
+    directory(&state, dirfd)
+      .and_then(|directory| resolve(&directory, &path))
+      .and_then(|relative| {
+        if stat(&state, &relative)?.is_dir {
+          Ok(OpenFile { path: relative, file: None })
+        } else if oflags & OFLAGS_DIRECTORY != 0 {
+          Err(ENOTDIR)
+        } else {
+          let vfs = state.vfs.as_ref().ok_or(EBADF)?;
+          vfs
+            .open_file(&relative)
+            .map(|file| OpenFile { path: relative, file: Some(file) })
+            .map_err(vfs_errno)
+        }
+      })
+  };
+
+  match res {
+    Ok(file) => {
+      let fd = state.borrow_mut().wasi.insert(file);
+      write_u32(state, fd_ptr, fd)?;
+      errno(ESUCCESS)
+    }
+    Err(e) => errno(e),
+  }
+}
+
+fn path_filestat_get(state: &Rc<RefCell<State>>, _io: &mut dyn Io, args: RuntimeArgs) -> HostResult {
+  let dirfd: u32 = args.nth(0);
+  let path_ptr: u32 = args.nth(2);
+  let path_len: u32 = args.nth(3);
+  let ptr: u32 = args.nth(4);
+
+  let path = state.borrow().read_str(path_ptr, path_len as u64)?;
+  let res = {
+    let state = state.borrow();
+    directory(&state, dirfd)
+      .and_then(|directory| resolve(&directory, &path))
+      .and_then(|relative| stat(&state, &relative))
+  };
+
+  match res {
+    Ok(stat) => write_filestat(state, ptr, filetype(&stat), Some(&stat)),
+    Err(e) => errno(e),
+  }
+}
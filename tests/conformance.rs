@@ -0,0 +1,428 @@
+//! runs the sample applications on both the async and sync servers,
+//! and checks that they answer the same way, with the expected response
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{self, Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// numbers the static file trees, for tests running in parallel
+static NEXT_STATIC_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// headers that legitimately differ between the two servers
+const IGNORED_HEADERS: [&str; 3] = ["date", "server", "connection"];
+
+struct Server {
+  child: Child,
+  address: String,
+}
+
+impl Drop for Server {
+  fn drop(&mut self) {
+    let _ = self.child.kill();
+    let _ = self.child.wait();
+  }
+}
+
+#[derive(Debug, PartialEq)]
+struct Response {
+  status: u16,
+  headers: Vec<(String, String)>,
+  body: Vec<u8>,
+}
+
+fn free_address() -> String {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  listener.local_addr().unwrap().to_string()
+}
+
+/// answers the first line sent on each connection, for the backend sample
+fn start_echo_backend() -> String {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let address = listener.local_addr().unwrap().to_string();
+
+  thread::spawn(move || {
+    for stream in listener.incoming() {
+      if let Ok(mut stream) = stream {
+        let mut buf = [0u8; 100];
+        if let Ok(sz) = stream.read(&mut buf) {
+          let _ = stream.write_all(&buf[..sz]);
+        }
+      }
+    }
+  });
+
+  address
+}
+
+/// exports `no_content` and `not_modified`, which only set the status line,
+/// hand assembled since the samples cannot be rebuilt without a wasm toolchain
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const EMPTY_BODY_MODULE: &[u8] = &[
+  0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+  // types: (i32, i32, i64) -> (), () -> ()
+  0x01, 0x0a, 0x02, 0x60, 0x03, 0x7f, 0x7f, 0x7e, 0x00, 0x60, 0x00, 0x00,
+  // import env.response_set_status_line
+  0x02, 0x20, 0x01, 0x03, b'e', b'n', b'v', 0x18,
+  b'r', b'e', b's', b'p', b'o', b'n', b's', b'e', b'_', b's', b'e', b't',
+  b'_', b's', b't', b'a', b't', b'u', b's', b'_', b'l', b'i', b'n', b'e',
+  0x00, 0x00,
+  // functions
+  0x03, 0x03, 0x02, 0x01, 0x01,
+  // one page of memory
+  0x05, 0x03, 0x01, 0x00, 0x01,
+  // exports
+  0x07, 0x26, 0x03,
+  0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x00,
+  0x0a, b'n', b'o', b'_', b'c', b'o', b'n', b't', b'e', b'n', b't', 0x00, 0x01,
+  0x0c, b'n', b'o', b't', b'_', b'm', b'o', b'd', b'i', b'f', b'i', b'e', b'd', 0x00, 0x02,
+  // code: response_set_status_line(204, 0, 10) and (304, 16, 12)
+  0x0a, 0x19, 0x02,
+  0x0b, 0x00, 0x41, 0xcc, 0x01, 0x41, 0x00, 0x42, 0x0a, 0x10, 0x00, 0x0b,
+  0x0b, 0x00, 0x41, 0xb0, 0x02, 0x41, 0x10, 0x42, 0x0c, 0x10, 0x00, 0x0b,
+  // data: the reasons at 0 and 16
+  0x0b, 0x21, 0x02,
+  0x00, 0x41, 0x00, 0x0b, 0x0a, b'N', b'o', b' ', b'C', b'o', b'n', b't', b'e', b'n', b't',
+  0x00, 0x41, 0x10, 0x0b, 0x0c, b'N', b'o', b't', b' ', b'M', b'o', b'd', b'i', b'f', b'i', b'e', b'd',
+];
+
+/// next to the configuration, tests running in parallel each get their own
+fn write_empty_body_module(config_path: &PathBuf) -> PathBuf {
+  let path = config_path.with_extension("wasm");
+  File::create(&path).unwrap().write_all(EMPTY_BODY_MODULE).unwrap();
+  path
+}
+
+/// files served under `/files` by both servers, shared so that their
+/// modification dates are the same
+fn write_static_dir() -> PathBuf {
+  let mut path = env::temp_dir();
+  path.push(format!(
+    "serverless-wasm-conformance-static-{}-{}",
+    process::id(),
+    NEXT_STATIC_DIR.fetch_add(1, Ordering::SeqCst)
+  ));
+  let _ = fs::remove_dir_all(&path);
+  fs::create_dir_all(path.join("sub")).unwrap();
+  File::create(path.join("index.html")).unwrap().write_all(b"<h1>home</h1>\n").unwrap();
+  File::create(path.join("sub/index.html")).unwrap().write_all(b"<h1>sub</h1>\n").unwrap();
+  File::create(path.join("data.txt")).unwrap().write_all(b"0123456789").unwrap();
+  path
+}
+
+fn write_config(name: &str, listen_address: &str, backend_address: &str, static_dir: &PathBuf) -> PathBuf {
+  let mut path = env::temp_dir();
+  path.push(format!("serverless-wasm-conformance-{}-{}.toml", name, listen_address.replace(':', "_")));
+  let empty_body_module = write_empty_body_module(&path);
+
+  let config = format!(
+    r#"listen_address = "{}"
+
+[[applications]]
+file_path = "./samples/testfunc.wasm"
+method = "GET"
+url_path = "/hello"
+function = "hello"
+
+[[applications]]
+file_path = "./samples/testfunc.wasm"
+method = "GET"
+url_path = "/bonjour"
+function = "bonjour"
+
+[[applications]]
+file_path = "./samples/testbackend.wasm"
+method = "GET"
+url_path = "/backend"
+function = "handle"
+env = {{ "/env/backend" = "{}" }}
+
+[[applications]]
+file_path = "{}"
+method = "GET"
+url_path = "/no-content"
+function = "no_content"
+
+[[applications]]
+file_path = "{}"
+method = "GET"
+url_path = "/not-modified"
+function = "not_modified"
+
+[[static]]
+url_prefix = "/samples"
+directory = "./samples"
+
+[[static]]
+url_prefix = "/files"
+directory = "{}"
+"#,
+    listen_address,
+    backend_address,
+    empty_body_module.display(),
+    empty_body_module.display(),
+    static_dir.display()
+  );
+
+  File::create(&path).unwrap().write_all(config.as_bytes()).unwrap();
+  path
+}
+
+fn start_server(mode: &str, backend_address: &str, static_dir: &PathBuf) -> Server {
+  let address = free_address();
+  let config = write_config(mode, &address, backend_address, static_dir);
+
+  let child = Command::new(env!("CARGO_BIN_EXE_serverless-wasm"))
+    .arg("serve")
+    .arg(&config)
+    .arg("--mode")
+    .arg(mode)
+    .stdout(Stdio::null())
+    .spawn()
+    .expect("could not start the server");
+  let server = Server { child, address };
+
+  let start = Instant::now();
+  while TcpStream::connect(&server.address).is_err() {
+    if start.elapsed() > Duration::from_secs(10) {
+      panic!("{} server did not start", mode);
+    }
+    thread::sleep(Duration::from_millis(50));
+  }
+
+  server
+}
+
+fn request(address: &str, method: &str, path: &str, headers: &[(&str, &str)], request_body: &[u8]) -> Response {
+  let mut stream = TcpStream::connect(address).unwrap();
+  stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+  let mut head = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n", method, path);
+  for &(name, value) in headers.iter() {
+    head.push_str(&format!("{}: {}\r\n", name, value));
+  }
+  head.push_str("\r\n");
+  stream.write_all(head.as_bytes()).unwrap();
+  stream.write_all(request_body).unwrap();
+
+  let mut data = Vec::new();
+  stream.read_to_end(&mut data).unwrap();
+  parse_response(&data)
+}
+
+fn parse_response(data: &[u8]) -> Response {
+  let end = data
+    .windows(4)
+    .position(|w| w == b"\r\n\r\n")
+    .expect("incomplete response head");
+  let head = String::from_utf8_lossy(&data[..end]).into_owned();
+  let mut lines = head.split("\r\n");
+
+  let status_line = lines.next().unwrap();
+  let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+
+  let mut headers: Vec<(String, String)> = lines
+    .map(|line| {
+      let mut parts = line.splitn(2, ':');
+      (
+        parts.next().unwrap().trim().to_lowercase(),
+        parts.next().unwrap_or("").trim().to_string(),
+      )
+    })
+    .filter(|&(ref name, _)| !IGNORED_HEADERS.contains(&name.as_str()))
+    .collect();
+  headers.sort();
+
+  Response {
+    status,
+    headers,
+    body: data[end + 4..].to_vec(),
+  }
+}
+
+/// runs the request on both servers, which must agree with each other and
+/// with the expected status and body
+fn check_request(method: &str, path: &str, headers: &[(&str, &str)], status: u16, body: &[u8]) -> Response {
+  check_upload(method, path, headers, b"", status, body)
+}
+
+/// like `check_request`, sending a request body
+fn check_upload(method: &str, path: &str, headers: &[(&str, &str)], request_body: &[u8], status: u16, body: &[u8]) -> Response {
+  let backend = start_echo_backend();
+  let static_dir = write_static_dir();
+  let async_server = start_server("async", &backend, &static_dir);
+  let sync_server = start_server("sync", &backend, &static_dir);
+
+  let async_response = request(&async_server.address, method, path, headers, request_body);
+  let sync_response = request(&sync_server.address, method, path, headers, request_body);
+
+  assert_eq!(async_response, sync_response, "servers disagree on {} {}", method, path);
+  assert_eq!(async_response.status, status, "unexpected status for {} {}", method, path);
+  assert_eq!(
+    String::from_utf8_lossy(&async_response.body),
+    String::from_utf8_lossy(body),
+    "unexpected body for {} {}",
+    method,
+    path
+  );
+  async_response
+}
+
+fn check_response(path: &str, status: u16, body: &[u8]) {
+  check_request("GET", path, &[], status, body);
+}
+
+fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+  response
+    .headers
+    .iter()
+    .find(|&&(ref header, _)| header == name)
+    .map(|&(_, ref value)| value.as_str())
+}
+
+#[test]
+fn hello() {
+  check_response("/hello", 200, b"Hello world from wasm!\n");
+}
+
+#[test]
+fn bonjour() {
+  check_response("/bonjour", 200, b"Bonjour tout le monde depuis le monde merveilleux de WASM!\n");
+}
+
+#[test]
+fn backend() {
+  // the sample falls through to its error branch after reading the answer,
+  // so the status is overwritten but the body carries the echoed line
+  check_response("/backend", 500, b"Hello world from wasm!\nanswer from backend:\nhello\n\n");
+}
+
+#[test]
+fn unknown_route() {
+  check_response("/unknown", 404, b"Route not found\n");
+}
+
+#[test]
+fn static_file() {
+  let mut expected = Vec::new();
+  File::open("samples/config.toml").unwrap().read_to_end(&mut expected).unwrap();
+  check_response("/samples/config.toml", 200, &expected);
+}
+
+#[test]
+fn no_content() {
+  check_response("/no-content", 204, b"");
+}
+
+#[test]
+fn not_modified() {
+  check_response("/not-modified", 304, b"");
+}
+
+#[test]
+fn static_index_file() {
+  check_response("/files/", 200, b"<h1>home</h1>\n");
+  check_response("/files/sub/", 200, b"<h1>sub</h1>\n");
+}
+
+#[test]
+fn static_directory_redirect() {
+  let response = check_request("GET", "/files/sub", &[], 301, b"");
+  assert_eq!(header(&response, "location"), Some("/files/sub/"));
+}
+
+#[test]
+fn static_not_modified() {
+  check_request("GET", "/files/data.txt", &[("If-None-Match", "*")], 304, b"");
+  check_request(
+    "GET",
+    "/files/data.txt",
+    &[("If-Modified-Since", "Fri, 01 Jan 2100 00:00:00 GMT")],
+    304,
+    b"",
+  );
+}
+
+#[test]
+fn static_range() {
+  let response = check_request("GET", "/files/data.txt", &[("Range", "bytes=2-5")], 206, b"2345");
+  assert_eq!(header(&response, "content-range"), Some("bytes 2-5/10"));
+  check_request("GET", "/files/data.txt", &[("Range", "bytes=-3")], 206, b"789");
+
+  let response = check_request("GET", "/files/data.txt", &[("Range", "bytes=10-")], 416, b"");
+  assert_eq!(header(&response, "content-range"), Some("bytes */10"));
+}
+
+#[test]
+fn static_head() {
+  let response = check_request("HEAD", "/files/data.txt", &[], 200, b"");
+  assert_eq!(header(&response, "content-length"), Some("10"));
+}
+
+#[test]
+fn large_body() {
+  // larger than the async server's initial buffer
+  let request_body = vec![b'a'; 20000];
+  let length = request_body.len().to_string();
+  check_upload("GET", "/hello", &[("Content-Length", &length)], &request_body, 200, b"Hello world from wasm!\n");
+}
+
+#[test]
+fn invalid_content_length() {
+  check_request("GET", "/hello", &[("Content-Length", "abc")], 400, b"Bad Request\n");
+}
+
+#[test]
+fn body_too_large() {
+  // rejected from the headers, before any body is sent
+  check_request("GET", "/hello", &[("Content-Length", "2000000")], 413, b"Payload Too Large\n");
+}
+
+/// `init` writes 'i' at 0 and `run` overwrites it with 'r' before sending
+/// it as the body, the host must read the request instance's memory
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const INIT_MEMORY_MODULE: &[u8] = &[
+  0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+  // types: (i32, i64) -> (), () -> ()
+  0x01, 0x09, 0x02, 0x60, 0x02, 0x7f, 0x7e, 0x00, 0x60, 0x00, 0x00,
+  // import env.response_set_body
+  0x02, 0x19, 0x01, 0x03, b'e', b'n', b'v', 0x11,
+  b'r', b'e', b's', b'p', b'o', b'n', b's', b'e', b'_', b's', b'e', b't', b'_', b'b', b'o', b'd', b'y',
+  0x00, 0x00,
+  // functions
+  0x03, 0x03, 0x02, 0x01, 0x01,
+  // one page of memory
+  0x05, 0x03, 0x01, 0x00, 0x01,
+  // exports
+  0x07, 0x17, 0x03,
+  0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x00,
+  0x04, b'i', b'n', b'i', b't', 0x00, 0x01,
+  0x03, b'r', b'u', b'n', 0x00, 0x02,
+  // code: store8(0, 'i') and store8(0, 'r'), response_set_body(0, 1)
+  0x0a, 0x1d, 0x02,
+  0x0a, 0x00, 0x41, 0x00, 0x41, 0xe9, 0x00, 0x3a, 0x00, 0x00, 0x0b,
+  0x10, 0x00, 0x41, 0x00, 0x41, 0xf2, 0x00, 0x3a, 0x00, 0x00, 0x41, 0x00, 0x42, 0x01, 0x10, 0x00, 0x0b,
+];
+
+#[test]
+fn invoke_after_init() {
+  let mut path = env::temp_dir();
+  path.push(format!("serverless-wasm-conformance-init-{}.wasm", process::id()));
+  File::create(&path).unwrap().write_all(INIT_MEMORY_MODULE).unwrap();
+
+  let output = Command::new(env!("CARGO_BIN_EXE_serverless-wasm"))
+    .arg("invoke")
+    .arg(&path)
+    .arg("run")
+    .output()
+    .expect("could not run invoke");
+  let _ = fs::remove_file(&path);
+
+  let stdout = String::from_utf8_lossy(&output.stdout);
+  assert!(output.status.success(), "invoke failed:\n{}", stdout);
+  assert!(stdout.contains("\n\nr\n"), "unexpected body:\n{}", stdout);
+}